@@ -0,0 +1,61 @@
+//! Routing time as the number of rules grows.
+//!
+//! Each tenant has a few rules under its own prefix, and a handful of rules
+//! without a prefix sit at the end, as in generated configurations. `linear`
+//! runs every rule's regex in turn, which is what routing costs without the
+//! compiled matcher.
+
+use criterion::{BenchmarkId, Criterion, criterion_group, criterion_main};
+use platypus::Router;
+use std::hint::black_box;
+
+fn router(tenants: usize) -> Router {
+    let mut router = Router::new();
+    for tenant in 0..tenants {
+        router = router
+            .route(&format!("^t{}/config/(?<name>.+)$", tenant), "config")
+            .route(&format!("^t{}/secret/(?<name>.+)$", tenant), "secret")
+            .route(&format!("^t{}/(?<a>[^/]+)/(?<b>.+)$", tenant), "other");
+    }
+    router
+        .route("(?<name>[^/]+)\\.json$", "json")
+        .route("^(?<tenant>[^/]+)/health$", "health")
+}
+
+fn bench_router(c: &mut Criterion) {
+    let mut group = c.benchmark_group("router");
+    for tenants in [10, 100, 1000] {
+        let router = router(tenants);
+        let rules = tenants * 3 + 2;
+        let last = format!("t{}/secret/db", tenants - 1);
+        let keys = [
+            ("first", "t0/config/db"),
+            ("last", last.as_str()),
+            ("miss", "x/y/z"),
+        ];
+
+        for (name, key) in keys {
+            // Compile the matcher before measuring
+            router.rule(key);
+            group.bench_with_input(BenchmarkId::new(name, rules), key, |b, key| {
+                b.iter(|| router.rule(black_box(key)))
+            });
+            group.bench_with_input(
+                BenchmarkId::new(format!("linear/{}", name), rules),
+                key,
+                |b, key| {
+                    b.iter(|| {
+                        router
+                            .rules()
+                            .iter()
+                            .find_map(|rule| rule.match_key(black_box(key)))
+                    })
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_router);
+criterion_main!(benches);
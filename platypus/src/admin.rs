@@ -0,0 +1,359 @@
+//! A small HTTP listener for operators, separate from the memcached port.
+//!
+//! - `/healthz` answers as long as the process is serving
+//! - `/readyz` answers 200 once the targets have connected, 503 before
+//! - `/targets` shows the health of each target node
+//! - `/tasks` lists the monitored keys, with values redacted by default
+//! - `/routes?key=...` shows which rule a key matches and its captures
+//! - `/metrics` serves Prometheus metrics
+
+use crate::{Distribution, Service, metrics};
+use anyhow::Result;
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::Instant;
+use tracing::{debug, error, info};
+
+pub struct AdminServer {
+    listen_address: String,
+    show_values: bool,
+    metrics_only: bool,
+}
+
+#[derive(Clone, Copy)]
+struct Options {
+    show_values: bool,
+    metrics_only: bool,
+}
+
+/// Status code, content type and body of an admin response
+pub struct AdminResponse {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl AdminResponse {
+    fn text(status: u16, body: &str) -> Self {
+        Self {
+            status,
+            content_type: "text/plain",
+            body: format!("{}\n", body),
+        }
+    }
+
+    fn metrics() -> Self {
+        Self {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: metrics::encode(),
+        }
+    }
+
+    fn json(value: serde_json::Value) -> Self {
+        Self {
+            status: 200,
+            content_type: "application/json",
+            body: value.to_string(),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+impl AdminServer {
+    pub fn bind(listen_address: &str) -> Self {
+        Self {
+            listen_address: listen_address.to_string(),
+            show_values: false,
+            metrics_only: false,
+        }
+    }
+
+    /// Only serve `/metrics`, for a dedicated metrics port
+    pub fn metrics_only(&mut self) -> &mut Self {
+        self.metrics_only = true;
+        self
+    }
+
+    /// Include task values in `/tasks`. Values are often secrets, so they are
+    /// redacted unless this is enabled.
+    pub fn with_values(&mut self, show_values: bool) -> &mut Self {
+        self.show_values = show_values;
+        self
+    }
+
+    pub async fn serve(self, service: Service) -> Result<()> {
+        let listener = TcpListener::bind(&self.listen_address).await?;
+        info!(address = self.listen_address, "Admin server listening");
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let service = service.clone();
+            let options = Options {
+                show_values: self.show_values,
+                metrics_only: self.metrics_only,
+            };
+            tokio::spawn(async move {
+                if let Err(e) = Self::handle_connection(stream, &service, options).await {
+                    error!(error = %e, "Admin connection error");
+                }
+            });
+        }
+    }
+
+    // One request per connection, answered with `Connection: close`
+    async fn handle_connection(
+        mut stream: TcpStream,
+        service: &Service,
+        options: Options,
+    ) -> Result<()> {
+        let (read, mut write) = stream.split();
+        let mut reader = BufReader::new(read);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await?;
+        // Skip the headers, nothing here needs them
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+                break;
+            }
+        }
+
+        let mut parts = request_line.split_whitespace();
+        let response = match (parts.next(), parts.next()) {
+            (Some("GET"), Some(target)) => {
+                let (path, query) = target.split_once('?').unwrap_or((target, ""));
+                debug!(path = path, "Admin request");
+                if options.metrics_only && path != "/metrics" {
+                    AdminResponse::text(404, "not found")
+                } else {
+                    handle(service, path, query, options.show_values)
+                }
+            }
+            (Some(_), Some(_)) => AdminResponse::text(405, "method not allowed"),
+            _ => AdminResponse::text(400, "bad request"),
+        };
+
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            response.status,
+            response.reason(),
+            response.content_type,
+            response.body.len()
+        );
+        write.write_all(head.as_bytes()).await?;
+        write.write_all(response.body.as_bytes()).await?;
+        write.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Answers a GET for `path` with the given query string.
+pub fn handle(service: &Service, path: &str, query: &str, show_values: bool) -> AdminResponse {
+    match path {
+        "/healthz" => AdminResponse::text(200, "ok"),
+        "/readyz" => {
+            if service.is_ready() {
+                AdminResponse::text(200, "ready")
+            } else {
+                AdminResponse::text(503, "not ready")
+            }
+        }
+        "/tasks" => tasks(service, show_values),
+        "/targets" => targets(service),
+        "/metrics" => AdminResponse::metrics(),
+        "/routes" => {
+            let key = url::form_urlencoded::parse(query.as_bytes())
+                .find(|(name, _)| name == "key")
+                .map(|(_, value)| value.into_owned());
+            match key {
+                Some(key) => routes(service, &key),
+                None => AdminResponse::text(400, "missing key parameter"),
+            }
+        }
+        _ => AdminResponse::text(404, "not found"),
+    }
+}
+
+fn tasks(service: &Service, show_values: bool) -> AdminResponse {
+    let now = Instant::now();
+    // Seconds from now, negative if in the past
+    let from_now = |at: Option<Instant>| {
+        at.map(|at| {
+            if at >= now {
+                (at - now).as_secs_f64()
+            } else {
+                -(now - at).as_secs_f64()
+            }
+        })
+    };
+
+    let mut tasks: Vec<_> = service
+        .monitor_tasks()
+        .tasks()
+        .into_iter()
+        .map(|task| {
+            let value = match task.last_result() {
+                Some(value) if show_values => json!(value),
+                Some(_) => json!("<redacted>"),
+                None => json!(null),
+            };
+            json!({
+                "key": task.request().key(),
+                "source": task.source_name(),
+                "last_refresh_secs_ago": from_now(task.last_refresh()).map(|secs| -secs),
+                "next_poll_in_secs": from_now(task.next_poll()),
+                "expires_in_secs": from_now(task.expires_at()),
+                "value": value,
+            })
+        })
+        .collect();
+    tasks.sort_by(|a, b| a["key"].as_str().cmp(&b["key"].as_str()));
+    AdminResponse::json(json!({ "tasks": tasks }))
+}
+
+fn targets(service: &Service) -> AdminResponse {
+    let Some(targets) = service.targets() else {
+        return AdminResponse::json(json!({ "targets": [] }));
+    };
+    let nodes: Vec<_> = targets
+        .health()
+        .into_iter()
+        .map(|node| {
+            json!({
+                "address": node.address,
+                "connected": node.connected,
+                "healthy": node.is_healthy(),
+                "consecutive_failures": node.consecutive_failures,
+            })
+        })
+        .collect();
+    let distribution = match targets.distribution() {
+        Distribution::Replicate => "replicate",
+        Distribution::Hash => "hash",
+    };
+    AdminResponse::json(json!({ "distribution": distribution, "targets": nodes }))
+}
+
+fn routes(service: &Service, key: &str) -> AdminResponse {
+    let router = service.router();
+    let namespace = service.namespace();
+    match router.rule(namespace.route_key(key)) {
+        Some((request, rule)) => AdminResponse::json(json!({
+            "key": key,
+            "matched": true,
+            "pattern": rule.pattern(),
+            "source": rule.source(),
+            "captures": request.captures(),
+            "target_key": namespace.target_key(&request),
+        })),
+        None => AdminResponse::json(json!({
+            "key": key,
+            "matched": false,
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Router, Sources};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn echo_service() -> Service {
+        let source: Arc<Box<dyn crate::Source>> = Arc::new(Box::new(crate::source::Echo::new()));
+        let sources: Sources = HashMap::from([("echo".to_string(), source)]);
+        Service::new()
+            .with_router(Router::new().route("^echo/(?<name>.+)$", "echo"))
+            .with_sources(sources)
+    }
+
+    fn body(response: &AdminResponse) -> serde_json::Value {
+        serde_json::from_str(&response.body).unwrap()
+    }
+
+    #[test]
+    fn test_health_and_ready() {
+        let service = echo_service();
+        assert_eq!(handle(&service, "/healthz", "", false).status, 200);
+        // No target writer, so nothing to wait for
+        assert_eq!(handle(&service, "/readyz", "", false).status, 200);
+        assert_eq!(handle(&service, "/nope", "", false).status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_targets() {
+        let writer = crate::Writer::builder("tcp://127.0.0.1:1")
+            .with_retries(0, std::time::Duration::ZERO)
+            .build();
+        let service = echo_service().with_writer(writer);
+
+        let json = body(&handle(&service, "/targets", "", false));
+        assert_eq!(json["distribution"], "hash");
+        assert_eq!(json["targets"][0]["address"], "tcp://127.0.0.1:1");
+        assert_eq!(json["targets"][0]["healthy"], false);
+        assert_eq!(handle(&service, "/readyz", "", false).status, 503);
+        service.shutdown().await;
+    }
+
+    #[test]
+    fn test_routes() {
+        let service = echo_service();
+
+        let response = handle(&service, "/routes", "key=echo%2Fworld", false);
+        assert_eq!(response.status, 200);
+        let json = body(&response);
+        assert_eq!(json["matched"], true);
+        assert_eq!(json["source"], "echo");
+        assert_eq!(json["captures"]["name"], "world");
+        assert_eq!(json["target_key"], "echo/world");
+
+        let response = handle(&service, "/routes", "key=other", false);
+        assert_eq!(body(&response)["matched"], false);
+
+        assert_eq!(handle(&service, "/routes", "", false).status, 400);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let service = echo_service();
+        service.prefetch(&["echo/a".to_string()]).await;
+
+        let response = handle(&service, "/metrics", "", false);
+        assert_eq!(response.status, 200);
+        assert!(response.body.contains("platypus_tasks_created_total"));
+        assert!(
+            response
+                .body
+                .contains(r#"platypus_source_call_duration_seconds_count{source="echo"}"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tasks_redacts_values() {
+        let service = echo_service();
+        service.prefetch(&["echo/a".to_string()]).await;
+
+        let redacted = body(&handle(&service, "/tasks", "", false));
+        let task = &redacted["tasks"][0];
+        assert_eq!(task["key"], "echo/a");
+        assert_eq!(task["source"], "echo");
+        assert_eq!(task["value"], "<redacted>");
+        assert!(task["next_poll_in_secs"].is_number());
+
+        let shown = body(&handle(&service, "/tasks", "", true));
+        assert_ne!(shown["tasks"][0]["value"], "<redacted>");
+    }
+}
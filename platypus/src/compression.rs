@@ -0,0 +1,182 @@
+//! Compression of values written to the target and returned by `get`.
+//!
+//! Compressed values carry the flag Dalli uses for compressed raw values, so
+//! Ruby clients, including Rails' `MemCacheStore`, decompress them
+//! transparently. Dalli's default compressor is zlib; gzip and zstd values
+//! need the matching `compressor` configured in Dalli.
+
+use flate2::Compression as Level;
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::io::{self, Read, Write};
+
+/// Item flag Dalli sets on compressed values
+pub const FLAG_COMPRESSED: u32 = 0x2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Zlib,
+    Gzip,
+    Zstd,
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zlib" => Ok(Algorithm::Zlib),
+            "gzip" => Ok(Algorithm::Gzip),
+            "zstd" => Ok(Algorithm::Zstd),
+            _ => Err(format!(
+                "unknown compression '{}', expected zlib, gzip or zstd",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compression {
+    algorithm: Algorithm,
+    min_size: usize,
+}
+
+impl Compression {
+    /// Compresses values of 4 KiB or more, like Dalli does by default
+    pub fn new(algorithm: Algorithm) -> Self {
+        Self {
+            algorithm,
+            min_size: 4096,
+        }
+    }
+
+    pub fn with_min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    /// Compresses `value` if it is large enough and compressing makes it smaller
+    pub fn encode(&self, value: &str) -> Encoded {
+        if value.len() < self.min_size {
+            return Encoded::from(value);
+        }
+        match compress(self.algorithm, value.as_bytes()) {
+            Ok(data) if data.len() < value.len() => Encoded {
+                data,
+                flags: FLAG_COMPRESSED,
+            },
+            Ok(_) => Encoded::from(value),
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to compress value, storing it as is");
+                Encoded::from(value)
+            }
+        }
+    }
+}
+
+/// A value as stored on the target: its bytes and item flags
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Encoded {
+    pub data: Vec<u8>,
+    pub flags: u32,
+}
+
+impl Encoded {
+    pub fn is_compressed(&self) -> bool {
+        self.flags & FLAG_COMPRESSED != 0
+    }
+}
+
+impl From<&str> for Encoded {
+    fn from(value: &str) -> Self {
+        Self {
+            data: value.as_bytes().to_vec(),
+            flags: 0,
+        }
+    }
+}
+
+impl From<String> for Encoded {
+    fn from(value: String) -> Self {
+        Self {
+            data: value.into_bytes(),
+            flags: 0,
+        }
+    }
+}
+
+pub fn compress(algorithm: Algorithm, data: &[u8]) -> io::Result<Vec<u8>> {
+    match algorithm {
+        Algorithm::Zlib => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Level::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Algorithm::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Algorithm::Zstd => zstd::encode_all(data, 0),
+    }
+}
+
+pub fn decompress(algorithm: Algorithm, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    match algorithm {
+        Algorithm::Zlib => {
+            ZlibDecoder::new(data).read_to_end(&mut out)?;
+        }
+        Algorithm::Gzip => {
+            GzDecoder::new(data).read_to_end(&mut out)?;
+        }
+        Algorithm::Zstd => out = zstd::decode_all(data)?,
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_small_values_are_not_compressed() {
+        let encoded = Compression::new(Algorithm::Zlib).encode("small");
+        assert_eq!(encoded, Encoded::from("small"));
+        assert!(!encoded.is_compressed());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let value = "platypus ".repeat(1000);
+        for algorithm in [Algorithm::Zlib, Algorithm::Gzip, Algorithm::Zstd] {
+            let encoded = Compression::new(algorithm).encode(&value);
+            assert_eq!(encoded.flags, FLAG_COMPRESSED);
+            assert!(encoded.data.len() < value.len());
+            assert_eq!(
+                decompress(algorithm, &encoded.data).unwrap(),
+                value.as_bytes()
+            );
+        }
+    }
+
+    #[test]
+    fn test_zlib_header() {
+        // Dalli's default compressor is Ruby's Zlib::Deflate, which writes a zlib header
+        let encoded = Compression::new(Algorithm::Zlib)
+            .with_min_size(0)
+            .encode(&"a".repeat(100));
+        assert_eq!(encoded.data[0], 0x78);
+    }
+
+    #[test]
+    fn test_algorithm_from_str() {
+        assert_eq!("zstd".parse::<Algorithm>(), Ok(Algorithm::Zstd));
+        assert!("lz4".parse::<Algorithm>().is_err());
+    }
+}
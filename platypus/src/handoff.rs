@@ -0,0 +1,193 @@
+//! Passing listening sockets between processes, so platypus can restart
+//! without refusing connections.
+//!
+//! Listeners can be inherited from systemd socket activation (`LISTEN_FDS`),
+//! given explicitly as a file descriptor, or requested from a running
+//! platypus over its upgrade socket. In the last case the old process also
+//! sends the keys it is monitoring so the new one can keep refreshing them.
+
+use anyhow::{Result, anyhow};
+use nix::sys::socket::{
+    AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockaddrLike, SockaddrStorage,
+    getsockname, recvmsg, sendmsg,
+};
+use std::io::{BufRead, BufReader, IoSlice, IoSliceMut, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+
+// First file descriptor passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket received from another process
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Listener {
+    pub fn from_fd(fd: OwnedFd) -> Result<Self> {
+        let family = getsockname::<SockaddrStorage>(fd.as_raw_fd())?.family();
+        let listener = match family {
+            Some(AddressFamily::Inet) | Some(AddressFamily::Inet6) => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Listener::Tcp(listener)
+            }
+            Some(AddressFamily::Unix) => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Listener::Unix(listener)
+            }
+            other => return Err(anyhow!("unsupported listener address family {:?}", other)),
+        };
+        Ok(listener)
+    }
+}
+
+/// Takes ownership of a listening socket inherited from the parent process,
+/// e.g. given with `--listen-fd`. Fails if `fd` is not an open socket.
+pub fn inherit_fd(fd: RawFd) -> Result<OwnedFd> {
+    getsockname::<SockaddrStorage>(fd)
+        .map_err(|e| anyhow!("file descriptor {} is not a socket: {}", fd, e))?;
+    // SAFETY: the fd was inherited from the parent process and is not used
+    // anywhere else in this process.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Returns the listening sockets passed by systemd socket activation, if any.
+///
+/// Like `sd_listen_fds`, this removes `LISTEN_PID`, `LISTEN_FDS` and
+/// `LISTEN_FDNAMES`, so child processes don't take the sockets for theirs.
+/// Call it before starting other threads.
+pub fn listen_fds_from_env() -> Result<Vec<OwnedFd>> {
+    let pid_matches = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        // SAFETY: called at startup, before other threads read the environment
+        unsafe { std::env::remove_var(name) };
+    }
+    if !pid_matches {
+        return Ok(Vec::new());
+    }
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(inherit_fd)
+        .collect()
+}
+
+/// What a new process receives from the one it replaces
+pub struct Handoff {
+    pub listener: OwnedFd,
+    pub keys: Vec<String>,
+}
+
+/// Asks the platypus listening on `upgrade_socket` for its listener and
+/// monitored keys. The old process stops accepting and drains once this returns.
+pub async fn request(upgrade_socket: &str) -> Result<Handoff> {
+    let path = upgrade_socket.to_string();
+    tokio::task::spawn_blocking(move || {
+        let stream = UnixStream::connect(&path)?;
+        receive(stream)
+    })
+    .await?
+}
+
+fn receive(stream: UnixStream) -> Result<Handoff> {
+    let mut marker = [0u8; 1];
+    let mut iov = [IoSliceMut::new(&mut marker)];
+    let mut cmsg_buffer = nix::cmsg_space!(RawFd);
+    let msg = recvmsg::<()>(
+        stream.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg_buffer),
+        MsgFlags::empty(),
+    )?;
+
+    let mut listener = None;
+    for cmsg in msg.cmsgs()? {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            for fd in fds {
+                // SAFETY: the kernel just installed this fd for us.
+                let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+                listener.get_or_insert(fd);
+            }
+        }
+    }
+    let listener = listener.ok_or_else(|| anyhow!("no listener received"))?;
+
+    let mut keys = Vec::new();
+    for line in BufReader::new(stream).lines() {
+        let key = line?;
+        if !key.is_empty() {
+            keys.push(key);
+        }
+    }
+
+    Ok(Handoff { listener, keys })
+}
+
+/// Sends `listener` and the monitored `keys` to a process that connected to the upgrade socket.
+pub(crate) fn send(mut stream: UnixStream, listener: RawFd, keys: &[String]) -> Result<()> {
+    let marker = [b'L'];
+    let iov = [IoSlice::new(&marker)];
+    let fds = [listener];
+    let cmsgs = [ControlMessage::ScmRights(&fds)];
+    sendmsg::<SockaddrStorage>(stream.as_raw_fd(), &iov, &cmsgs, MsgFlags::empty(), None)?;
+
+    for key in keys {
+        stream.write_all(key.as_bytes())?;
+        stream.write_all(b"\n")?;
+    }
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_fds_from_env_without_systemd() {
+        // LISTEN_PID is not set for test processes
+        assert!(listen_fds_from_env().unwrap().is_empty());
+
+        // Variables meant for another process are removed all the same
+        // SAFETY: no other test reads or writes these variables
+        unsafe {
+            std::env::set_var("LISTEN_PID", "1");
+            std::env::set_var("LISTEN_FDS", "1");
+        }
+        assert!(listen_fds_from_env().unwrap().is_empty());
+        assert!(std::env::var("LISTEN_PID").is_err());
+        assert!(std::env::var("LISTEN_FDS").is_err());
+    }
+
+    #[test]
+    fn test_inherit_fd_rejects_non_socket() {
+        let file = std::fs::File::open("/dev/null").unwrap();
+        assert!(inherit_fd(file.as_raw_fd()).is_err());
+    }
+
+    #[test]
+    fn test_send_and_receive() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (old, new) = UnixStream::pair().unwrap();
+
+        let keys = vec!["a/config".to_string(), "b/secret".to_string()];
+        send(old, listener.as_raw_fd(), &keys).unwrap();
+        let handoff = receive(new).unwrap();
+        assert_eq!(handoff.keys, keys);
+
+        match Listener::from_fd(handoff.listener).unwrap() {
+            Listener::Tcp(received) => assert_eq!(received.local_addr().unwrap(), addr),
+            Listener::Unix(_) => panic!("Expected TCP listener"),
+        }
+    }
+}
@@ -2,7 +2,12 @@ use std::collections::HashMap;
 use std::pin::Pin;
 use thiserror::Error;
 
+pub mod admin;
+pub mod compression;
+pub mod handoff;
+pub mod metrics;
 pub mod monitor;
+pub mod namespace;
 pub mod pool;
 pub mod protocol;
 pub mod request;
@@ -11,9 +16,15 @@ pub mod router;
 pub mod server;
 pub mod service;
 pub mod source;
+pub mod stats;
+pub mod target;
+pub mod template;
 pub mod writer;
 
+pub use admin::AdminServer;
+pub use compression::{Compression, Encoded};
 pub use monitor::{MonitorTask, MonitorTasks};
+pub use namespace::Namespace;
 pub use pool::{AwsSecretsManagerConnectionManager, AwsSecretsManagerPoolBuilder};
 pub use request::Request;
 pub use response::Response;
@@ -22,7 +33,10 @@ pub use server::Server;
 pub use service::Service;
 pub use source::Source;
 pub use source::Sources;
-pub use writer::Writer;
+pub use stats::Stats;
+pub use target::{Distribution, InMemory, Oversize, Target, TargetSet, ValueLimit};
+pub use template::Template;
+pub use writer::{QueueOverflow, Writer};
 
 pub use source::source;
 
@@ -36,6 +50,9 @@ pub enum Error {
     #[error("not ready")]
     NotReady,
 
+    #[error("invalid key: {0}")]
+    InvalidKey(String),
+
     #[error("other: {0}")]
     Other(#[from] anyhow::Error),
 }
@@ -56,40 +73,12 @@ impl<F> AsyncGetter for F where
 {
 }
 
-pub fn replace_placeholders(text: &str, replacements: &HashMap<String, String>) -> String {
-    let mut ret = String::new();
-    let mut placeholders: Vec<String> = Vec::new();
-
-    for (_i, ch) in text.chars().enumerate() {
-        match ch {
-            '{' => {
-                placeholders.push(String::new());
-            }
-            '}' => {
-                if let Some(placeholder) = placeholders.pop() {
-                    match replacements.get(&placeholder) {
-                        Some(replacement) => {
-                            if let Some(parent) = placeholders.last_mut() {
-                                parent.push_str(&replacement);
-                            } else {
-                                ret.push_str(&replacement);
-                            }
-                        }
-                        None => {}
-                    }
-                }
-            }
-            _ => {
-                if let Some(current) = placeholders.last_mut() {
-                    current.push(ch);
-                } else {
-                    ret.push(ch);
-                }
-            }
-        }
-    }
-
-    ret
+/// Fills in `text` as a [`Template`]. Fails if it is not a valid one.
+pub fn replace_placeholders(
+    text: &str,
+    replacements: &HashMap<String, String>,
+) -> Result<String, String> {
+    Template::new(text).render(replacements)
 }
 
 #[cfg(test)]
@@ -139,7 +128,7 @@ mod tests {
         placeholders.insert("age".to_string(), "30".to_string());
 
         let template = "Hello {name}, you are {age} years old";
-        let result = replace_placeholders(template, &placeholders);
+        let result = replace_placeholders(template, &placeholders).unwrap();
         assert_eq!(result, "Hello Alice, you are 30 years old");
     }
 
@@ -147,7 +136,7 @@ mod tests {
     fn test_replace_placeholders_no_matches() {
         let placeholders = HashMap::new();
         let template = "Hello {name}";
-        let result = replace_placeholders(template, &placeholders);
+        let result = replace_placeholders(template, &placeholders).unwrap();
         assert_eq!(result, "Hello ");
     }
 
@@ -157,7 +146,7 @@ mod tests {
         placeholders.insert("word".to_string(), "test".to_string());
 
         let template = "{word} {word} {word}";
-        let result = replace_placeholders(template, &placeholders);
+        let result = replace_placeholders(template, &placeholders).unwrap();
         assert_eq!(result, "test test test");
     }
 
@@ -168,7 +157,13 @@ mod tests {
         placeholders.insert("test".to_string(), "other".to_string());
 
         let template = "{{word}} {{word}} {{word}}";
-        let result = replace_placeholders(template, &placeholders);
+        let result = replace_placeholders(template, &placeholders).unwrap();
         assert_eq!(result, "other other other");
     }
+
+    #[test]
+    fn test_replace_placeholders_invalid_template() {
+        let placeholders = HashMap::new();
+        assert!(replace_placeholders("Hello {name", &placeholders).is_err());
+    }
 }
@@ -0,0 +1,247 @@
+//! Prometheus metrics.
+//!
+//! Metrics are process wide and registered in [`registry()`]. Connection
+//! counts live in [`Stats`](crate::Stats), which is registered separately
+//! by whoever owns it.
+
+use crate::protocol::ProtocolType;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::sync::LazyLock;
+
+pub struct Metrics {
+    /// Get requests, by protocol and hit or miss
+    pub gets: IntCounterVec,
+    /// Time taken by `Source::call`, by source
+    pub source_call_duration: HistogramVec,
+    /// Source calls that returned no value, by source
+    pub source_errors: IntCounterVec,
+    /// Background refreshes of a monitored key, by source
+    pub refreshes: IntCounterVec,
+    pub tasks_created: IntCounter,
+    /// Monitor tasks removed, by reason: expired, retired or size
+    pub tasks_evicted: IntCounterVec,
+    pub tasks: IntGauge,
+    pub tasks_weighted_size: IntGauge,
+    /// Open connections to each target node
+    pub target_connections: IntGaugeVec,
+    /// Writes queued for the target and not yet sent
+    pub writer_queue_depth: IntGauge,
+    /// Time writes waited in the queue
+    pub writer_queue_wait: Histogram,
+    /// Writes dropped because the queue was full
+    pub writer_dropped: IntCounter,
+    /// Writes folded into a write to the same key that was still queued
+    pub writer_coalesced: IntCounter,
+    /// Time taken by a set, touch or delete on the target, by operation
+    pub writer_write_duration: HistogramVec,
+    /// Guarded writes skipped because the key changed on the target, by operation
+    pub writer_conflicts: IntCounterVec,
+    /// Writes retried after a failure, by operation
+    pub writer_retries: IntCounterVec,
+    /// Writes to the target that failed after all retries, by operation: set, touch or delete
+    pub writer_failures: IntCounterVec,
+    /// Values over the maximum value size, by action: rejected, truncated or chunked
+    pub oversize_values: IntCounterVec,
+    /// Keys rejected because their captures are not allowed, by source
+    pub rejected_keys: IntCounterVec,
+    /// Values of shadow sources compared to the primary's, by shadow source and match or mismatch
+    pub shadow_comparisons: IntCounterVec,
+}
+
+impl Metrics {
+    fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let metrics = Self {
+            gets: IntCounterVec::new(
+                Opts::new("platypus_gets_total", "Get requests"),
+                &["protocol", "result"],
+            )?,
+            source_call_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "platypus_source_call_duration_seconds",
+                    "Time taken to call a source",
+                ),
+                &["source"],
+            )?,
+            source_errors: IntCounterVec::new(
+                Opts::new(
+                    "platypus_source_errors_total",
+                    "Source calls that returned no value",
+                ),
+                &["source"],
+            )?,
+            refreshes: IntCounterVec::new(
+                Opts::new(
+                    "platypus_refreshes_total",
+                    "Background refreshes of monitored keys",
+                ),
+                &["source"],
+            )?,
+            tasks_created: IntCounter::new(
+                "platypus_tasks_created_total",
+                "Monitor tasks created",
+            )?,
+            tasks_evicted: IntCounterVec::new(
+                Opts::new("platypus_tasks_evicted_total", "Monitor tasks removed"),
+                &["reason"],
+            )?,
+            tasks: IntGauge::new("platypus_tasks", "Monitor tasks")?,
+            tasks_weighted_size: IntGauge::new(
+                "platypus_tasks_weighted_size_bytes",
+                "Estimated memory used by monitor tasks",
+            )?,
+            target_connections: IntGaugeVec::new(
+                Opts::new(
+                    "platypus_target_connections",
+                    "Open connections to each target node",
+                ),
+                &["target"],
+            )?,
+            writer_queue_depth: IntGauge::new(
+                "platypus_writer_queue_depth",
+                "Writes waiting to be sent to the target",
+            )?,
+            writer_queue_wait: Histogram::with_opts(HistogramOpts::new(
+                "platypus_writer_queue_wait_seconds",
+                "Time writes waited to be sent to the target",
+            ))?,
+            writer_dropped: IntCounter::new(
+                "platypus_writer_dropped_total",
+                "Writes dropped because the queue was full",
+            )?,
+            writer_coalesced: IntCounter::new(
+                "platypus_writer_coalesced_total",
+                "Writes replaced by a newer write to the same key before being sent",
+            )?,
+            writer_write_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "platypus_writer_write_duration_seconds",
+                    "Time taken to write to the target",
+                ),
+                &["op"],
+            )?,
+            writer_conflicts: IntCounterVec::new(
+                Opts::new(
+                    "platypus_writer_conflicts_total",
+                    "Guarded writes skipped because the key changed on the target",
+                ),
+                &["op"],
+            )?,
+            writer_retries: IntCounterVec::new(
+                Opts::new(
+                    "platypus_writer_retries_total",
+                    "Writes to the target retried after a failure",
+                ),
+                &["op"],
+            )?,
+            writer_failures: IntCounterVec::new(
+                Opts::new(
+                    "platypus_writer_failures_total",
+                    "Writes to the target that failed after all retries",
+                ),
+                &["op"],
+            )?,
+            oversize_values: IntCounterVec::new(
+                Opts::new(
+                    "platypus_oversize_values_total",
+                    "Values over the maximum value size",
+                ),
+                &["action"],
+            )?,
+            rejected_keys: IntCounterVec::new(
+                Opts::new(
+                    "platypus_rejected_keys_total",
+                    "Keys rejected because their captures are not allowed",
+                ),
+                &["source"],
+            )?,
+            shadow_comparisons: IntCounterVec::new(
+                Opts::new(
+                    "platypus_shadow_comparisons_total",
+                    "Values of shadow sources compared to the primary source's",
+                ),
+                &["source", "result"],
+            )?,
+        };
+
+        registry.register(Box::new(metrics.gets.clone()))?;
+        registry.register(Box::new(metrics.source_call_duration.clone()))?;
+        registry.register(Box::new(metrics.source_errors.clone()))?;
+        registry.register(Box::new(metrics.refreshes.clone()))?;
+        registry.register(Box::new(metrics.tasks_created.clone()))?;
+        registry.register(Box::new(metrics.tasks_evicted.clone()))?;
+        registry.register(Box::new(metrics.tasks.clone()))?;
+        registry.register(Box::new(metrics.tasks_weighted_size.clone()))?;
+        registry.register(Box::new(metrics.target_connections.clone()))?;
+        registry.register(Box::new(metrics.writer_queue_depth.clone()))?;
+        registry.register(Box::new(metrics.writer_queue_wait.clone()))?;
+        registry.register(Box::new(metrics.writer_dropped.clone()))?;
+        registry.register(Box::new(metrics.writer_coalesced.clone()))?;
+        registry.register(Box::new(metrics.writer_write_duration.clone()))?;
+        registry.register(Box::new(metrics.writer_conflicts.clone()))?;
+        registry.register(Box::new(metrics.writer_retries.clone()))?;
+        registry.register(Box::new(metrics.writer_failures.clone()))?;
+        registry.register(Box::new(metrics.oversize_values.clone()))?;
+        registry.register(Box::new(metrics.rejected_keys.clone()))?;
+        registry.register(Box::new(metrics.shadow_comparisons.clone()))?;
+        Ok(metrics)
+    }
+}
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+static METRICS: LazyLock<Metrics> =
+    LazyLock::new(|| Metrics::new(&REGISTRY).expect("failed to register metrics"));
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+pub fn registry() -> &'static Registry {
+    // Make sure the metrics are registered before anything is gathered
+    LazyLock::force(&METRICS);
+    &REGISTRY
+}
+
+/// Renders all registered metrics in the Prometheus text format
+pub fn encode() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&registry().gather(), &mut buffer) {
+        tracing::error!(error = %e, "Failed to encode metrics");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+pub fn protocol_label(protocol: &ProtocolType) -> &'static str {
+    match protocol {
+        ProtocolType::Text => "text",
+        ProtocolType::Binary { .. } => "binary",
+        ProtocolType::Meta => "meta",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode() {
+        metrics().gets.with_label_values(&["text", "hit"]).inc();
+        let text = encode();
+        assert!(text.contains("# TYPE platypus_gets_total counter"));
+        assert!(text.contains(r#"platypus_gets_total{protocol="text",result="hit"}"#));
+        assert!(text.contains("platypus_writer_queue_depth"));
+    }
+
+    #[test]
+    fn test_protocol_label() {
+        assert_eq!(protocol_label(&ProtocolType::Text), "text");
+        assert_eq!(
+            protocol_label(&ProtocolType::Binary { opaque: 1 }),
+            "binary"
+        );
+        assert_eq!(protocol_label(&ProtocolType::Meta), "meta");
+    }
+}
@@ -1,10 +1,25 @@
-use crate::router::Router;
-use crate::{Source, Sources, Value};
-use crate::{request::Request, response::Response, writer::Writer};
+use crate::metrics::metrics;
+use crate::router::{Fetch, Overrides, Router, Rule};
+use crate::{Compression, Encoded, Error, Source, Sources, Value};
+use crate::{namespace::Namespace, request::Request, response::Response, target::TargetSet};
+use futures::StreamExt;
 use moka::future::Cache;
+use moka::notification::RemovalCause;
+use moka::ops::compute::{CompResult, Op};
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::sync::Arc;
 use tokio::time::Instant;
-use tracing::debug;
+use tracing::{Instrument, Span, debug, field, info_span};
+
+// Tasks polled at once by a tick, so a burst of due tasks doesn't flood the sources
+const MAX_CONCURRENT_POLLS: usize = 64;
+
+fn hash_value(value: &Value, flags: u32) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    flags.hash(&mut hasher);
+    hasher.finish()
+}
 
 #[derive(Clone)]
 pub struct MonitorTask {
@@ -19,8 +34,32 @@ pub struct MonitorTask {
     // Result
     source: Arc<Box<dyn Source>>,
 
+    // Name the source is configured under
+    source_name: String,
+
     // The target where updated values will be written to
-    target: Option<Arc<Writer>>,
+    target: Option<Arc<TargetSet>>,
+
+    // The key the value is written to in the target, if not the request's key
+    target_key: Option<String>,
+
+    // Keys of requested aliases, written along with the target key
+    aliases: Vec<String>,
+
+    // Hash of the value and flags last written to the target, so unchanged values only extend its TTL
+    last_written: Option<u64>,
+
+    // How values are compressed for the target and for clients
+    compression: Option<Compression>,
+
+    // Client flags set by the route, instead of the ones the source sets
+    flags: Option<u32>,
+
+    // Last result as written to the target and returned to clients
+    last_encoded: Option<Encoded>,
+
+    // Settings from the route, applied on top of the source's response
+    overrides: Overrides,
 }
 
 impl MonitorTask {
@@ -30,7 +69,15 @@ impl MonitorTask {
             request,
             last_response: None,
             source,
+            source_name: String::new(),
             target: None,
+            target_key: None,
+            aliases: Vec::new(),
+            last_written: None,
+            compression: None,
+            flags: None,
+            last_encoded: None,
+            overrides: Overrides::default(),
         }
     }
 
@@ -46,14 +93,54 @@ impl MonitorTask {
         }
     }
 
+    /// Last result with its item flags, compressed if configured
+    pub fn last_encoded(&self) -> Option<Encoded> {
+        self.last_encoded.clone()
+    }
+
+    fn encode(&self, value: &str, flags: u32) -> Encoded {
+        let mut encoded = match &self.compression {
+            Some(compression) => compression.encode(value),
+            None => Encoded::from(value),
+        };
+        encoded.flags |= flags;
+        encoded
+    }
+
     pub async fn get(&mut self) -> Option<Value> {
         debug!("get");
-        let response = self.source.call(&self.request).await;
-        if let Some(target) = &self.target {
-            let value = response.value();
-            let _ = target.send(self.request.key(), value, response.ttl());
+        let started = Instant::now();
+        let span = info_span!("source", source = self.source_name());
+        let response = self.source.call(&self.request).instrument(span).await;
+        let response = self.overrides.apply(response);
+        metrics()
+            .source_call_duration
+            .with_label_values(&[self.source_name()])
+            .observe(started.elapsed().as_secs_f64());
+        if response.value().is_none() {
+            metrics()
+                .source_errors
+                .with_label_values(&[self.source_name()])
+                .inc();
         }
         let ret = response.value();
+        let flags = self.flags.unwrap_or(response.flags());
+        // Unchanged values are not compressed again
+        let hash = ret.as_ref().map(|value| hash_value(value, flags));
+        if hash.is_none() || hash != self.last_written {
+            self.last_encoded = ret.as_deref().map(|value| self.encode(value, flags));
+        }
+        if let Some(target) = &self.target {
+            for key in self.target_keys() {
+                let _ = match &self.last_encoded {
+                    Some(encoded) if hash == self.last_written => {
+                        target.touch(key, encoded.clone(), response.ttl()).await
+                    }
+                    encoded => target.send(key, encoded.clone(), response.ttl()).await,
+                };
+            }
+        }
+        self.last_written = hash;
         self.last_response = Some(response);
         ret
     }
@@ -61,16 +148,26 @@ impl MonitorTask {
     // Returns time this should be polled next.
     // If None is returned, then it has expired.
     pub async fn poll(&mut self) -> Option<Instant> {
-        if let Some(response) = &self.last_response {
+        if self.overrides.fetch() == Fetch::Once {
+            // Kept until the value written to the target expires, then fetched again on a miss
+            let written_until = self
+                .last_response
+                .as_ref()
+                .map(|r| r.updated_at() + r.ttl());
+            return written_until.filter(|until| Instant::now() < *until);
+        }
+        if let (Some(poll_until), Some(next_poll)) = (self.expires_at(), self.next_poll()) {
             // Check if expired
-            let poll_until = self.last_touch + response.expiry();
             if Instant::now().gt(&poll_until) {
                 return None;
             }
 
             // Check whether to poll
-            let next_poll = response.updated_at() + (response.ttl() / 2);
             if Instant::now().gt(&next_poll) {
+                metrics()
+                    .refreshes
+                    .with_label_values(&[self.source_name()])
+                    .inc();
                 let _ = self.get().await;
             }
             Some(next_poll)
@@ -80,21 +177,115 @@ impl MonitorTask {
         }
     }
 
-    pub fn with_target(mut self, target: Arc<Writer>) -> Self {
+    // Takes the result of polling a copy of this task, keeping what changed
+    // here in the meantime, e.g. aliases, touches and the request
+    fn merge_poll(&mut self, polled: MonitorTask) {
+        if polled.last_refresh() > self.last_refresh() {
+            self.last_response = polled.last_response;
+            self.last_written = polled.last_written;
+            self.last_encoded = polled.last_encoded;
+        }
+    }
+
+    pub fn with_target(mut self, target: Arc<TargetSet>) -> Self {
         self.target = Some(target);
         self
     }
 
+    pub fn with_target_key(mut self, target_key: String) -> Self {
+        self.target_key = Some(target_key);
+        self
+    }
+
+    pub fn target_key(&self) -> &str {
+        self.target_key.as_deref().unwrap_or(self.request.key())
+    }
+
+    /// Also writes values to `alias`, the target key of a key rewritten to
+    /// this task's key. The current value is written to it right away.
+    pub async fn add_alias(&mut self, alias: String) {
+        if alias == self.target_key() || self.aliases.contains(&alias) {
+            return;
+        }
+        if let (Some(target), Some(encoded), Some(response)) =
+            (&self.target, &self.last_encoded, &self.last_response)
+        {
+            let _ = target
+                .send(&alias, Some(encoded.clone()), response.ttl())
+                .await;
+        }
+        self.aliases.push(alias);
+    }
+
+    pub fn aliases(&self) -> &[String] {
+        &self.aliases
+    }
+
+    // Every key values are written to
+    fn target_keys(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.target_key()).chain(self.aliases.iter().map(String::as_str))
+    }
+
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    pub fn with_flags(mut self, flags: Option<u32>) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn with_overrides(mut self, overrides: Overrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    pub fn with_source_name(mut self, source_name: &str) -> Self {
+        self.source_name = source_name.to_string();
+        self
+    }
+
     pub fn request(&self) -> &Request {
         &self.request
     }
 
+    pub fn source_name(&self) -> &str {
+        self.source_name.as_str()
+    }
+
+    // Time the source was last called
+    pub fn last_refresh(&self) -> Option<Instant> {
+        self.last_response.as_ref().map(|r| r.updated_at())
+    }
+
+    // Time the source will be called again
+    pub fn next_poll(&self) -> Option<Instant> {
+        self.last_response
+            .as_ref()
+            .map(|r| r.updated_at() + (r.ttl() / 2))
+    }
+
+    // Time polling stops unless the key is requested again
+    pub fn expires_at(&self) -> Option<Instant> {
+        self.last_response
+            .as_ref()
+            .map(|r| self.last_touch + r.expiry())
+    }
+
     /// Estimates the memory size of this MonitorTask in bytes
     pub fn estimated_size(&self) -> u32 {
         let mut size = 0u32;
 
         // Size of the key in the request
         size += self.request.key().len() as u32;
+        for alias in &self.aliases {
+            size += alias.len() as u32;
+        }
 
         // Size of captures in the request (approximate)
         for (k, v) in self.request.captures() {
@@ -105,6 +296,9 @@ impl MonitorTask {
         if let Some(value) = &self.last_result() {
             size += value.len() as u32;
         }
+        if let Some(encoded) = &self.last_encoded {
+            size += encoded.data.len() as u32;
+        }
 
         // Fixed overhead for the struct itself (approximate)
         // Instant + Option<Response> + Arc pointers
@@ -114,6 +308,33 @@ impl MonitorTask {
     }
 }
 
+// Rejects `request` if its captures break the rule's constraints or the source refuses them
+fn validate(rule: &Rule, request: &Request, sources: &Arc<Sources>) -> Result<(), Error> {
+    let result = rule
+        .validate(request)
+        .and_then(|_| match sources.get(rule.source()) {
+            // Sources that pick other sources look them up through the request
+            Some(source) => source.validate(&request.clone().with_sources(sources.clone())),
+            None => Ok(()),
+        });
+    result.map_err(|reason| {
+        debug!(key = request.key(), reason = reason, "Rejected key");
+        metrics()
+            .rejected_keys
+            .with_label_values(&[rule.source()])
+            .inc();
+        Error::InvalidKey(reason)
+    })
+}
+
+// The key the value for `request` is written to, with the rule's prefix if it has one
+fn target_key(namespace: &Namespace, rule: &Rule, request: &Request) -> String {
+    match rule.overrides().prefix() {
+        Some(prefix) => namespace.clone().with_prefix(prefix).target_key(request),
+        None => namespace.target_key(request),
+    }
+}
+
 #[derive(Clone)]
 pub struct MonitorTasks {
     tasks: Cache<String, MonitorTask>,
@@ -130,58 +351,404 @@ impl MonitorTasks {
         let tasks = Cache::builder()
             .max_capacity(max_bytes)
             .weigher(|_key: &String, value: &MonitorTask| value.estimated_size())
+            .eviction_listener(|_key, _value, cause| {
+                if cause == RemovalCause::Size {
+                    metrics().tasks_evicted.with_label_values(&["size"]).inc();
+                }
+            })
             .build();
         Self { tasks }
     }
 
+    /// The value for `key`, from its task or from a new one. Keys whose
+    /// captures the rule or source does not allow are an error.
     pub async fn get_or_create_task(
         &self,
         key: &str,
         router: Arc<Router>,
         sources: Arc<Sources>,
-        target_writer: &Option<Arc<Writer>>,
-    ) -> Option<Value> {
+        targets: &Option<Arc<TargetSet>>,
+        namespace: &Namespace,
+    ) -> Result<Option<Encoded>, Error> {
+        let span = info_span!(
+            "get",
+            key = key,
+            route = field::Empty,
+            source = field::Empty
+        );
+        self.get_or_create_task_inner(key, router, sources, targets, namespace)
+            .instrument(span)
+            .await
+    }
+
+    async fn get_or_create_task_inner(
+        &self,
+        key: &str,
+        router: Arc<Router>,
+        sources: Arc<Sources>,
+        targets: &Option<Arc<TargetSet>>,
+        namespace: &Namespace,
+    ) -> Result<Option<Encoded>, Error> {
         // Try to get existing task
         if let Some(mut task) = self.tasks.get(key).await {
+            Span::current().record("source", task.source_name());
             task.touch();
-            if let Some(value) = task.last_result() {
+            if let Some(encoded) = task.last_encoded() {
                 // Update the cache with the touched task
                 self.tasks.insert(key.to_string(), task).await;
-                return Some(value);
+                return Ok(Some(encoded));
+            }
+        }
+
+        let Some((request, rule)) = router.rule(key) else {
+            return Ok(None);
+        };
+        Span::current().record("route", rule.pattern());
+        Span::current().record("source", rule.source().as_str());
+        validate(rule, &request, &sources)?;
+
+        // Keys rewritten to another key share its task, which also writes them
+        if let Some(canonical) = rule.rewrite(&request).filter(|canonical| canonical != key) {
+            let alias = target_key(namespace, rule, &request);
+            let mut aliases = vec![alias.clone()];
+            if let Some(mut task) = self.tasks.get(&canonical).await {
+                task.touch();
+                task.add_alias(alias).await;
+                let encoded = task.last_encoded();
+                if encoded.is_some() {
+                    self.tasks.insert(canonical, task).await;
+                    return Ok(encoded);
+                }
+                aliases = task.aliases;
+            }
+            match router.rule(&canonical) {
+                Some((request, rule)) => {
+                    validate(rule, &request, &sources)?;
+                    return Ok(self
+                        .create_task(
+                            &canonical,
+                            (request, rule),
+                            aliases,
+                            sources,
+                            targets,
+                            namespace,
+                        )
+                        .await);
+                }
+                None => {
+                    debug!(key = ?key, canonical = ?canonical, "Rewritten key matches no route");
+                }
             }
         }
 
-        // Create new task if not found or no result
+        Ok(self
+            .create_task(
+                key,
+                (request, rule),
+                Vec::new(),
+                sources,
+                targets,
+                namespace,
+            )
+            .await)
+    }
+
+    async fn create_task(
+        &self,
+        key: &str,
+        (request, rule): (Request, &Rule),
+        aliases: Vec<String>,
+        sources: Arc<Sources>,
+        targets: &Option<Arc<TargetSet>>,
+        namespace: &Namespace,
+    ) -> Option<Encoded> {
         debug!(key= ?key, "New MonitorTask");
-        if let Some((request, rule)) = router.rule(key) {
-            if let Some(source) = sources.get(rule.source()) {
-                let request_with_sources = request.with_sources(sources.clone());
-                let mut monitor_task = MonitorTask::new(source.clone(), request_with_sources);
-                if let Some(target_writer) = target_writer {
-                    monitor_task = monitor_task.with_target(target_writer.clone());
+        let source = sources.get(rule.source())?;
+        let target_key = target_key(namespace, rule, &request);
+        let request_with_sources = request.with_sources(sources.clone());
+        let mut monitor_task = MonitorTask::new(source.clone(), request_with_sources)
+            .with_source_name(rule.source())
+            .with_compression(rule.compression())
+            .with_flags(rule.flags())
+            .with_overrides(rule.overrides().clone());
+        if let Some(targets) = targets {
+            monitor_task = monitor_task
+                .with_target_key(target_key)
+                .with_target(targets.clone());
+            monitor_task.aliases = aliases;
+        }
+        monitor_task.touch();
+        monitor_task.get().await;
+        let encoded = monitor_task.last_encoded();
+        metrics().tasks_created.inc();
+        self.tasks.insert(key.to_string(), monitor_task).await;
+        encoded
+    }
+
+    /// Re-routes every task after the router or sources were replaced.
+    ///
+    /// A task is kept when its key still routes to the same source object with
+    /// the same captures, compression, flags and overrides. Other tasks are
+    /// removed, and will be recreated from the new configuration on their next
+    /// miss. Returns (kept, retired).
+    pub async fn reconcile(&self, router: &Router, sources: &Arc<Sources>) -> (usize, usize) {
+        let mut kept = 0;
+        let mut retired = 0;
+        for (key, task) in self.tasks.iter() {
+            let unchanged = match router.rule(&key) {
+                Some((request, rule)) => {
+                    sources
+                        .get(rule.source())
+                        .is_some_and(|source| Arc::ptr_eq(source, &task.source))
+                        && request.captures() == task.request.captures()
+                        && rule.compression() == task.compression
+                        && rule.flags() == task.flags
+                        && *rule.overrides() == task.overrides
+                        && rule.validate(&request).is_ok()
                 }
-                monitor_task.touch();
-                let value = monitor_task.get().await;
-                self.tasks.insert(key.to_string(), monitor_task).await;
-                value
+                None => false,
+            };
+
+            if unchanged {
+                // Merge sources look up other sources through the request
+                let mut task = task;
+                task.request = task.request.with_sources(sources.clone());
+                self.tasks.insert(key.as_ref().clone(), task).await;
+                kept += 1;
             } else {
-                None
+                debug!(key = ?key, "Retiring MonitorTask");
+                self.tasks.invalidate(key.as_ref()).await;
+                metrics()
+                    .tasks_evicted
+                    .with_label_values(&["retired"])
+                    .inc();
+                retired += 1;
             }
-        } else {
-            None
         }
+        (kept, retired)
+    }
+
+    /// Returns a copy of all tasks currently being monitored
+    pub fn tasks(&self) -> Vec<MonitorTask> {
+        self.tasks.iter().map(|(_, task)| task).collect()
+    }
+
+    /// Returns the keys of all tasks currently being monitored
+    pub fn keys(&self) -> Vec<String> {
+        self.tasks
+            .iter()
+            .map(|(key, _)| key.as_ref().clone())
+            .collect()
     }
 
+    /// Refreshes the tasks that are due, and removes the ones no client has
+    /// asked for within their expiry.
     pub async fn tick(&self) {
-        // Iterate through all cached tasks and check if they're expired
-        // Moka doesn't have a direct way to iterate, so we'll use run_pending_tasks
-        // to ensure any scheduled evictions are processed
         self.tasks.run_pending_tasks().await;
 
-        // Note: We can't easily iterate over Moka entries to check expiry.
-        // Instead, expired tasks will be lazily removed when accessed or during
-        // normal eviction. If we need explicit expiry checking, we would need to
-        // maintain a separate index of keys, but that adds complexity.
-        // For now, relying on the TTL/expiry logic in get_or_create_task is sufficient.
+        let mut polls = futures::stream::iter(self.tasks.iter())
+            .map(|(key, mut task)| async move {
+                let next_poll = task.poll().await;
+                (key, task, next_poll)
+            })
+            .buffer_unordered(MAX_CONCURRENT_POLLS);
+        while let Some((key, polled, next_poll)) = polls.next().await {
+            let result = self
+                .tasks
+                .entry(key.as_ref().clone())
+                .and_compute_with(|current| async move {
+                    // Skip tasks removed while polling, e.g. by a reload
+                    let Some(current) = current else {
+                        return Op::Nop;
+                    };
+                    let mut current = current.into_value();
+                    // Tasks touched while polling are not expired
+                    if next_poll.is_none() && current.last_touch <= polled.last_touch {
+                        return Op::Remove;
+                    }
+                    current.merge_poll(polled);
+                    Op::Put(current)
+                })
+                .await;
+            if let CompResult::Removed(_) = result {
+                debug!(key = ?key, "MonitorTask expired");
+                metrics()
+                    .tasks_evicted
+                    .with_label_values(&["expired"])
+                    .inc();
+            }
+        }
+
+        self.tasks.run_pending_tasks().await;
+        metrics().tasks.set(self.tasks.entry_count() as i64);
+        metrics()
+            .tasks_weighted_size
+            .set(self.tasks.weighted_size() as i64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::MonitorConfig;
+    use crate::source::Echo;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    async fn monitor(source_name: &str, ttl: Duration, expiry: Duration) -> MonitorTasks {
+        let mut echo = Echo::new();
+        *echo = MonitorConfig::new(ttl, expiry);
+        let source: Arc<Box<dyn Source>> = Arc::new(Box::new(echo));
+        let sources = Arc::new(HashMap::from([(source_name.to_string(), source)]));
+        let router = Arc::new(Router::new().route("^key$", source_name));
+
+        let monitor_tasks = MonitorTasks::new();
+        monitor_tasks
+            .get_or_create_task("key", router, sources, &None, &Namespace::new())
+            .await
+            .unwrap();
+        monitor_tasks
+    }
+
+    #[tokio::test]
+    async fn test_tick_refreshes_due_tasks() {
+        let monitor_tasks = monitor("tick_due", Duration::ZERO, Duration::from_secs(60)).await;
+        let refreshes = || metrics().refreshes.with_label_values(&["tick_due"]).get();
+        let before = refreshes();
+
+        monitor_tasks.tick().await;
+        assert_eq!(refreshes(), before + 1);
+        assert_eq!(monitor_tasks.keys(), vec!["key".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_tick_removes_expired_tasks() {
+        let monitor_tasks = monitor("tick_expired", Duration::ZERO, Duration::ZERO).await;
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        monitor_tasks.tick().await;
+        assert!(monitor_tasks.keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_poll_result_keeps_changes_made_while_polling() {
+        let source: Arc<Box<dyn Source>> = Arc::new(Box::new(Echo::new()));
+        let mut current = MonitorTask::new(source, Request::new("key"));
+        current.get().await;
+        let mut polled = current.clone();
+        polled.get().await;
+
+        current.add_alias("alias".to_string()).await;
+        current.merge_poll(polled.clone());
+        assert_eq!(current.aliases(), ["alias"]);
+        assert_eq!(current.last_refresh(), polled.last_refresh());
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_value_is_touched() {
+        let (address, server) =
+            crate::writer::tests::fake_memcached(vec!["STORED\r\n", "HD kkey\r\n"]).await;
+        let writer = crate::Writer::builder(&address).with_pool_size(1).build();
+        let writer = Arc::new(TargetSet::single(writer));
+        let source: Arc<Box<dyn Source>> = Arc::new(Box::new(Echo::new()));
+        let mut task = MonitorTask::new(source, Request::new("key")).with_target(writer.clone());
+
+        task.get().await;
+        // Let the first write go out, so the second is not folded into it
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        task.get().await;
+        writer.shutdown().await;
+
+        let commands = server.await.unwrap();
+        assert!(commands[0].starts_with("set key "));
+        assert!(commands[1].starts_with("mg key T"));
+    }
+
+    #[tokio::test]
+    async fn test_route_overrides() {
+        let source: Arc<Box<dyn Source>> = Arc::new(Box::new(Echo::new()));
+        let sources = Arc::new(HashMap::from([("route_overrides".to_string(), source)]));
+        let overrides = Overrides::new()
+            .with_ttl(Duration::from_secs(5))
+            .with_fetch(Fetch::Once);
+        let rule = crate::router::Rule::new("^hot/.+$", "route_overrides")
+            .unwrap()
+            .with_overrides(overrides);
+        let router = Arc::new(
+            Router::new()
+                .with_rule(rule)
+                .route("^cold/.+$", "route_overrides"),
+        );
+
+        let monitor_tasks = MonitorTasks::new();
+        for key in ["hot/a", "cold/a"] {
+            monitor_tasks
+                .get_or_create_task(
+                    key,
+                    router.clone(),
+                    sources.clone(),
+                    &None,
+                    &Namespace::new(),
+                )
+                .await
+                .unwrap();
+        }
+        let mut tasks = monitor_tasks.tasks();
+        tasks.sort_by(|a, b| a.request().key().cmp(b.request().key()));
+        let (cold, hot) = (&tasks[0], &tasks[1]);
+        assert_eq!(
+            hot.next_poll().unwrap() - hot.last_refresh().unwrap(),
+            Duration::from_millis(2500)
+        );
+        assert_eq!(cold.next_poll(), cold.last_refresh());
+
+        // Fetched once, so the hot key is not refreshed while its value lives
+        let refreshes = || {
+            metrics()
+                .refreshes
+                .with_label_values(&["route_overrides"])
+                .get()
+        };
+        let before = refreshes();
+        let mut hot = hot.clone();
+        assert!(hot.poll().await.is_some());
+        assert_eq!(refreshes(), before);
+    }
+
+    #[tokio::test]
+    async fn test_rewritten_keys_share_a_task() {
+        let source: Arc<Box<dyn Source>> = Arc::new(Box::new(Echo::new().with_template("{name}")));
+        let sources = Arc::new(HashMap::from([("rewrite".to_string(), source)]));
+        let rule = crate::router::Rule::new("^(?<name>[^@]+)@v\\d+$", "rewrite")
+            .unwrap()
+            .with_rewrite("{name}");
+        let router = Arc::new(
+            Router::new()
+                .with_rule(rule)
+                .route("^(?<name>[^@]+)$", "rewrite"),
+        );
+        let memory = crate::InMemory::new();
+        let targets = Some(Arc::new(TargetSet::single(memory.clone())));
+
+        let monitor_tasks = MonitorTasks::new();
+        for key in ["a@v1", "a@v2", "a@v2"] {
+            let encoded = monitor_tasks
+                .get_or_create_task(
+                    key,
+                    router.clone(),
+                    sources.clone(),
+                    &targets,
+                    &Namespace::new(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(encoded, Some(Encoded::from("a")));
+        }
+        monitor_tasks.tasks.run_pending_tasks().await;
+        assert_eq!(monitor_tasks.keys(), vec!["a".to_string()]);
+        assert_eq!(monitor_tasks.tasks()[0].aliases(), ["a@v1", "a@v2"]);
+        for key in ["a", "a@v1", "a@v2"] {
+            assert_eq!(memory.get(key), Some(Encoded::from("a")));
+        }
     }
 }
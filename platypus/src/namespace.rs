@@ -0,0 +1,103 @@
+//! Keys as clients ask for them, as they are routed, and as they are written
+//! to the target.
+//!
+//! With a prefix, several apps can share one target without their keys
+//! colliding. Values are written under `{prefix}{key}` by default, or under a
+//! `target_key` template that can also use the route's captures.
+
+use crate::{Request, replace_placeholders};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Namespace {
+    prefix: String,
+    strip_prefix: bool,
+    target_key: Option<String>,
+}
+
+impl Namespace {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Removes the prefix from requested keys before routing them, for when
+    /// clients ask for the prefixed key, e.g. on a miss through a proxy
+    pub fn with_strip_prefix(mut self, strip_prefix: bool) -> Self {
+        self.strip_prefix = strip_prefix;
+        self
+    }
+
+    /// Template for the key values are written to, with `{prefix}`, `{key}`
+    /// and the route's captures as placeholders
+    pub fn with_target_key(mut self, template: &str) -> Self {
+        self.target_key = Some(template.to_string());
+        self
+    }
+
+    pub fn prefix(&self) -> &str {
+        &self.prefix
+    }
+
+    /// The key to route for a key a client asked for
+    pub fn route_key<'a>(&self, key: &'a str) -> &'a str {
+        if self.strip_prefix {
+            key.strip_prefix(self.prefix.as_str()).unwrap_or(key)
+        } else {
+            key
+        }
+    }
+
+    /// The key the value for `request` is written to on the target
+    pub fn target_key(&self, request: &Request) -> String {
+        if let Some(template) = &self.target_key {
+            let mut variables = request.captures().clone();
+            variables.insert("prefix".to_string(), self.prefix.clone());
+            variables.insert("key".to_string(), request.key().to_string());
+            match replace_placeholders(template, &variables) {
+                Ok(target_key) => return target_key,
+                // Checked when the configuration loads, so only when embedded
+                Err(e) => tracing::error!(template = template, error = e, "Invalid target key"),
+            }
+        }
+        format!("{}{}", self.prefix, request.key())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    #[test]
+    fn test_default_is_unchanged() {
+        let namespace = Namespace::new();
+        assert_eq!(namespace.route_key("a/b"), "a/b");
+        assert_eq!(namespace.target_key(&Request::new("a/b")), "a/b");
+    }
+
+    #[test]
+    fn test_prefix() {
+        let namespace = Namespace::new().with_prefix("app/");
+        assert_eq!(namespace.route_key("app/a"), "app/a");
+        assert_eq!(namespace.target_key(&Request::new("a")), "app/a");
+
+        let namespace = namespace.with_strip_prefix(true);
+        assert_eq!(namespace.route_key("app/a"), "a");
+        // Keys without the prefix are routed as they are
+        assert_eq!(namespace.route_key("other/a"), "other/a");
+    }
+
+    #[test]
+    fn test_target_key_template() {
+        let re = Regex::new("^(?<instance>[^/]+)/config$").unwrap();
+        let request = Request::match_regex(&re, "web/config").unwrap();
+        let namespace = Namespace::new()
+            .with_prefix("app")
+            .with_target_key("{prefix}:{instance}:{key}");
+        assert_eq!(namespace.target_key(&request), "app:web:web/config");
+    }
+}
@@ -38,6 +38,24 @@ pub enum Command {
     Quit,
 }
 
+impl Command {
+    /// Command name as it appears on the wire, for logs and spans
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Get(_) => "get",
+            Command::Gets(_) => "gets",
+            Command::Gat(_, _) => "gat",
+            Command::Gats(_, _) => "gats",
+            Command::MetaGet(_, _) => "mg",
+            Command::MetaNoOp => "mn",
+            Command::Version => "version",
+            Command::Stats(_) => "stats",
+            Command::Touch(_, _) => "touch",
+            Command::Quit => "quit",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum MetaFlag {
     // Flags without tokens
@@ -96,7 +114,7 @@ pub enum Response {
 impl Response {
     pub fn serialize(&self, protocol: &ProtocolType) -> Vec<u8> {
         match protocol {
-            ProtocolType::Text | ProtocolType::Meta => self.format().into_bytes(),
+            ProtocolType::Text | ProtocolType::Meta => self.serialize_text(),
             ProtocolType::Binary { opaque } => self.serialize_binary(*opaque),
         }
     }
@@ -108,33 +126,50 @@ impl Response {
         })
     }
 
-    pub fn format(&self) -> String {
+    // Values are written as they are, since they need not be UTF-8, e.g. when compressed
+    fn serialize_text(&self) -> Vec<u8> {
+        let mut result = Vec::new();
         match self {
             Response::Value(item) => {
-                format!(
-                    "VALUE {} {} {}\r\n{}\r\n",
-                    item.key,
-                    item.flags,
-                    item.data.len(),
-                    String::from_utf8_lossy(&item.data)
-                )
+                result.extend(
+                    format!("VALUE {} {} {}\r\n", item.key, item.flags, item.data.len()).bytes(),
+                );
+                push_data(&mut result, &item.data);
             }
             Response::Values(items) => {
-                let mut result = String::new();
                 for item in items {
-                    result.push_str(&format!(
-                        "VALUE {} {} {}",
-                        item.key,
-                        item.flags,
-                        item.data.len()
-                    ));
+                    result.extend(
+                        format!("VALUE {} {} {}", item.key, item.flags, item.data.len()).bytes(),
+                    );
                     if let Some(cas) = item.cas {
-                        result.push_str(&format!(" {}", cas));
+                        result.extend(format!(" {}", cas).bytes());
                     }
-                    result.push_str(&format!("\r\n{}\r\n", String::from_utf8_lossy(&item.data)));
+                    result.extend(b"\r\n");
+                    push_data(&mut result, &item.data);
                 }
-                result.push_str("END\r\n");
-                result
+                result.extend(b"END\r\n");
+            }
+            Response::MetaValue(item, flags) => {
+                result.extend(format!("VA {}", item.data.len()).bytes());
+                for flag in flags {
+                    result.push(b' ');
+                    match flag {
+                        MetaFlag::ReturnFlags => result.extend(format!("f{}", item.flags).bytes()),
+                        flag => result.extend(flag.format_response().bytes()),
+                    }
+                }
+                result.extend(b"\r\n");
+                push_data(&mut result, &item.data);
+            }
+            response => result = response.format().into_bytes(),
+        }
+        result
+    }
+
+    pub fn format(&self) -> String {
+        match self {
+            Response::Value(_) | Response::Values(_) | Response::MetaValue(_, _) => {
+                String::from_utf8_lossy(&self.serialize_text()).into_owned()
             }
             Response::End => "END\r\n".to_string(),
             Response::Stored => "STORED\r\n".to_string(),
@@ -156,15 +191,6 @@ impl Response {
                 result
             }
             // Meta responses
-            Response::MetaValue(item, flags) => {
-                let mut result = format!("VA {}", item.data.len());
-                for flag in flags {
-                    result.push(' ');
-                    result.push_str(&flag.format_response());
-                }
-                result.push_str(&format!("\r\n{}\r\n", String::from_utf8_lossy(&item.data)));
-                result
-            }
             Response::MetaHit(flags) => {
                 let mut result = "HD".to_string();
                 for flag in flags {
@@ -180,6 +206,11 @@ impl Response {
     }
 }
 
+fn push_data(result: &mut Vec<u8>, data: &[u8]) {
+    result.extend_from_slice(data);
+    result.extend(b"\r\n");
+}
+
 impl MetaFlag {
     pub fn format_response(&self) -> String {
         match self {
@@ -381,4 +412,26 @@ mod tests {
         assert!(result.is_err());
         assert!(result.unwrap_err().to_string().contains("empty data"));
     }
+
+    #[test]
+    fn test_values_are_written_as_bytes() {
+        let item = Item {
+            key: "key".to_string(),
+            flags: 2,
+            exptime: 0,
+            data: vec![0x78, 0x9c, 0xff],
+            cas: None,
+        };
+        let response = Response::Values(vec![item.clone()]);
+        assert_eq!(
+            response.serialize(&ProtocolType::Text),
+            b"VALUE key 2 3\r\n\x78\x9c\xff\r\nEND\r\n"
+        );
+
+        let response = Response::MetaValue(item, vec![MetaFlag::ReturnFlags]);
+        assert_eq!(
+            response.serialize(&ProtocolType::Meta),
+            b"VA 3 f2\r\n\x78\x9c\xff\r\n"
+        );
+    }
 }
@@ -3,11 +3,18 @@ use regex::Regex;
 use std::collections::HashMap;
 use std::sync::Arc;
 
+/// How deep sources may call other sources for one request, e.g. a merge of
+/// merges, before giving up
+pub const MAX_DEPTH: usize = 16;
+
 #[derive(Clone)]
 pub struct Request {
     key: String,
     captures: HashMap<String, String>,
     sources: Option<Arc<Sources>>,
+
+    // Number of sources between the route and the one handling this request
+    depth: usize,
 }
 
 impl Request {
@@ -18,6 +25,7 @@ impl Request {
             key: key.into(),
             captures,
             sources: None,
+            depth: 0,
         }
     }
     pub fn match_regex(re: &Regex, key: &str) -> Option<Self> {
@@ -35,6 +43,7 @@ impl Request {
             key: key.into(),
             captures,
             sources: None,
+            depth: 0,
         })
     }
 
@@ -65,6 +74,21 @@ impl Request {
         self.captures = captures;
         self
     }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn with_depth(mut self, depth: usize) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// This request, for a source that another source calls, or None if
+    /// sources are nested too deep, which means they refer to each other
+    pub fn nested(&self) -> Option<Self> {
+        (self.depth < MAX_DEPTH).then(|| self.clone().with_depth(self.depth + 1))
+    }
 }
 
 #[cfg(test)]
@@ -87,4 +111,14 @@ mod tests {
         assert_eq!(request.key(), "abc/both");
         assert_eq!(request.get("instance"), Some("abc"));
     }
+
+    #[test]
+    fn test_nested_depth_is_limited() {
+        let mut request = Request::new("key");
+        for depth in 1..=MAX_DEPTH {
+            request = request.nested().unwrap();
+            assert_eq!(request.depth(), depth);
+        }
+        assert!(request.nested().is_none());
+    }
 }
@@ -11,11 +11,18 @@ pub struct MonitorConfig {
 
     // Refresh will keep running until this instant
     expiry: Duration,
+
+    // Client flags stored with the value, e.g. to tell clients how it is serialized
+    flags: u32,
 }
 
 impl MonitorConfig {
     pub fn new(ttl: Duration, expiry: Duration) -> Self {
-        Self { ttl, expiry }
+        Self {
+            ttl,
+            expiry,
+            flags: 0,
+        }
     }
 
     pub fn with_ttl(mut self, ttl: Duration) -> Self {
@@ -35,6 +42,15 @@ impl MonitorConfig {
     pub fn expiry(&self) -> Duration {
         self.expiry
     }
+
+    pub fn with_flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
 }
 
 #[derive(Clone)]
@@ -84,6 +100,15 @@ impl Response {
         self.expiry
     }
 
+    pub fn with_flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
     pub fn updated_at(&self) -> Instant {
         self.updated_at
     }
@@ -0,0 +1,177 @@
+//! Limits on what a rule's captures may hold.
+//!
+//! Captures are substituted into URLs, file paths and secret ids, so a
+//! pattern that accepts more than intended reaches further than intended.
+//! Keys whose captures break a constraint are rejected before any source is
+//! called.
+
+use regex::Regex;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptureType {
+    String,
+    /// Digits, optionally signed
+    Integer,
+}
+
+impl std::str::FromStr for CaptureType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "string" => Ok(CaptureType::String),
+            "integer" => Ok(CaptureType::Integer),
+            _ => Err(format!(
+                "unknown capture type '{}', expected string or integer",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Constraint {
+    values: Option<Vec<String>>,
+    capture_type: CaptureType,
+    max_length: Option<usize>,
+    chars: Option<Regex>,
+}
+
+impl Default for Constraint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PartialEq for Constraint {
+    fn eq(&self, other: &Self) -> bool {
+        self.values == other.values
+            && self.capture_type == other.capture_type
+            && self.max_length == other.max_length
+            && self.chars.as_ref().map(Regex::as_str) == other.chars.as_ref().map(Regex::as_str)
+    }
+}
+
+impl Constraint {
+    pub fn new() -> Self {
+        Self {
+            values: None,
+            capture_type: CaptureType::String,
+            max_length: None,
+            chars: None,
+        }
+    }
+
+    /// Only these values are allowed
+    pub fn with_values(mut self, values: Vec<String>) -> Self {
+        self.values = Some(values);
+        self
+    }
+
+    pub fn with_type(mut self, capture_type: CaptureType) -> Self {
+        self.capture_type = capture_type;
+        self
+    }
+
+    /// Longest value allowed, in bytes
+    pub fn with_max_length(mut self, max_length: usize) -> Self {
+        self.max_length = Some(max_length);
+        self
+    }
+
+    /// Characters allowed, e.g. `a-z0-9_-`. A `-` between two characters is a
+    /// range, every other character stands for itself.
+    pub fn with_chars(mut self, chars: &str) -> Result<Self, regex::Error> {
+        let mut class = String::new();
+        let mut previous = None;
+        for c in chars.chars() {
+            // Anything that would end the class, negate it or start a nested
+            // class or set operation (`&&`, `--`, `~~`) is escaped
+            if matches!(c, '\\' | '[' | ']' | '^' | '&' | '~')
+                || (c == '-' && previous == Some('-'))
+            {
+                class.push('\\');
+            }
+            class.push(c);
+            previous = Some(c);
+        }
+        self.chars = Some(Regex::new(&format!("^[{}]*$", class))?);
+        Ok(self)
+    }
+
+    /// Why `value`, captured as `name`, is not allowed, if it is not
+    pub fn check(&self, name: &str, value: &str) -> Result<(), String> {
+        if let Some(values) = &self.values
+            && !values.iter().any(|allowed| allowed == value)
+        {
+            return Err(format!("{} must be one of {}", name, values.join(", ")));
+        }
+        if self.capture_type == CaptureType::Integer && value.parse::<i64>().is_err() {
+            return Err(format!("{} must be an integer", name));
+        }
+        if let Some(max_length) = self.max_length
+            && value.len() > max_length
+        {
+            return Err(format!(
+                "{} must be at most {} bytes long",
+                name, max_length
+            ));
+        }
+        if let Some(chars) = &self.chars
+            && !chars.is_match(value)
+        {
+            // The class, without the anchors around it
+            let class = chars
+                .as_str()
+                .trim_start_matches('^')
+                .trim_end_matches("*$");
+            return Err(format!("{} has characters outside of {}", name, class));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values_and_type() {
+        let env = Constraint::new().with_values(vec!["prod".into(), "staging".into()]);
+        assert!(env.check("env", "prod").is_ok());
+        assert_eq!(
+            env.check("env", "dev"),
+            Err("env must be one of prod, staging".to_string())
+        );
+
+        let id = Constraint::new().with_type(CaptureType::Integer);
+        assert!(id.check("id", "-42").is_ok());
+        assert!(id.check("id", "42a").is_err());
+    }
+
+    #[test]
+    fn test_max_length_and_chars() {
+        let name = Constraint::new()
+            .with_max_length(8)
+            .with_chars("a-z0-9_-")
+            .unwrap();
+        assert!(name.check("name", "app_1").is_ok());
+        assert!(name.check("name", "much_too_long").is_err());
+        assert!(name.check("name", "../etc").is_err());
+    }
+
+    #[test]
+    fn test_chars_are_literal() {
+        // Neither `]` nor `^` can end or negate the class
+        let brackets = Constraint::new().with_chars("a]|[^.*").unwrap();
+        assert!(brackets.check("name", "a]^").is_ok());
+        assert!(brackets.check("name", "b").is_err());
+
+        let backslash = Constraint::new().with_chars("\\d").unwrap();
+        assert!(backslash.check("name", "\\d").is_ok());
+        assert!(backslash.check("name", "1").is_err());
+
+        let operators = Constraint::new().with_chars("a&&b~~c").unwrap();
+        assert!(operators.check("name", "a&b~c").is_ok());
+    }
+}
@@ -0,0 +1,415 @@
+use crate::{Compression, Request, replace_placeholders};
+use regex::{Regex, RegexSet};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use tracing::warn;
+
+mod constraint;
+mod overlap;
+mod overrides;
+pub use constraint::{CaptureType, Constraint};
+pub use overlap::Overlap;
+pub use overrides::{Fetch, Overrides};
+
+macro_rules! panic_on_err {
+    ($expr:expr) => {
+        match $expr {
+            Ok(x) => x,
+            Err(err) => panic!("{err}"),
+        }
+    };
+}
+
+pub enum Error {}
+
+pub struct Rule {
+    patten: Regex,
+    source: String,
+    compression: Option<Compression>,
+    flags: Option<u32>,
+    overrides: Overrides,
+    rewrite: Option<String>,
+    constraints: Vec<(String, Constraint)>,
+}
+
+impl Rule {
+    pub fn new(pattern: &str, source: impl Into<String>) -> Result<Self, regex::Error> {
+        let re = Regex::new(pattern)?;
+        Ok(Self {
+            patten: re,
+            source: source.into(),
+            compression: None,
+            flags: None,
+            overrides: Overrides::default(),
+            rewrite: None,
+            constraints: Vec::new(),
+        })
+    }
+
+    /// Compresses values fetched for keys matching this rule
+    pub fn with_compression(mut self, compression: Option<Compression>) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Client flags for values fetched for keys matching this rule, instead
+    /// of the ones the source sets
+    pub fn with_flags(mut self, flags: Option<u32>) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// TTL, expiry, fetch mode and target prefix for keys matching this
+    /// rule, instead of the source's and the server's
+    pub fn with_overrides(mut self, overrides: Overrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Template for the canonical key of keys matching this rule, with
+    /// `{key}` and the rule's captures as placeholders. Keys with the same
+    /// canonical key share one fetch.
+    pub fn with_rewrite(mut self, template: &str) -> Self {
+        self.rewrite = Some(template.to_string());
+        self
+    }
+
+    /// Rejects keys whose capture `name` breaks `constraint`
+    pub fn with_constraint(mut self, name: &str, constraint: Constraint) -> Self {
+        self.constraints.push((name.to_string(), constraint));
+        self
+    }
+
+    pub fn is_match(&self, key: &str) -> bool {
+        self.patten.is_match(key)
+    }
+
+    pub fn match_key(&self, key: &str) -> Option<Request> {
+        Request::match_regex(&self.patten, key)
+    }
+
+    pub fn source(&self) -> &String {
+        &self.source
+    }
+
+    pub fn pattern(&self) -> &str {
+        self.patten.as_str()
+    }
+
+    pub fn compression(&self) -> Option<Compression> {
+        self.compression
+    }
+
+    pub fn flags(&self) -> Option<u32> {
+        self.flags
+    }
+
+    pub fn overrides(&self) -> &Overrides {
+        &self.overrides
+    }
+
+    /// Why the captures of `request` are not allowed, if they are not
+    pub fn validate(&self, request: &Request) -> Result<(), String> {
+        for (name, constraint) in &self.constraints {
+            if let Some(value) = request.get(name) {
+                constraint.check(name, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The canonical key for `request`, if this rule rewrites keys
+    pub fn rewrite(&self, request: &Request) -> Option<String> {
+        let template = self.rewrite.as_ref()?;
+        let mut variables = request.captures().clone();
+        variables.insert("key".to_string(), request.key().to_string());
+        // Checked when the configuration loads, so only when embedded
+        replace_placeholders(template, &variables)
+            .inspect_err(|e| tracing::error!(template = template, error = e, "Invalid rewrite"))
+            .ok()
+    }
+}
+
+/// Routes keys to the first rule that matches them.
+///
+/// Rules are compiled into a matcher on the first lookup, so routing
+/// stays fast with hundreds of rules.
+pub struct Router {
+    rules: Vec<Rule>,
+    matcher: OnceLock<Matcher>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self {
+            rules: Vec::new(),
+            matcher: OnceLock::new(),
+        }
+    }
+
+    /// Adds a rule, panicking if `pattern` is not a valid regex. Use
+    /// [`Router::try_route`] for patterns that are not known to be valid.
+    pub fn route(self, pattern: &str, source: impl Into<String>) -> Self {
+        panic_on_err!(self.try_route(pattern, source))
+    }
+
+    /// Adds a rule, or fails if `pattern` is not a valid regex
+    pub fn try_route(self, pattern: &str, source: impl Into<String>) -> Result<Self, regex::Error> {
+        Ok(self.with_rule(Rule::new(pattern, source)?))
+    }
+
+    pub fn with_rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self.matcher = OnceLock::new();
+        self
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Pairs of rules that match some of the same keys, where the earlier
+    /// one wins
+    pub fn overlaps(&self) -> Vec<Overlap> {
+        overlap::overlaps(&self.rules)
+    }
+
+    pub fn rule(&self, key: &str) -> Option<(Request, &Rule)> {
+        let matcher = self.matcher.get_or_init(|| Matcher::new(&self.rules));
+        let rule = &self.rules[matcher.first_match(&self.rules, key)?];
+        rule.match_key(key).map(|request| (request, rule))
+    }
+}
+
+// Narrows a key down to the rules that can match it. Rules anchored to a
+// literal prefix are looked up by the key's prefixes, and the others are
+// matched together with a RegexSet.
+struct Matcher {
+    // Indexes of the rules with each literal prefix
+    prefixed: HashMap<String, Vec<usize>>,
+
+    // Distinct lengths of those prefixes
+    prefix_lengths: Vec<usize>,
+
+    // Indexes of the rules without a literal prefix
+    unprefixed: Vec<usize>,
+
+    // Their patterns, or None if they are too many to compile together
+    unprefixed_set: Option<RegexSet>,
+}
+
+impl Matcher {
+    fn new(rules: &[Rule]) -> Self {
+        let mut prefixed: HashMap<String, Vec<usize>> = HashMap::new();
+        let mut unprefixed = Vec::new();
+        for (index, rule) in rules.iter().enumerate() {
+            match literal_prefix(rule.pattern()) {
+                Some(prefix) => prefixed.entry(prefix).or_default().push(index),
+                None => unprefixed.push(index),
+            }
+        }
+
+        let mut prefix_lengths: Vec<usize> = prefixed.keys().map(String::len).collect();
+        prefix_lengths.sort_unstable();
+        prefix_lengths.dedup();
+
+        let patterns = unprefixed.iter().map(|&index| rules[index].pattern());
+        let unprefixed_set = match RegexSet::new(patterns) {
+            Ok(set) => Some(set),
+            Err(e) => {
+                warn!(error = %e, "Failed to compile routes together, matching them one by one");
+                None
+            }
+        };
+
+        Self {
+            prefixed,
+            prefix_lengths,
+            unprefixed,
+            unprefixed_set,
+        }
+    }
+
+    // Index of the first rule matching `key`
+    fn first_match(&self, rules: &[Rule], key: &str) -> Option<usize> {
+        let mut candidates: Vec<usize> = self
+            .prefix_lengths
+            .iter()
+            .filter_map(|&len| key.get(..len))
+            .filter_map(|prefix| self.prefixed.get(prefix))
+            .flatten()
+            .copied()
+            .collect();
+        candidates.sort_unstable();
+
+        // Candidates ahead of every unprefixed rule are tried without the RegexSet
+        let first_unprefixed_rule = self.unprefixed.first().copied().unwrap_or(usize::MAX);
+        let (ahead, behind) =
+            candidates.split_at(candidates.partition_point(|&index| index < first_unprefixed_rule));
+        if let Some(index) = ahead
+            .iter()
+            .copied()
+            .find(|&index| rules[index].is_match(key))
+        {
+            return Some(index);
+        }
+
+        let first_unprefixed = match &self.unprefixed_set {
+            Some(set) => set.matches(key).iter().next().map(|i| self.unprefixed[i]),
+            None => self
+                .unprefixed
+                .iter()
+                .copied()
+                .find(|&index| rules[index].is_match(key)),
+        };
+        // The rest only win if they come before it
+        behind
+            .iter()
+            .copied()
+            .take_while(|&index| first_unprefixed.is_none_or(|first| index < first))
+            .find(|&index| rules[index].is_match(key))
+            .or(first_unprefixed)
+    }
+}
+
+// The literal text every key matching `pattern` starts with, if the pattern
+// is anchored to the start and begins with any
+fn literal_prefix(pattern: &str) -> Option<String> {
+    let rest = pattern.strip_prefix('^')?;
+    if has_top_level_alternation(rest) {
+        return None;
+    }
+
+    let mut prefix = String::new();
+    let mut chars = rest.chars().peekable();
+    while let Some(ch) = chars.next() {
+        let literal = match ch {
+            '\\' => match chars.next() {
+                // Escaped punctuation is literal, escapes like \d are classes
+                Some(next) if !next.is_ascii_alphanumeric() => next,
+                _ => break,
+            },
+            '.' | '+' | '*' | '?' | '(' | ')' | '[' | ']' | '{' | '}' | '|' | '^' | '$' => break,
+            ch => ch,
+        };
+        match chars.peek() {
+            // The character may be missing
+            Some('?' | '*' | '{') => break,
+            // The character is there at least once
+            Some('+') => {
+                prefix.push(literal);
+                break;
+            }
+            _ => prefix.push(literal),
+        }
+    }
+    (!prefix.is_empty()).then_some(prefix)
+}
+
+fn has_top_level_alternation(pattern: &str) -> bool {
+    let mut depth = 0;
+    let mut in_class = false;
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => {
+                chars.next();
+            }
+            '[' => in_class = true,
+            ']' => in_class = false,
+            '(' if !in_class => depth += 1,
+            ')' if !in_class => depth -= 1,
+            '|' if !in_class && depth == 0 => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_literal_prefix() {
+        assert_eq!(
+            literal_prefix("^echo/(?<name>.+)$").as_deref(),
+            Some("echo/")
+        );
+        assert_eq!(literal_prefix("^a\\.b/(.+)").as_deref(), Some("a.b/"));
+        assert_eq!(literal_prefix("^abc?/").as_deref(), Some("ab"));
+        assert_eq!(literal_prefix("^ab+c").as_deref(), Some("ab"));
+        assert_eq!(literal_prefix("^ab\\d").as_deref(), Some("ab"));
+        assert_eq!(literal_prefix("echo/(.+)"), None);
+        assert_eq!(literal_prefix("^(?i)echo/"), None);
+        assert_eq!(literal_prefix("^a/b|c/d"), None);
+        assert_eq!(literal_prefix("^a/(b|c)").as_deref(), Some("a/"));
+    }
+
+    #[test]
+    fn test_first_match_wins() {
+        let router = Router::new()
+            .route("^a/special$", "special")
+            .route("/special$", "suffix")
+            .route("^a/(?<name>.+)$", "a")
+            .route("^a/b/(?<name>.+)$", "ab")
+            .route("(?<name>.+)", "fallback");
+
+        let source = |key| router.rule(key).map(|(_, rule)| rule.source().clone());
+        assert_eq!(source("a/special").as_deref(), Some("special"));
+        assert_eq!(source("b/special").as_deref(), Some("suffix"));
+        assert_eq!(source("a/b/c").as_deref(), Some("a"));
+        assert_eq!(source("c").as_deref(), Some("fallback"));
+
+        let (request, _) = router.rule("a/b/c").unwrap();
+        assert_eq!(
+            request.captures().get("name").map(String::as_str),
+            Some("b/c")
+        );
+    }
+
+    #[test]
+    fn test_try_route() {
+        let router = Router::new().try_route("^a/(?<name>.+)$", "a").unwrap();
+        assert_eq!(router.rules().len(), 1);
+        assert!(router.try_route("^b/(?<name>.+$", "b").is_err());
+    }
+
+    #[test]
+    fn test_matches_linear_scan() {
+        let patterns = [
+            "^t1/config/(?<name>.+)$",
+            "^t1/(?<name>[^/]+)$",
+            "^t2/",
+            "\\.json$",
+            "^t1/config/x$",
+            "^t\\d/(?<name>.+)$",
+            "^(t3|t4)/(?<name>.+)$",
+        ];
+        let router = patterns.iter().fold(Router::new(), |router, pattern| {
+            router.route(pattern, *pattern)
+        });
+        let keys = [
+            "t1/config/x",
+            "t1/a",
+            "t1/a.json",
+            "t2/a",
+            "t3/a",
+            "t4/b",
+            "t5/a",
+            "x.json",
+            "",
+            "t",
+        ];
+        for key in keys {
+            let expected = router.rules().iter().find(|rule| rule.is_match(key));
+            let actual = router.rule(key).map(|(_, rule)| rule);
+            assert_eq!(
+                actual.map(Rule::pattern),
+                expected.map(Rule::pattern),
+                "key {}",
+                key
+            );
+        }
+    }
+}
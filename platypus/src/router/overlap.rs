@@ -0,0 +1,212 @@
+//! Finding rules that match some of the same keys.
+//!
+//! Only the first of two overlapping rules gets the keys both match, which is
+//! easy to get wrong in a large route table. Two rules overlap when the
+//! product of their DFAs reaches a key both accept; the shortest such key is
+//! kept as an example.
+
+use super::{Rule, literal_prefix};
+use regex_automata::dfa::{Automaton, dense};
+use regex_automata::util::{primitives::StateID, start};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+
+// Product states explored before giving up on a pair of rules
+const MAX_STATES: usize = 20_000;
+
+/// Two rules matching some of the same keys
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overlap {
+    /// Index of the rule that wins for the keys both match
+    pub earlier: usize,
+    pub later: usize,
+    /// A key both match
+    pub example: String,
+    /// The later rule matches no key the earlier one does not, so it is never used
+    pub shadowed: bool,
+}
+
+/// Overlapping pairs of `rules`, in the order of the later rule
+pub fn overlaps(rules: &[Rule]) -> Vec<Overlap> {
+    let prefixes: Vec<Option<String>> = rules
+        .iter()
+        .map(|rule| literal_prefix(rule.pattern()))
+        .collect();
+    let mut dfas: HashMap<usize, Option<dense::DFA<Vec<u32>>>> = HashMap::new();
+    let mut overlaps = Vec::new();
+
+    for later in 0..rules.len() {
+        for earlier in 0..later {
+            // Keys with different literal prefixes cannot match both
+            if let (Some(a), Some(b)) = (&prefixes[earlier], &prefixes[later])
+                && !a.starts_with(b.as_str())
+                && !b.starts_with(a.as_str())
+            {
+                continue;
+            }
+            for index in [earlier, later] {
+                dfas.entry(index)
+                    .or_insert_with(|| dense::DFA::new(rules[index].pattern()).ok());
+            }
+            let (Some(a), Some(b)) = (&dfas[&earlier], &dfas[&later]) else {
+                continue;
+            };
+            if let Some((example, shadowed)) = compare(a, b) {
+                overlaps.push(Overlap {
+                    earlier,
+                    later,
+                    example,
+                    shadowed,
+                });
+            }
+        }
+    }
+    overlaps
+}
+
+// Where one DFA is along a key
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Progress {
+    Live(StateID),
+    Matched,
+    // Can no longer match
+    Dead,
+}
+
+impl Progress {
+    fn start(dfa: &dense::DFA<Vec<u32>>) -> Option<Self> {
+        dfa.start_state(&start::Config::new())
+            .ok()
+            .map(Progress::Live)
+    }
+
+    fn next(self, dfa: &dense::DFA<Vec<u32>>, byte: u8) -> Self {
+        match self {
+            Progress::Live(id) => Self::of(dfa, dfa.next_state(id, byte)),
+            done => done,
+        }
+    }
+
+    // Whether the key read so far matches, once it ends
+    fn matches_at_end(self, dfa: &dense::DFA<Vec<u32>>) -> bool {
+        match self {
+            Progress::Live(id) => dfa.is_match_state(dfa.next_eoi_state(id)),
+            Progress::Matched => true,
+            Progress::Dead => false,
+        }
+    }
+
+    fn of(dfa: &dense::DFA<Vec<u32>>, id: StateID) -> Self {
+        if dfa.is_match_state(id) {
+            Progress::Matched
+        } else if dfa.is_dead_state(id) || dfa.is_quit_state(id) {
+            Progress::Dead
+        } else {
+            Progress::Live(id)
+        }
+    }
+}
+
+// Where each DFA is along the same key
+type Pair = (Progress, Progress);
+
+// Bytes in the order examples are built from, readable ones first
+fn example_bytes() -> impl Iterator<Item = u8> {
+    (b'a'..=b'z')
+        .chain(b'0'..=b'9')
+        .chain(b'/'..=b'/')
+        .chain((0x20..=0x7e).filter(|b: &u8| !b.is_ascii_alphanumeric() && *b != b'/'))
+        .chain((0x80..=0xff).chain(0x00..0x20).chain(0x7f..=0x7f))
+}
+
+// The shortest key both DFAs match, and whether every key `b` matches is
+// also matched by `a`
+fn compare(a: &dense::DFA<Vec<u32>>, b: &dense::DFA<Vec<u32>>) -> Option<(String, bool)> {
+    let start = (Progress::start(a)?, Progress::start(b)?);
+    // How each pair of states was first reached, to rebuild the example
+    let mut parents: HashMap<Pair, Option<(Pair, u8)>> = HashMap::from([(start, None)]);
+    let mut queue = VecDeque::from([start]);
+    let mut both = None;
+    let mut only_b = false;
+
+    while let Some(state) = queue.pop_front() {
+        let (at_a, at_b) = (state.0.matches_at_end(a), state.1.matches_at_end(b));
+        if at_a && at_b && both.is_none() {
+            both = Some(state);
+        }
+        if at_b && !at_a {
+            only_b = true;
+        }
+        if both.is_some() && only_b {
+            break;
+        }
+        if parents.len() > MAX_STATES {
+            // Too many to be sure `b` is covered
+            only_b = true;
+            if both.is_some() {
+                break;
+            }
+            continue;
+        }
+        for byte in example_bytes() {
+            let next = (state.0.next(a, byte), state.1.next(b, byte));
+            if next.0 == Progress::Dead && next.1 == Progress::Dead {
+                continue;
+            }
+            if let Entry::Vacant(entry) = parents.entry(next) {
+                entry.insert(Some((state, byte)));
+                queue.push_back(next);
+            }
+        }
+    }
+
+    let mut state = both?;
+    let mut example = Vec::new();
+    while let Some(Some((parent, byte))) = parents.get(&state) {
+        example.push(*byte);
+        state = *parent;
+    }
+    example.reverse();
+    Some((String::from_utf8_lossy(&example).into_owned(), !only_b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(patterns: &[&str]) -> Vec<Rule> {
+        patterns
+            .iter()
+            .map(|pattern| Rule::new(pattern, "source").unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_disjoint_rules() {
+        let rules = rules(&[
+            "^a/(?<x>.+)$",
+            "^b/(?<x>.+)$",
+            "^[cd]/[^.]+$",
+            "^[cd]/.+\\.json$",
+        ]);
+        assert_eq!(overlaps(&rules), vec![]);
+    }
+
+    #[test]
+    fn test_overlap() {
+        let rules = rules(&["^a/(?<x>[^/]+)$", "(?<x>.+)$"]);
+        let overlaps = overlaps(&rules);
+        assert_eq!(overlaps.len(), 1);
+        assert_eq!(overlaps[0].example, "a/a");
+        assert!(!overlaps[0].shadowed);
+    }
+
+    #[test]
+    fn test_shadowed() {
+        let rules = rules(&["^a/(?<x>.+)$", "^a/b/(?<x>\\w+)$"]);
+        let overlaps = overlaps(&rules);
+        assert_eq!(overlaps.len(), 1);
+        assert_eq!(overlaps[0].example, "a/b/a");
+        assert!(overlaps[0].shadowed);
+    }
+}
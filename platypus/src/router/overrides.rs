@@ -0,0 +1,124 @@
+//! Settings a rule applies on top of what its source returns, so one source
+//! can serve routes that need different refresh rates.
+
+use crate::Response;
+use tokio::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Fetch {
+    /// Keep refreshing the value until it expires
+    #[default]
+    Poll,
+    /// Fetch the value on a miss and write it once, without refreshing it
+    Once,
+}
+
+impl std::str::FromStr for Fetch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "poll" => Ok(Fetch::Poll),
+            "once" => Ok(Fetch::Once),
+            _ => Err(format!("unknown fetch mode '{}', expected poll or once", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Overrides {
+    ttl: Option<Duration>,
+    expiry: Option<Duration>,
+    negative_ttl: Option<Duration>,
+    fetch: Fetch,
+    prefix: Option<String>,
+}
+
+impl Overrides {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = Some(ttl);
+        self
+    }
+
+    pub fn with_expiry(mut self, expiry: Duration) -> Self {
+        self.expiry = Some(expiry);
+        self
+    }
+
+    /// TTL when the source returns no value, so misses are retried sooner,
+    /// or later, than values are refreshed
+    pub fn with_negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = Some(negative_ttl);
+        self
+    }
+
+    pub fn with_fetch(mut self, fetch: Fetch) -> Self {
+        self.fetch = fetch;
+        self
+    }
+
+    /// Prefix of the target key, instead of the server's
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = Some(prefix.to_string());
+        self
+    }
+
+    pub fn fetch(&self) -> Fetch {
+        self.fetch
+    }
+
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    /// `response` with this rule's TTL and expiry in place of the source's
+    pub fn apply(&self, mut response: Response) -> Response {
+        if let Some(ttl) = self.ttl {
+            response = response.with_ttl(ttl);
+        }
+        if let Some(expiry) = self.expiry {
+            response = response.with_expiry(expiry);
+        }
+        if let Some(negative_ttl) = self.negative_ttl
+            && response.value().is_none()
+        {
+            response = response.with_ttl(negative_ttl);
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let response = Response::new()
+            .with_ttl(Duration::from_secs(300))
+            .with_expiry(Duration::from_secs(3600));
+
+        let unchanged = Overrides::new().apply(response.clone());
+        assert_eq!(unchanged.ttl(), Duration::from_secs(300));
+
+        let overrides = Overrides::new()
+            .with_ttl(Duration::from_secs(5))
+            .with_negative_ttl(Duration::from_secs(1));
+        let miss = overrides.apply(response.clone());
+        assert_eq!(miss.ttl(), Duration::from_secs(1));
+        assert_eq!(miss.expiry(), Duration::from_secs(3600));
+
+        let hit = overrides.apply(response.with_value("value".to_string()));
+        assert_eq!(hit.ttl(), Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_fetch_from_str() {
+        assert_eq!("once".parse::<Fetch>(), Ok(Fetch::Once));
+        assert!("push".parse::<Fetch>().is_err());
+    }
+}
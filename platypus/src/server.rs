@@ -1,26 +1,128 @@
+use crate::handoff::{self, Listener};
 use crate::monitor::MonitorTasks;
 use crate::protocol::{self, ParseError};
+use crate::stats::Stats;
 use anyhow::Result;
 use std::error::Error;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::MetadataExt;
 use std::sync::Arc;
-use tokio::io::{AsyncWriteExt, BufReader};
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, UnixListener};
 use tokio::signal::unix::{SignalKind, signal};
-use tokio::sync::Notify;
-use tokio::time::Duration;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
 use tower::Service as TowerService;
-use tracing::{error, info, warn};
+use tracing::{Instrument, error, info, info_span, warn};
 
 #[derive(Clone)]
 pub enum SocketType {
     Tcp(String),
     Unix(String),
+    Inherited(Arc<OwnedFd>),
+}
+
+/// What to do with new connections once `max_connections` is reached
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+    /// Accept the connection, reply with `SERVER_ERROR` and close it
+    Reject,
+
+    /// Stop accepting until a connection closes
+    Backpressure,
+}
+
+#[derive(Clone)]
+struct ConnectionLimiter {
+    semaphore: Arc<Semaphore>,
+    policy: OverflowPolicy,
+
+    // Set while accepting is paused, so each pause is only counted once
+    paused: Arc<AtomicBool>,
+}
+
+impl ConnectionLimiter {
+    fn new(max_connections: usize, policy: OverflowPolicy) -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(max_connections)),
+            policy,
+            paused: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Called before accepting. Under backpressure this waits for a free slot,
+    // so the listener is not polled while the server is full.
+    async fn reserve(&self, stats: &Stats) -> Option<OwnedSemaphorePermit> {
+        match self.policy {
+            OverflowPolicy::Reject => None,
+            OverflowPolicy::Backpressure => {
+                if self.semaphore.available_permits() == 0 && !self.paused.swap(true, Relaxed) {
+                    stats.listen_disabled();
+                }
+                let permit = self.semaphore.clone().acquire_owned().await.ok();
+                self.paused.store(false, Relaxed);
+                permit
+            }
+        }
+    }
+
+    // Called after accepting. Returns None if there is no slot for the connection.
+    fn admit(&self, reserved: Option<OwnedSemaphorePermit>) -> Option<OwnedSemaphorePermit> {
+        reserved.or_else(|| self.semaphore.clone().try_acquire_owned().ok())
+    }
+}
+
+// Keeps the connection counted in stats, and its slot taken, until dropped
+struct ConnectionGuard {
+    stats: Arc<Stats>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionGuard {
+    fn new(stats: Arc<Stats>, permit: Option<OwnedSemaphorePermit>) -> Self {
+        stats.connection_opened();
+        Self {
+            stats,
+            _permit: permit,
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.stats.connection_closed();
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Timeouts {
+    // Maximum time to wait for a client to start sending a command
+    idle: Option<Duration>,
+
+    // Maximum time to receive the rest of a command once it has started
+    read: Option<Duration>,
+}
+
+enum NextCommand {
+    Command(Result<protocol::CommandContext>),
+    Closed,
+    IdleTimeout,
+    ReadTimeout,
 }
 
 pub struct Server {
     socket_config: SocketType,
     notify_shutdown: Arc<Notify>,
+    close_connections: CancellationToken,
     monitor_tasks: Option<MonitorTasks>,
+    stats: Arc<Stats>,
+    max_connections: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    timeouts: Timeouts,
+    upgrade_socket: Option<String>,
+    drain_timeout: Duration,
 }
 
 impl Server {
@@ -32,11 +134,7 @@ impl Server {
     /// # Returns
     /// A new Server instance ready for configuration
     pub fn bind(listen_address: &str) -> Self {
-        Self {
-            socket_config: SocketType::Tcp(listen_address.to_owned()),
-            notify_shutdown: Arc::new(Notify::new()),
-            monitor_tasks: None,
-        }
+        Self::with_socket(SocketType::Tcp(listen_address.to_owned()))
     }
 
     /// Creates a new Server instance bound to the specified Unix socket path.
@@ -47,10 +145,33 @@ impl Server {
     /// # Returns
     /// A new Server instance ready for configuration
     pub fn bind_unix(socket_path: &str) -> Self {
+        Self::with_socket(SocketType::Unix(socket_path.to_owned()))
+    }
+
+    /// Creates a new Server instance that accepts on an already listening socket,
+    /// e.g. one inherited through systemd socket activation or an upgrade handoff.
+    ///
+    /// # Arguments
+    /// * `listener` - A listening TCP or Unix socket
+    ///
+    /// # Returns
+    /// A new Server instance ready for configuration
+    pub fn from_fd(listener: OwnedFd) -> Self {
+        Self::with_socket(SocketType::Inherited(Arc::new(listener)))
+    }
+
+    fn with_socket(socket_config: SocketType) -> Self {
         Self {
-            socket_config: SocketType::Unix(socket_path.to_owned()),
+            socket_config,
             notify_shutdown: Arc::new(Notify::new()),
+            close_connections: CancellationToken::new(),
             monitor_tasks: None,
+            stats: Arc::new(Stats::new()),
+            max_connections: None,
+            overflow_policy: OverflowPolicy::Reject,
+            timeouts: Timeouts::default(),
+            upgrade_socket: None,
+            drain_timeout: Duration::from_secs(30),
         }
     }
 
@@ -59,6 +180,52 @@ impl Server {
         self
     }
 
+    /// Shares connection counters with the `Service` so they show up in `stats`
+    pub fn with_stats(&mut self, stats: Arc<Stats>) -> &mut Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Limits the number of concurrent client connections.
+    ///
+    /// # Arguments
+    /// * `max_connections` - Maximum number of connections served at once
+    /// * `policy` - Whether to reject connections over the limit or stop accepting
+    pub fn with_max_connections(
+        &mut self,
+        max_connections: usize,
+        policy: OverflowPolicy,
+    ) -> &mut Self {
+        self.max_connections = Some(max_connections);
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Closes connections that have not sent a command for this long
+    pub fn with_idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeouts.idle = Some(timeout);
+        self
+    }
+
+    /// Closes connections that take longer than this to send a complete command
+    pub fn with_read_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeouts.read = Some(timeout);
+        self
+    }
+
+    /// Listens on a Unix socket for a new platypus process asking to take over.
+    /// The listener and monitored keys are handed over, then this server drains.
+    pub fn with_upgrade_socket(&mut self, path: &str) -> &mut Self {
+        self.upgrade_socket = Some(path.to_owned());
+        self
+    }
+
+    /// Maximum time to wait for open connections to finish when shutting down
+    pub fn with_drain_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
     /// Starts the memcached server and handles incoming connections.
     ///
     /// This method starts the TCP server, sets up signal handling for graceful shutdown,
@@ -95,6 +262,30 @@ impl Server {
                 let _ = std::fs::remove_file(path);
                 (None, Some(UnixListener::bind(path)?))
             }
+            SocketType::Inherited(fd) => {
+                info!(fd = fd.as_raw_fd(), "Starting server on inherited listener");
+                match Listener::from_fd(fd.try_clone()?)? {
+                    Listener::Tcp(listener) => (Some(TcpListener::from_std(listener)?), None),
+                    Listener::Unix(listener) => (None, Some(UnixListener::from_std(listener)?)),
+                }
+            }
+        };
+        let listener_fd = match (&tcp_listener, &unix_listener) {
+            (Some(listener), _) => listener.as_raw_fd(),
+            (_, Some(listener)) => listener.as_raw_fd(),
+            (None, None) => unreachable!(),
+        };
+
+        let upgrade_listener = match &self.upgrade_socket {
+            Some(path) => {
+                info!("Accepting upgrades on {}", path);
+                let _ = std::fs::remove_file(path);
+                let listener = UnixListener::bind(path)?;
+                // Remembered so we don't unlink the socket of the process we hand off to
+                let inode = std::fs::metadata(path)?.ino();
+                Some((listener, inode))
+            }
+            None => None,
         };
 
         // Trigger shutdown on Ctrl+C
@@ -115,63 +306,221 @@ impl Server {
             }
         });
 
-        let mut monitor_interval = tokio::time::interval(Duration::from_secs(1));
+        // Refresh monitored keys in the background, so slow sources don't hold up accepting
+        let monitor = self.monitor_tasks.clone().map(|monitor_tasks| {
+            tokio::spawn(async move {
+                let mut monitor_interval = tokio::time::interval(Duration::from_secs(1));
+                loop {
+                    monitor_interval.tick().await;
+                    monitor_tasks.tick().await;
+                }
+            })
+        });
         let service = Arc::new(tokio::sync::Mutex::new(service));
 
+        let limiter = self.max_connections.map(|max_connections| {
+            self.stats.set_max_connections(max_connections);
+            ConnectionLimiter::new(max_connections, self.overflow_policy)
+        });
+
         loop {
             tokio::select! {
                 _ = self.notify_shutdown.notified() => {
                     break;
                 }
 
-                _ = monitor_interval.tick() => {
-                    if let Some(ref monitor_tasks) = self.monitor_tasks {
-                        monitor_tasks.tick().await;
+                // Hand the listener over to a new process, then drain
+                Ok((stream, _)) = async {
+                    match &upgrade_listener {
+                        Some((listener, _)) => listener.accept().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    match self.hand_off(stream, listener_fd).await {
+                        Ok(()) => {
+                            warn!("Listener handed off to new process, draining");
+                            break;
+                        }
+                        Err(e) => error!(error = %e, "Upgrade handoff failed"),
                     }
                 }
 
                 // Handle TCP connections
-                Ok((socket, _)) = async {
+                Ok((socket, reserved)) = async {
                     match &tcp_listener {
-                        Some(listener) => listener.accept().await,
+                        Some(listener) => {
+                            let reserved = Self::reserve(&limiter, &self.stats).await;
+                            listener.accept().await.map(|(socket, _)| (socket, reserved))
+                        }
                         None => std::future::pending().await,
                     }
                 } => {
-                    let notify = self.notify_shutdown.clone();
-                    let service = service.clone();
-
-                    tokio::spawn(async move {
-                        let (read_half, write_half) = socket.into_split();
-                        Self::handle_connection(read_half, write_half, notify, service).await;
-                    });
+                    let (read_half, write_half) = socket.into_split();
+                    self.spawn_connection(read_half, write_half, &limiter, reserved, &service);
                 }
 
                 // Handle Unix socket connections
-                Ok((socket, _)) = async {
+                Ok((socket, reserved)) = async {
                     match &unix_listener {
-                        Some(listener) => listener.accept().await,
+                        Some(listener) => {
+                            let reserved = Self::reserve(&limiter, &self.stats).await;
+                            listener.accept().await.map(|(socket, _)| (socket, reserved))
+                        }
                         None => std::future::pending().await,
                     }
                 } => {
-                    let notify = self.notify_shutdown.clone();
-                    let service = service.clone();
+                    let (read_half, write_half) = socket.into_split();
+                    self.spawn_connection(read_half, write_half, &limiter, reserved, &service);
+                }
+            }
+        }
+
+        drop(tcp_listener);
+        drop(unix_listener);
+        if let Some(monitor) = monitor {
+            monitor.abort();
+        }
+        if let (Some((_, inode)), Some(path)) = (upgrade_listener, &self.upgrade_socket) {
+            let ours = std::fs::metadata(path).is_ok_and(|metadata| metadata.ino() == inode);
+            if ours {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        self.drain().await;
+
+        Ok(())
+    }
+
+    async fn hand_off(&self, stream: tokio::net::UnixStream, listener_fd: RawFd) -> Result<()> {
+        let keys = match &self.monitor_tasks {
+            Some(monitor_tasks) => monitor_tasks.keys(),
+            None => Vec::new(),
+        };
+        info!(keys = keys.len(), "Handing off listener");
+
+        let stream = stream.into_std()?;
+        stream.set_nonblocking(false)?;
+        tokio::task::spawn_blocking(move || handoff::send(stream, listener_fd, &keys)).await?
+    }
+
+    // Asks open connections to close after their current command, and waits
+    // for them up to the drain timeout.
+    async fn drain(&self) {
+        self.close_connections.cancel();
+
+        let deadline = Instant::now() + self.drain_timeout;
+        while self.stats.curr_connections() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        let remaining = self.stats.curr_connections();
+        if remaining > 0 {
+            warn!(connections = remaining, "Drain timed out");
+        }
+    }
+
+    async fn reserve(
+        limiter: &Option<ConnectionLimiter>,
+        stats: &Stats,
+    ) -> Option<OwnedSemaphorePermit> {
+        match limiter {
+            Some(limiter) => limiter.reserve(stats).await,
+            None => None,
+        }
+    }
 
+    fn spawn_connection<R, W, S>(
+        &self,
+        read_half: R,
+        mut write_half: W,
+        limiter: &Option<ConnectionLimiter>,
+        reserved: Option<OwnedSemaphorePermit>,
+        service: &Arc<tokio::sync::Mutex<S>>,
+    ) where
+        R: tokio::io::AsyncRead + Unpin + Send + 'static,
+        W: tokio::io::AsyncWrite + Unpin + Send + 'static,
+        S: TowerService<
+                protocol::CommandContext,
+                Response = protocol::Response,
+                Error = Box<dyn Error + Send + Sync>,
+            > + Clone
+            + Send
+            + 'static,
+        S::Future: Send,
+    {
+        let permit = match limiter {
+            Some(limiter) => match limiter.admit(reserved) {
+                Some(permit) => Some(permit),
+                None => {
+                    warn!("Rejecting connection, max connections reached");
+                    self.stats.connection_rejected();
                     tokio::spawn(async move {
-                        let (read_half, write_half) = socket.into_split();
-                        Self::handle_connection(read_half, write_half, notify, service).await;
+                        let response =
+                            protocol::Response::ServerError("too many connections".to_string());
+                        let response_data = response.serialize(&protocol::ProtocolType::Text);
+                        _ = write_half.write_all(&response_data).await;
+                        _ = write_half.shutdown().await;
                     });
+                    return;
                 }
+            },
+            None => None,
+        };
+
+        let guard = ConnectionGuard::new(self.stats.clone(), permit);
+        let close = self.close_connections.clone();
+        let service = service.clone();
+        let stats = self.stats.clone();
+        let timeouts = self.timeouts;
+        let span = info_span!("connection", id = self.stats.total_connections());
+
+        tokio::spawn(
+            async move {
+                let _guard = guard;
+                Self::handle_connection(read_half, write_half, close, service, timeouts, stats)
+                    .await;
             }
+            .instrument(span),
+        );
+    }
+
+    // Waits for the next command, applying the idle timeout until the first
+    // byte arrives and the read timeout until the command is complete.
+    async fn next_command<R>(reader: &mut BufReader<R>, timeouts: Timeouts) -> NextCommand
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        let fill = reader.fill_buf();
+        let filled = match timeouts.idle {
+            Some(idle) => match tokio::time::timeout(idle, fill).await {
+                Ok(filled) => filled,
+                Err(_) => return NextCommand::IdleTimeout,
+            },
+            None => fill.await,
+        };
+        match filled {
+            Ok([]) => return NextCommand::Closed,
+            Ok(_) => {}
+            Err(e) => return NextCommand::Command(Err(e.into())),
         }
 
-        Ok(())
+        let recv = protocol::recv_command(reader);
+        match timeouts.read {
+            Some(read) => match tokio::time::timeout(read, recv).await {
+                Ok(result) => NextCommand::Command(result),
+                Err(_) => NextCommand::ReadTimeout,
+            },
+            None => NextCommand::Command(recv.await),
+        }
     }
 
     async fn handle_connection<R, W, S>(
         read_half: R,
         write_half: W,
-        notify: Arc<Notify>,
+        close: CancellationToken,
         service: Arc<tokio::sync::Mutex<S>>,
+        timeouts: Timeouts,
+        stats: Arc<Stats>,
     ) where
         R: tokio::io::AsyncRead + Unpin,
         W: tokio::io::AsyncWrite + Unpin,
@@ -190,11 +539,25 @@ impl Server {
 
         loop {
             tokio::select! {
-                _ = notify.notified() => {
+                _ = close.cancelled() => {
                     break;
                 }
 
-                data = protocol::recv_command(&mut reader) => {
+                next = Self::next_command(&mut reader, timeouts) => {
+                    let data = match next {
+                        NextCommand::Command(data) => data,
+                        NextCommand::Closed => break,
+                        NextCommand::IdleTimeout => {
+                            info!("Closing idle connection");
+                            stats.idle_kick();
+                            break;
+                        }
+                        NextCommand::ReadTimeout => {
+                            warn!("Closing connection, timed out reading command");
+                            stats.read_timeout();
+                            break;
+                        }
+                    };
                     match data {
                         Ok(command_context) => {
                             let protocol = command_context.protocol.clone();
@@ -237,7 +600,14 @@ impl Clone for Server {
         Self {
             socket_config: self.socket_config.clone(),
             notify_shutdown: self.notify_shutdown.clone(),
+            close_connections: self.close_connections.clone(),
             monitor_tasks: self.monitor_tasks.clone(),
+            stats: self.stats.clone(),
+            max_connections: self.max_connections,
+            overflow_policy: self.overflow_policy,
+            timeouts: self.timeouts,
+            upgrade_socket: self.upgrade_socket.clone(),
+            drain_timeout: self.drain_timeout,
         }
     }
 }
@@ -453,4 +823,166 @@ mod tests {
             _ => panic!("Expected Values response"),
         }
     }
+
+    #[test]
+    fn test_server_with_connection_limits() {
+        let mut server = Server::bind("127.0.0.1:11211");
+        server
+            .with_max_connections(10, OverflowPolicy::Backpressure)
+            .with_idle_timeout(Duration::from_secs(60))
+            .with_read_timeout(Duration::from_secs(5));
+
+        assert_eq!(server.max_connections, Some(10));
+        assert_eq!(server.overflow_policy, OverflowPolicy::Backpressure);
+        assert_eq!(server.timeouts.idle, Some(Duration::from_secs(60)));
+        assert_eq!(server.timeouts.read, Some(Duration::from_secs(5)));
+    }
+
+    #[tokio::test]
+    async fn test_connection_limiter_reject() {
+        let stats = Stats::new();
+        let limiter = ConnectionLimiter::new(1, OverflowPolicy::Reject);
+
+        let reserved = limiter.reserve(&stats).await;
+        assert!(reserved.is_none());
+
+        let first = limiter.admit(reserved);
+        assert!(first.is_some());
+        assert!(limiter.admit(None).is_none());
+
+        drop(first);
+        assert!(limiter.admit(None).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_connection_limiter_backpressure() {
+        let stats = Stats::new();
+        let limiter = ConnectionLimiter::new(1, OverflowPolicy::Backpressure);
+
+        let first = limiter.reserve(&stats).await;
+        assert!(first.is_some());
+
+        // The second reservation waits until the first connection is gone
+        let blocked =
+            tokio::time::timeout(Duration::from_millis(50), limiter.reserve(&stats)).await;
+        assert!(blocked.is_err());
+
+        drop(first);
+        let second = limiter.reserve(&stats).await;
+        assert!(limiter.admit(second).is_some());
+    }
+
+    async fn run_connection(
+        read_half: tokio::io::DuplexStream,
+        timeouts: Timeouts,
+        stats: Arc<Stats>,
+    ) {
+        let service = Arc::new(tokio::sync::Mutex::new(MockService {
+            should_error: false,
+        }));
+        Server::handle_connection(
+            read_half,
+            tokio::io::sink(),
+            CancellationToken::new(),
+            service,
+            timeouts,
+            stats,
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_handle_connection_closes_on_eof() {
+        let (client, server_side) = tokio::io::duplex(64);
+        drop(client);
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            run_connection(server_side, Timeouts::default(), Arc::new(Stats::new())),
+        )
+        .await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_handle_connection_idle_timeout() {
+        let (_client, server_side) = tokio::io::duplex(64);
+        let stats = Arc::new(Stats::new());
+        let timeouts = Timeouts {
+            idle: Some(Duration::from_millis(20)),
+            read: None,
+        };
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            run_connection(server_side, timeouts, stats.clone()),
+        )
+        .await;
+        assert!(result.is_ok());
+        assert!(
+            stats
+                .snapshot()
+                .contains(&("idle_kicks".to_string(), "1".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_handle_connection_read_timeout() {
+        let (mut client, server_side) = tokio::io::duplex(64);
+        let stats = Arc::new(Stats::new());
+        let timeouts = Timeouts {
+            idle: None,
+            read: Some(Duration::from_millis(20)),
+        };
+
+        // Send a partial command without the line terminator
+        client.write_all(b"get some_key").await.unwrap();
+
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            run_connection(server_side, timeouts, stats.clone()),
+        )
+        .await;
+        assert!(result.is_ok());
+        assert!(
+            stats
+                .snapshot()
+                .contains(&("read_timeouts".to_string(), "1".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_hands_off_listener() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let upgrade_socket = std::env::temp_dir()
+            .join(format!("platypus_upgrade_{}.sock", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let mut server = Server::from_fd(OwnedFd::from(listener));
+        server
+            .with_upgrade_socket(&upgrade_socket)
+            .with_drain_timeout(Duration::from_millis(100));
+        let serving = tokio::spawn(server.serve(MockService {
+            should_error: false,
+        }));
+
+        let handoff = loop {
+            match handoff::request(&upgrade_socket).await {
+                Ok(handoff) => break handoff,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+
+        // The old server stops serving once the listener is handed off
+        let result = tokio::time::timeout(Duration::from_secs(1), serving).await;
+        assert!(result.unwrap().unwrap().is_ok());
+
+        match Listener::from_fd(handoff.listener).unwrap() {
+            Listener::Tcp(listener) => assert_eq!(listener.local_addr().unwrap(), addr),
+            Listener::Unix(_) => panic!("Expected TCP listener"),
+        }
+    }
 }
@@ -1,22 +1,32 @@
 use crate::{
-    MonitorTasks, Router, Sources, Writer,
-    protocol::{self, Command, Item, Response},
+    Encoded, MonitorTasks, Namespace, Router, Sources, Stats, TargetSet, Writer,
+    metrics::{metrics, protocol_label},
+    protocol::{self, Command, Item, ProtocolType, Response},
+    target::Oversize,
 };
 use anyhow::Result;
 use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
 use std::task::{Context, Poll};
 use tower;
-use tracing::info;
+use tracing::{Instrument, info, info_span};
 
+// Router and sources are swapped together on reload
 #[derive(Clone)]
-pub struct Service {
+struct Routing {
     router: Arc<Router>,
     sources: Arc<Sources>,
+}
+
+#[derive(Clone)]
+pub struct Service {
+    routing: Arc<RwLock<Routing>>,
     monitor_tasks: MonitorTasks,
-    target_writer: Option<Arc<Writer>>,
+    targets: Option<Arc<TargetSet>>,
+    namespace: Arc<Namespace>,
+    stats: Arc<Stats>,
     version: String,
 }
 
@@ -31,22 +41,34 @@ impl tower::Service<protocol::CommandContext> for Service {
 
     fn call(&mut self, req: protocol::CommandContext) -> Self::Future {
         let service = self.clone();
-        Box::pin(async move {
-            match service.handle_command(req.command).await {
-                Ok(response) => Ok(response),
-                Err(e) => Ok(protocol::Response::Error(e.to_string())),
+        let span = info_span!(
+            "command",
+            command = req.command.name(),
+            protocol = protocol_label(&req.protocol)
+        );
+        Box::pin(
+            async move {
+                match service.handle_command(req.command, &req.protocol).await {
+                    Ok(response) => Ok(response),
+                    Err(e) => Ok(protocol::Response::Error(e.to_string())),
+                }
             }
-        })
+            .instrument(span),
+        )
     }
 }
 
 impl Service {
     pub fn new() -> Self {
         Self {
-            router: Arc::new(Router::new()),
-            sources: Arc::new(HashMap::default()),
+            routing: Arc::new(RwLock::new(Routing {
+                router: Arc::new(Router::new()),
+                sources: Arc::new(HashMap::default()),
+            })),
             monitor_tasks: MonitorTasks::new(),
-            target_writer: None,
+            targets: None,
+            namespace: Arc::new(Namespace::new()),
+            stats: Arc::new(Stats::new()),
             version: "0.0.0".into(),
         }
     }
@@ -56,18 +78,63 @@ impl Service {
         self
     }
 
-    pub fn with_router(mut self, router: Router) -> Self {
-        self.router = Arc::new(router);
+    pub fn with_router(self, router: Router) -> Self {
+        self.routing.write().unwrap().router = Arc::new(router);
+        self
+    }
+
+    pub fn with_sources(self, sources: Sources) -> Self {
+        self.routing.write().unwrap().sources = Arc::new(sources);
+        self
+    }
+
+    /// Atomically replaces the router and sources.
+    ///
+    /// Monitor tasks whose key still routes to the same source, with the same
+    /// captures, keep running. All other tasks are retired.
+    pub async fn reload(&self, router: Router, sources: Sources) {
+        let routing = Routing {
+            router: Arc::new(router),
+            sources: Arc::new(sources),
+        };
+        *self.routing.write().unwrap() = routing.clone();
+
+        let (kept, retired) = self
+            .monitor_tasks
+            .reconcile(&routing.router, &routing.sources)
+            .await;
+        info!(
+            kept = kept,
+            retired = retired,
+            "Reloaded routes and sources"
+        );
+    }
+
+    fn routing(&self) -> Routing {
+        self.routing.read().unwrap().clone()
+    }
+
+    pub fn with_target(self, target_address: &str) -> Self {
+        self.with_writer(Writer::new(target_address))
+    }
+
+    pub fn with_writer(self, writer: Writer) -> Self {
+        self.with_targets(TargetSet::single(writer))
+    }
+
+    pub fn with_targets(mut self, targets: TargetSet) -> Self {
+        self.targets = Some(Arc::new(targets));
         self
     }
 
-    pub fn with_sources(mut self, sources: Sources) -> Self {
-        self.sources = Arc::new(sources);
+    /// Maps requested keys to routed keys and target keys. Not reloaded.
+    pub fn with_namespace(mut self, namespace: Namespace) -> Self {
+        self.namespace = Arc::new(namespace);
         self
     }
 
-    pub fn with_target(mut self, target_address: &str) -> Self {
-        self.target_writer = Some(Arc::new(Writer::new(target_address)));
+    pub fn with_stats(mut self, stats: Arc<Stats>) -> Self {
+        self.stats = stats;
         self
     }
 
@@ -76,30 +143,53 @@ impl Service {
         self
     }
 
-    async fn get_or_create_monitor_task(&self, key: &str) -> Option<String> {
+    async fn get_or_create_monitor_task(&self, key: &str) -> Result<Option<Encoded>, crate::Error> {
+        let routing = self.routing();
+        let key = self.namespace.route_key(key);
         self.monitor_tasks
             .get_or_create_task(
                 key,
-                self.router.clone(),
-                self.sources.clone(),
-                &self.target_writer,
+                routing.router,
+                routing.sources,
+                &self.targets,
+                &self.namespace,
             )
             .await
     }
 
-    async fn handle_command(&self, command: Command) -> anyhow::Result<Response> {
+    // Gets the value for `key`, counting the hit or miss for `protocol`. Keys
+    // that are not allowed are a miss, so the other keys of a get are answered.
+    async fn get_value(&self, key: &str, protocol: &ProtocolType) -> Option<Encoded> {
+        let value = self
+            .get_or_create_monitor_task(key)
+            .await
+            .inspect_err(|e| tracing::debug!(key, error = %e, "Refused key"))
+            .unwrap_or(None);
+        let result = if value.is_some() { "hit" } else { "miss" };
+        metrics()
+            .gets
+            .with_label_values(&[protocol_label(protocol), result])
+            .inc();
+        value
+    }
+
+    async fn handle_command(
+        &self,
+        command: Command,
+        protocol: &ProtocolType,
+    ) -> anyhow::Result<Response> {
         match command {
             Command::Get(keys) => {
                 info!(keys = ?keys, "GET command");
                 let mut items = Vec::new();
                 for key in &keys {
-                    match self.get_or_create_monitor_task(key).await {
+                    match self.get_value(key, protocol).await {
                         Some(value) => {
                             let item = Item {
                                 key: key.clone(),
-                                flags: 0,
+                                flags: value.flags,
                                 exptime: 0,
-                                data: value.into_bytes(),
+                                data: value.data,
                                 cas: None,
                             };
                             items.push(item);
@@ -113,13 +203,13 @@ impl Service {
                 info!(keys = ?keys, "GETS command");
                 let mut items = Vec::new();
                 for key in &keys {
-                    match self.get_or_create_monitor_task(key).await {
+                    match self.get_value(key, protocol).await {
                         Some(value) => {
                             let item = Item {
                                 key: key.clone(),
-                                flags: 0,
+                                flags: value.flags,
                                 exptime: 0,
-                                data: value.into_bytes(),
+                                data: value.data,
                                 cas: Some(12345),
                             };
                             items.push(item);
@@ -161,12 +251,12 @@ impl Service {
             }
             Command::MetaGet(key, flags) => {
                 info!(key = key, flags = ?flags, "META GET command");
-                if let Some(value) = self.get_or_create_monitor_task(&key).await {
+                if let Some(value) = self.get_value(&key, protocol).await {
                     let item = Item {
                         key: key.clone(),
-                        flags: 0,
+                        flags: value.flags,
                         exptime: 0,
-                        data: value.into_bytes(),
+                        data: value.data,
                         cas: Some(12345),
                     };
                     Ok(Response::MetaValue(item, flags))
@@ -184,13 +274,17 @@ impl Service {
             }
             Command::Stats(arg) => {
                 info!(arg = ?arg, "STATS command");
-                let stats = vec![
-                    ("version".to_string(), "0.1.0".to_string()),
-                    ("curr_connections".to_string(), "1".to_string()),
-                    ("total_connections".to_string(), "1".to_string()),
-                    ("cmd_get".to_string(), "0".to_string()),
-                    ("cmd_set".to_string(), "0".to_string()),
-                ];
+                let mut stats = vec![("version".to_string(), "0.1.0".to_string())];
+                stats.extend(self.stats.snapshot());
+                for oversize in [Oversize::Reject, Oversize::Truncate, Oversize::Chunk] {
+                    let count = metrics()
+                        .oversize_values
+                        .with_label_values(&[oversize.name()])
+                        .get();
+                    stats.push((format!("oversize_{}", oversize.name()), count.to_string()));
+                }
+                stats.push(("cmd_get".to_string(), "0".to_string()));
+                stats.push(("cmd_set".to_string(), "0".to_string()));
                 Ok(Response::Stats(stats))
             }
             Command::Touch(key, exptime) => {
@@ -208,16 +302,40 @@ impl Service {
         self.monitor_tasks.tick().await;
     }
 
+    /// Starts monitoring `keys` before any client asks for them, e.g. the keys
+    /// handed over by the process this one replaced.
+    pub async fn prefetch(&self, keys: &[String]) {
+        for key in keys {
+            let _ = self.get_or_create_monitor_task(key).await;
+        }
+    }
+
     pub fn monitor_tasks(&self) -> &MonitorTasks {
         &self.monitor_tasks
     }
 
-    pub fn shutdown(self) {
-        if let Some(writer) = self.target_writer {
-            // Try to get exclusive access to the writer
-            if let Ok(writer) = Arc::try_unwrap(writer) {
-                writer.shutdown();
-            }
+    pub fn namespace(&self) -> &Namespace {
+        &self.namespace
+    }
+
+    pub fn router(&self) -> Arc<Router> {
+        self.routing().router
+    }
+
+    pub fn targets(&self) -> Option<&TargetSet> {
+        self.targets.as_deref()
+    }
+
+    /// Ready to serve once the targets, if any, are connected
+    pub fn is_ready(&self) -> bool {
+        self.targets
+            .as_ref()
+            .is_none_or(|targets| targets.is_ready())
+    }
+
+    pub async fn shutdown(self) {
+        if let Some(targets) = self.targets {
+            targets.shutdown().await;
         }
     }
 }
@@ -231,4 +349,228 @@ mod tests {
         // Just test that MonitorTasks can be created
         let _monitor_tasks: MonitorTasks = MonitorTasks::new();
     }
+
+    #[tokio::test]
+    async fn test_stats_reports_connections() {
+        let stats = Arc::new(Stats::new());
+        stats.connection_opened();
+        let service = Service::new().with_stats(stats);
+
+        let response = service
+            .handle_command(Command::Stats(None), &ProtocolType::Text)
+            .await
+            .unwrap();
+        match response {
+            Response::Stats(stats) => {
+                assert!(stats.contains(&("curr_connections".to_string(), "1".to_string())));
+                assert!(stats.contains(&("total_connections".to_string(), "1".to_string())));
+            }
+            _ => panic!("Expected Stats response"),
+        }
+    }
+
+    fn echo_sources() -> Sources {
+        let source: Arc<Box<dyn crate::Source>> = Arc::new(Box::new(crate::source::Echo::new()));
+        HashMap::from([("echo".to_string(), source)])
+    }
+
+    #[tokio::test]
+    async fn test_reload_keeps_unchanged_tasks() {
+        let sources = echo_sources();
+        let service = Service::new()
+            .with_router(Router::new().route("^echo/(?<name>.+)$", "echo"))
+            .with_sources(sources.clone());
+        service.prefetch(&["echo/a".to_string()]).await;
+        assert_eq!(service.monitor_tasks().keys(), vec!["echo/a".to_string()]);
+
+        // Same source object and captures, so the task is kept
+        service
+            .reload(Router::new().route("^echo/(?<name>.+)$", "echo"), sources)
+            .await;
+        assert_eq!(service.monitor_tasks().keys(), vec!["echo/a".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_gets_counted_by_protocol_and_result() {
+        let service = Service::new()
+            .with_router(Router::new().route("^echo/(?<name>.+)$", "echo"))
+            .with_sources(echo_sources());
+        let gets = |result| metrics().gets.with_label_values(&["meta", result]).get();
+        let (hits, misses) = (gets("hit"), gets("miss"));
+
+        let meta_get = |key: &str| Command::MetaGet(key.to_string(), Vec::new());
+        service
+            .handle_command(meta_get("echo/a"), &ProtocolType::Meta)
+            .await
+            .unwrap();
+        service
+            .handle_command(meta_get("unrouted"), &ProtocolType::Meta)
+            .await
+            .unwrap();
+
+        assert_eq!(gets("hit"), hits + 1);
+        assert_eq!(gets("miss"), misses + 1);
+    }
+
+    #[tokio::test]
+    async fn test_reload_retires_changed_tasks() {
+        let service = Service::new()
+            .with_router(Router::new().route("^echo/(?<name>.+)$", "echo"))
+            .with_sources(echo_sources());
+        service.prefetch(&["echo/a".to_string()]).await;
+
+        // A rebuilt source is a different object, so the task is retired
+        service
+            .reload(
+                Router::new().route("^echo/(?<name>.+)$", "echo"),
+                echo_sources(),
+            )
+            .await;
+        assert!(service.monitor_tasks().keys().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_values_written_to_target() {
+        let memory = crate::InMemory::new();
+        let service = Service::new()
+            .with_router(Router::new().route("^echo/(?<name>.+)$", "echo"))
+            .with_sources(echo_sources())
+            .with_targets(TargetSet::single(memory.clone()));
+        service.prefetch(&["echo/a".to_string()]).await;
+
+        assert!(memory.get("echo/a").is_some());
+        assert!(service.is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_namespace() {
+        let memory = crate::InMemory::new();
+        let service = Service::new()
+            .with_router(Router::new().route("^echo/(?<name>.+)$", "echo"))
+            .with_sources(echo_sources())
+            .with_targets(TargetSet::single(memory.clone()))
+            .with_namespace(Namespace::new().with_prefix("app/").with_strip_prefix(true));
+        service.prefetch(&["app/echo/a".to_string()]).await;
+
+        assert_eq!(service.monitor_tasks().keys(), vec!["echo/a".to_string()]);
+        assert!(memory.get("app/echo/a").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_compressed_values() {
+        use crate::compression::{Algorithm, Compression, FLAG_COMPRESSED, decompress};
+        use crate::router::Rule;
+
+        let memory = crate::InMemory::new();
+        let rule = Rule::new("^echo/(?<name>.+)$", "echo")
+            .unwrap()
+            .with_compression(Some(Compression::new(Algorithm::Zlib).with_min_size(100)));
+        let source: Arc<Box<dyn crate::Source>> =
+            Arc::new(Box::new(crate::source::Echo::new().with_template("{name}")));
+        let service = Service::new()
+            .with_router(Router::new().with_rule(rule))
+            .with_sources(HashMap::from([("echo".to_string(), source)]))
+            .with_targets(TargetSet::single(memory.clone()));
+        let name = "a".repeat(500);
+        let key = format!("echo/{}", name);
+
+        let response = service
+            .handle_command(Command::Get(vec![key.clone()]), &ProtocolType::Text)
+            .await
+            .unwrap();
+        let Response::Values(items) = response else {
+            panic!("Expected values");
+        };
+        assert_eq!(items[0].flags, FLAG_COMPRESSED);
+        assert_eq!(
+            decompress(Algorithm::Zlib, &items[0].data).unwrap(),
+            name.as_bytes()
+        );
+
+        // The target gets the same bytes and flags clients do
+        let written = memory.get(&key).unwrap();
+        assert_eq!(written.flags, FLAG_COMPRESSED);
+        assert_eq!(written.data, items[0].data);
+    }
+
+    #[tokio::test]
+    async fn test_client_flags() {
+        use crate::router::Rule;
+
+        let memory = crate::InMemory::new();
+        let source: Arc<Box<dyn crate::Source>> =
+            Arc::new(Box::new(crate::source::Echo::new().with_flags(1)));
+        let router = Router::new()
+            .with_rule(
+                Rule::new("^json/(?<name>.+)$", "echo")
+                    .unwrap()
+                    .with_flags(Some(4)),
+            )
+            .route("^echo/(?<name>.+)$", "echo");
+        let service = Service::new()
+            .with_router(router)
+            .with_sources(HashMap::from([("echo".to_string(), source)]))
+            .with_targets(TargetSet::single(memory.clone()));
+
+        let keys = vec!["echo/a".to_string(), "json/a".to_string()];
+        let response = service
+            .handle_command(Command::Get(keys), &ProtocolType::Text)
+            .await
+            .unwrap();
+        let Response::Values(items) = response else {
+            panic!("Expected values");
+        };
+        // The source's flags, unless the route sets its own
+        assert_eq!(items[0].flags, 1);
+        assert_eq!(items[1].flags, 4);
+        assert_eq!(memory.get("echo/a").unwrap().flags, 1);
+        assert_eq!(memory.get("json/a").unwrap().flags, 4);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_keys() {
+        use crate::router::{CaptureType, Constraint, Rule};
+
+        let file: Arc<Box<dyn crate::Source>> =
+            Arc::new(Box::new(crate::source::File::new("/data/{path}.json")));
+        let router = Router::new()
+            .with_rule(
+                Rule::new("^user/(?<id>.+)$", "echo")
+                    .unwrap()
+                    .with_constraint("id", Constraint::new().with_type(CaptureType::Integer)),
+            )
+            .route("^file/(?<path>.+)$", "file");
+        let mut sources = echo_sources();
+        sources.insert("file".to_string(), file);
+        let service = Service::new().with_router(router).with_sources(sources);
+        let rejected = |source| metrics().rejected_keys.with_label_values(&[source]).get();
+        let (echo_before, file_before) = (rejected("echo"), rejected("file"));
+
+        let get = |keys: &[&str]| Command::Get(keys.iter().map(|key| key.to_string()).collect());
+        let response = service
+            .handle_command(get(&["user/42"]), &ProtocolType::Text)
+            .await
+            .unwrap();
+        assert!(matches!(response, Response::Values(items) if items.len() == 1));
+
+        // A key that is not allowed is a miss, and the others are still answered
+        let response = service
+            .handle_command(get(&["user/me", "user/42"]), &ProtocolType::Text)
+            .await
+            .unwrap();
+        assert!(
+            matches!(response, Response::Values(items) if items.len() == 1 && items[0].key == "user/42")
+        );
+
+        // File sources refuse paths outside their directory without any constraint
+        let response = service
+            .handle_command(get(&["file/../../etc/passwd"]), &ProtocolType::Text)
+            .await
+            .unwrap();
+        assert_eq!(response, Response::Values(Vec::new()));
+
+        assert_eq!(rejected("echo"), echo_before + 1);
+        assert_eq!(rejected("file"), file_before + 1);
+        assert_eq!(service.monitor_tasks().keys(), vec!["user/42".to_string()]);
+    }
 }
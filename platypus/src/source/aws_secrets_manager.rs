@@ -1,19 +1,20 @@
 use crate::pool::aws_secrets_manager::AwsSecretsManagerConnectionManager;
 #[cfg(test)]
 use crate::pool::aws_secrets_manager::AwsSecretsManagerPoolBuilder;
-use crate::{Request, Response, replace_placeholders, response::MonitorConfig, source::Source};
+use crate::{Request, Response, Template, response::MonitorConfig, source::Source};
 use async_trait::async_trait;
 use base64::{Engine as _, engine::general_purpose};
 use r2d2::Pool;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
 use std::time::Duration;
-use tracing;
+use tracing::{self, Instrument};
 
 pub struct AwsSecretsManager {
     monitor_config: MonitorConfig,
     secret_id_template: String,
     pool: Arc<Pool<AwsSecretsManagerConnectionManager>>,
+    strict: bool,
 }
 
 impl Deref for AwsSecretsManager {
@@ -37,6 +38,7 @@ impl AwsSecretsManager {
             monitor_config: MonitorConfig::default(),
             secret_id_template: String::new(),
             pool,
+            strict: false,
         }
     }
 
@@ -60,19 +62,47 @@ impl AwsSecretsManager {
         self
     }
 
-    fn build_secret_id(&self, request: &Request) -> String {
-        replace_placeholders(&self.secret_id_template, request.captures())
+    /// Rejects keys missing a variable the template uses, instead of leaving it empty
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn with_flags(mut self, flags: u32) -> Self {
+        self.monitor_config = self.monitor_config.with_flags(flags);
+        self
+    }
+
+    fn build_secret_id(&self, request: &Request) -> Result<String, String> {
+        Template::new(&self.secret_id_template)
+            .with_strict(self.strict)
+            .render(request.captures())
     }
 }
 
 #[async_trait]
 impl Source for AwsSecretsManager {
+    fn validate(&self, request: &Request) -> Result<(), String> {
+        self.build_secret_id(request).map(|_| ())
+    }
+
     async fn call(&self, request: &Request) -> Response {
         let response = Response::new()
             .with_expiry(self.expiry())
-            .with_ttl(self.ttl());
+            .with_ttl(self.ttl())
+            .with_flags(self.flags());
 
-        let secret_id = self.build_secret_id(request);
+        let secret_id = match self.build_secret_id(request) {
+            Ok(secret_id) => secret_id,
+            Err(e) => {
+                tracing::error!(
+                    "Failed to fill in secret id template '{}': {}",
+                    self.secret_id_template,
+                    e
+                );
+                return response;
+            }
+        };
 
         // Get a connection from the pool
         let connection = match self.pool.get() {
@@ -85,7 +115,15 @@ impl Source for AwsSecretsManager {
 
         let client = connection.client();
 
-        match client.get_secret_value().secret_id(&secret_id).send().await {
+        // Record the template rather than the id, which may identify a tenant
+        let span = tracing::info_span!("awssm", secret_id = self.secret_id_template.as_str());
+        let result = client
+            .get_secret_value()
+            .secret_id(&secret_id)
+            .send()
+            .instrument(span)
+            .await;
+        match result {
             Ok(secret_response) => {
                 if let Some(secret_string) = secret_response.secret_string() {
                     response.with_value(secret_string.to_string())
@@ -154,7 +192,7 @@ mod tests {
         let awssm = AwsSecretsManager::new(pool).with_secret_id("myapp/{$key}");
         let request = Request::new("database_password");
 
-        let secret_id = awssm.build_secret_id(&request);
+        let secret_id = awssm.build_secret_id(&request).unwrap();
         assert_eq!(secret_id, "myapp/database_password");
     }
 
@@ -170,7 +208,7 @@ mod tests {
         let re = Regex::new(r"^(?<environment>[^/]+)/(?<service>[^/]+)/(?<key>.+)$").unwrap();
         let request = Request::match_regex(&re, "prod/api/database_password").unwrap();
 
-        let secret_id = awssm.build_secret_id(&request);
+        let secret_id = awssm.build_secret_id(&request).unwrap();
         assert_eq!(secret_id, "myapp/prod/api/database_password");
     }
 
@@ -185,7 +223,7 @@ mod tests {
         let request = Request::new("simple_key");
 
         // Should still work, missing placeholders will be removed
-        let secret_id = awssm.build_secret_id(&request);
+        let secret_id = awssm.build_secret_id(&request).unwrap();
         assert_eq!(secret_id, "myapp//simple_key");
     }
 
@@ -220,6 +258,7 @@ mod tests {
             monitor_config: MonitorConfig::default(),
             secret_id_template: "myapp/{key}".to_string(),
             pool: pool.clone(),
+            strict: false,
         };
 
         assert_eq!(awssm.pool.max_size(), 5);
@@ -1,10 +1,11 @@
-use crate::{Request, Response, replace_placeholders, response::MonitorConfig, source::Source};
+use crate::{Request, Response, Template, response::MonitorConfig, source::Source};
 use async_trait::async_trait;
 use std::ops::{Deref, DerefMut};
 
 pub struct Echo {
     monitor_config: MonitorConfig,
     template: String,
+    strict: bool,
 }
 
 impl Deref for Echo {
@@ -26,6 +27,7 @@ impl Echo {
         Self {
             monitor_config: MonitorConfig::default(),
             template: String::new(),
+            strict: false,
         }
     }
 
@@ -37,16 +39,43 @@ impl Echo {
         self.template = template.to_string();
         self
     }
+
+    /// Rejects keys missing a variable the template uses, instead of leaving it empty
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn with_flags(mut self, flags: u32) -> Self {
+        self.monitor_config = self.monitor_config.with_flags(flags);
+        self
+    }
+
+    fn render(&self, request: &Request) -> Result<String, String> {
+        Template::new(self.template())
+            .with_strict(self.strict)
+            .render(request.captures())
+    }
 }
 
 #[async_trait]
 impl Source for Echo {
+    fn validate(&self, request: &Request) -> Result<(), String> {
+        self.render(request).map(|_| ())
+    }
+
     async fn call(&self, request: &Request) -> Response {
         let response = Response::new()
             .with_expiry(self.expiry())
-            .with_ttl(self.ttl());
+            .with_ttl(self.ttl())
+            .with_flags(self.flags());
 
-        let value = replace_placeholders(self.template(), request.captures());
-        response.with_value(value)
+        match self.render(request) {
+            Ok(value) => response.with_value(value),
+            Err(e) => {
+                tracing::error!("Failed to fill in template '{}': {}", self.template(), e);
+                response
+            }
+        }
     }
 }
@@ -1,12 +1,14 @@
-use crate::{Request, Response, replace_placeholders, response::MonitorConfig, source::Source};
+use crate::{Request, Response, Template, response::MonitorConfig, source::Source};
 use async_trait::async_trait;
 use std::ops::{Deref, DerefMut};
+use std::path::{Component, Path};
 use std::time::Duration;
 use tracing;
 
 pub struct File {
     monitor_config: MonitorConfig,
     path_template: String,
+    strict: bool,
 }
 
 impl Deref for File {
@@ -28,6 +30,7 @@ impl File {
         Self {
             monitor_config: MonitorConfig::default(),
             path_template: path_template.to_string(),
+            strict: false,
         }
     }
 
@@ -41,19 +44,88 @@ impl File {
         self
     }
 
-    fn build_path(&self, request: &Request) -> String {
-        replace_placeholders(&self.path_template, request.captures())
+    /// Rejects keys missing a variable the template uses, instead of leaving it empty
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn with_flags(mut self, flags: u32) -> Self {
+        self.monitor_config = self.monitor_config.with_flags(flags);
+        self
+    }
+
+    fn build_path(&self, request: &Request) -> Result<String, String> {
+        Template::new(&self.path_template)
+            .with_strict(self.strict)
+            .render(request.captures())
+    }
+}
+
+// Whether `value` could take a path outside the directory it is placed in
+fn escapes(value: &str) -> bool {
+    value.contains('\0')
+        || Path::new(value).components().any(|component| {
+            matches!(
+                component,
+                Component::ParentDir | Component::RootDir | Component::Prefix(_)
+            )
+        })
+}
+
+// Whether the rendered `path` leaves `directory`, the directory of the
+// template's text before its first placeholder. Captures that are harmless on
+// their own can still combine into `..`.
+fn leaves(path: &str, directory: &str) -> bool {
+    let Some(rest) = path.strip_prefix(directory) else {
+        return true;
+    };
+    // Repeated separators after the directory are still within it
+    match directory {
+        "" => escapes(rest),
+        _ => escapes(rest.trim_start_matches('/')),
     }
 }
 
 #[async_trait]
 impl Source for File {
+    /// Refuses captures that would reach outside the path template's directory
+    fn validate(&self, request: &Request) -> Result<(), String> {
+        let path = self.build_path(request)?;
+        let template = Template::new(&self.path_template);
+        for name in template.variables() {
+            if request.get(name).is_some_and(escapes) {
+                return Err(format!(
+                    "{} is not a relative path within the directory",
+                    name
+                ));
+            }
+        }
+        let prefix = template.prefix();
+        let directory = &prefix[..prefix.rfind('/').map_or(0, |i| i + 1)];
+        if leaves(&path, directory) {
+            return Err(format!("{} is outside the path template's directory", path));
+        }
+        Ok(())
+    }
+
     async fn call(&self, request: &Request) -> Response {
         let response = Response::new()
             .with_expiry(self.expiry())
-            .with_ttl(self.ttl());
+            .with_ttl(self.ttl())
+            .with_flags(self.flags());
 
-        let path = self.build_path(request);
+        let path = match self.build_path(request) {
+            Ok(path) => path,
+            Err(e) => {
+                tracing::error!(
+                    "Failed to fill in path template '{}': {}",
+                    self.path_template,
+                    e
+                );
+                return response;
+            }
+        };
 
         match tokio::fs::read_to_string(&path).await {
             Ok(contents) => response.with_value(contents),
@@ -98,7 +170,7 @@ mod tests {
         let file = File::new("/data/{$key}.txt");
         let request = Request::new("test_file");
 
-        let path = file.build_path(&request);
+        let path = file.build_path(&request).unwrap();
         assert_eq!(path, "/data/test_file.txt");
     }
 
@@ -108,7 +180,7 @@ mod tests {
         let re = Regex::new(r"^(?<environment>[^/]+)/(?<service>[^/]+)/(?<key>.+)$").unwrap();
         let request = Request::match_regex(&re, "prod/api/config").unwrap();
 
-        let path = file.build_path(&request);
+        let path = file.build_path(&request).unwrap();
         assert_eq!(path, "/data/prod/api/config.json");
     }
 
@@ -118,7 +190,7 @@ mod tests {
         let request = Request::new("simple_key");
 
         // Missing placeholders will be removed
-        let path = file.build_path(&request);
+        let path = file.build_path(&request).unwrap();
         assert_eq!(path, "/data//simple_key.txt");
     }
 
@@ -173,7 +245,10 @@ mod tests {
 
         let file_path = test_dir.join("config.json");
         let mut file_handle = fs::File::create(&file_path).await.unwrap();
-        file_handle.write_all(b"{\"key\": \"value\"}").await.unwrap();
+        file_handle
+            .write_all(b"{\"key\": \"value\"}")
+            .await
+            .unwrap();
         file_handle.sync_all().await.unwrap();
         drop(file_handle);
 
@@ -201,4 +276,51 @@ mod tests {
         assert_eq!(file.ttl(), Duration::from_secs(120));
         assert_eq!(file.expiry(), Duration::from_secs(600));
     }
+
+    #[test]
+    fn test_validate_rejects_path_traversal() {
+        let file = File::new("/data/{environment}/{name}.json");
+        let re = Regex::new(r"^(?<environment>[^/]+)/(?<name>.+)$").unwrap();
+        let request = |key| Request::match_regex(&re, key).unwrap();
+
+        assert!(file.validate(&request("prod/app/config")).is_ok());
+        assert!(file.validate(&request("prod/../secrets")).is_err());
+        assert!(file.validate(&request("prod//etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_captures_joined_into_traversal() {
+        let file = File::new("/data/{a}{b}/x");
+        let re = Regex::new(r"^(?<a>[^/]+)/(?<b>[^/]+)$").unwrap();
+        let request = |key| Request::match_regex(&re, key).unwrap();
+
+        assert!(file.validate(&request("app/config")).is_ok());
+        assert!(file.validate(&request("./.")).is_err());
+
+        let file = File::new("{a}{b}/x");
+        assert!(file.validate(&request("./.")).is_err());
+    }
+
+    #[test]
+    fn test_validate_filtered_and_strict_templates() {
+        let re = Regex::new(r"^(?<name>.+)$").unwrap();
+        let request = |key| Request::match_regex(&re, key).unwrap();
+
+        let file = File::new("/data/{name:lower}.json");
+        assert!(file.validate(&request("App")).is_ok());
+        assert!(file.validate(&request("../Secrets")).is_err());
+
+        let file = File::new("/data/{environment}/{name}.json");
+        assert!(file.validate(&request("app")).is_ok());
+        let file = file.with_strict(true);
+        assert_eq!(
+            file.validate(&request("app")),
+            Err("missing variable 'environment'".to_string())
+        );
+        let file = File::new("/data/{environment|prod}/{name}.json").with_strict(true);
+        assert_eq!(
+            file.build_path(&request("app")).unwrap(),
+            "/data/prod/app.json"
+        );
+    }
 }
@@ -1,9 +1,12 @@
-use crate::{Response, Source, replace_placeholders, request::Request, response::MonitorConfig};
+use crate::{
+    Response, Source, Template, request::Request, response::MonitorConfig, source::nested,
+};
 use async_trait::async_trait;
 use serde_json::Value;
 use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
 use std::time::Duration;
+use tracing::Instrument;
 
 #[derive(Debug, Clone)]
 pub enum RuleArgs {
@@ -33,6 +36,8 @@ pub struct Merge {
     format: String,
 
     rules: Vec<Rule>,
+
+    strict: bool,
 }
 
 impl Deref for Merge {
@@ -55,6 +60,7 @@ impl Merge {
             monitor_config: MonitorConfig::default(),
             format: "json".to_string(),
             rules: Vec::new(),
+            strict: false,
         }
     }
 
@@ -79,6 +85,33 @@ impl Merge {
         self
     }
 
+    pub fn with_flags(mut self, flags: u32) -> Self {
+        self.monitor_config = self.monitor_config.with_flags(flags);
+        self
+    }
+
+    /// Rejects keys missing a variable the replaced args use, instead of leaving it empty
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    // Captures for a source called with replaced args, filled in from `request`
+    fn replace_captures(
+        &self,
+        args: &HashMap<String, String>,
+        request: &Request,
+    ) -> Result<HashMap<String, String>, String> {
+        let mut captures = HashMap::new();
+        for (key, value) in args {
+            let value = Template::new(value)
+                .with_strict(self.strict)
+                .render(request.captures())?;
+            captures.insert(key.clone(), value);
+        }
+        Ok(captures)
+    }
+
     // Helper method to set a value at a nested key path
     fn set_nested_value(
         map: &mut serde_json::Map<String, Value>,
@@ -112,15 +145,29 @@ impl Merge {
 
 #[async_trait]
 impl Source for Merge {
+    fn validate(&self, request: &Request) -> Result<(), String> {
+        for rule in &self.rules {
+            if let RuleArgs::Replace { args } = &rule.args {
+                self.replace_captures(args, request)?;
+            }
+        }
+        Ok(())
+    }
+
     async fn call(&self, request: &Request) -> Response {
         let response = Response::new()
             .with_expiry(self.expiry())
-            .with_ttl(self.ttl());
+            .with_ttl(self.ttl())
+            .with_flags(self.flags());
 
         let sources = match request.sources() {
             Some(sources) => sources,
             None => return response,
         };
+        let Some(request) = nested(request) else {
+            return response;
+        };
+        let request = &request;
 
         let mut merged_results = serde_json::Map::new();
 
@@ -131,21 +178,29 @@ impl Source for Merge {
                     RuleArgs::Inherit => request,
                     RuleArgs::Replace { args } => {
                         // Create processed captures from rule args
-                        let mut processed_captures = HashMap::new();
-                        for (key, value) in args {
-                            let replaced_value = replace_placeholders(value, request.captures());
-                            processed_captures.insert(key.clone(), replaced_value);
-                        }
+                        let processed_captures = match self.replace_captures(args, request) {
+                            Ok(captures) => captures,
+                            Err(e) => {
+                                tracing::error!(
+                                    source = rule.source.as_str(),
+                                    "Failed to fill in args: {}",
+                                    e
+                                );
+                                continue;
+                            }
+                        };
 
                         // Create new request with processed captures
                         &Request::new(request.key())
                             .with_captures(processed_captures)
                             .with_sources(sources.clone())
+                            .with_depth(request.depth())
                     }
                 };
 
                 // Call the source with appropriate request
-                let source_response = source.call(replace_request).await;
+                let span = tracing::info_span!("source", source = rule.source.as_str());
+                let source_response = source.call(replace_request).instrument(span).await;
 
                 // If the source returned a value, place it at the nested key path
                 if let Some(value) = source_response.value() {
@@ -723,4 +778,30 @@ mod tests {
         let json: serde_json::Value = serde_json::from_str(&response_value).unwrap();
         assert_eq!(json["result"], "echo1 response");
     }
+
+    #[tokio::test]
+    async fn test_merge_referring_to_itself_stops() {
+        let merge = Merge::new().with_rule(
+            vec!["again".to_string()],
+            "looped".to_string(),
+            RuleArgs::Inherit,
+        );
+        let mut sources = HashMap::new();
+        let looped: Arc<Box<dyn Source>> = Arc::new(Box::new(merge.clone()));
+        sources.insert("looped".to_string(), looped);
+
+        let request = Request::new("test_key").with_sources(Arc::new(sources));
+        let response = merge.call(&request).await;
+
+        // Each merge nests the next one's value, until the one too deep to
+        // call any source, which has no value
+        let json: serde_json::Value = serde_json::from_str(&response.value().unwrap()).unwrap();
+        let mut depth = 0;
+        let mut value = &json;
+        while let Some(inner) = value.get("again") {
+            value = inner;
+            depth += 1;
+        }
+        assert_eq!(depth, crate::request::MAX_DEPTH - 1);
+    }
 }
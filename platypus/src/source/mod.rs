@@ -18,13 +18,39 @@ pub use merge::Merge;
 pub mod aws_secrets_manager;
 pub use aws_secrets_manager::AwsSecretsManager;
 
+pub mod shadow;
+pub use shadow::Shadow;
+
+pub mod switch;
+pub use switch::Switch;
+
 #[async_trait]
 pub trait Source: Send + Sync + 'static {
     async fn call(&self, request: &Request) -> Response;
+
+    /// Why this source refuses `request`, if it does. Refused keys are
+    /// rejected without calling the source.
+    fn validate(&self, _request: &Request) -> Result<(), String> {
+        Ok(())
+    }
 }
 
 pub type Sources = HashMap<String, Arc<Box<dyn Source>>>;
 
+// The request for the sources that a source calls itself, or None if they are
+// nested too deep, e.g. a merge that refers back to itself
+pub(crate) fn nested(request: &Request) -> Option<Request> {
+    let nested = request.nested();
+    if nested.is_none() {
+        tracing::error!(
+            key = request.key(),
+            depth = request.depth(),
+            "Sources nested too deep, they may refer to each other"
+        );
+    }
+    nested
+}
+
 pub struct FnGetter<F> {
     func: F,
     ttl: Duration,
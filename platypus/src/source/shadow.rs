@@ -0,0 +1,191 @@
+//! Calls a second source alongside the one whose values are used, and
+//! compares their values, e.g. while moving to a new backend.
+//!
+//! The shadow source is called in the background after the primary one
+//! returns, so it adds no latency, and its value is never used. Calls beyond
+//! [`MAX_PENDING`] in flight are dropped, so a slow shadow source can't pile
+//! up tasks.
+
+use crate::{
+    Request, Response,
+    metrics::metrics,
+    source::{Source, nested},
+};
+use async_trait::async_trait;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::{Instrument, warn};
+
+/// Shadow calls in flight at once for one shadow source
+pub const MAX_PENDING: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct Shadow {
+    primary: String,
+    shadow: String,
+    pending: Arc<Semaphore>,
+}
+
+impl Shadow {
+    pub fn new(primary: &str, shadow: &str) -> Self {
+        Self {
+            primary: primary.to_string(),
+            shadow: shadow.to_string(),
+            pending: Arc::new(Semaphore::new(MAX_PENDING)),
+        }
+    }
+
+    pub fn primary(&self) -> &str {
+        &self.primary
+    }
+
+    pub fn shadow(&self) -> &str {
+        &self.shadow
+    }
+}
+
+#[async_trait]
+impl Source for Shadow {
+    /// Only the primary source decides which keys are valid; the shadow one
+    /// just gets no call for keys it refuses
+    fn validate(&self, request: &Request) -> Result<(), String> {
+        let Some(sources) = request.sources() else {
+            return Ok(());
+        };
+        let request = nested(request).ok_or("sources nested too deep")?;
+        match sources.get(&self.primary) {
+            Some(primary) => primary.validate(&request),
+            None => Ok(()),
+        }
+    }
+
+    async fn call(&self, request: &Request) -> Response {
+        let Some(sources) = request.sources() else {
+            return Response::new();
+        };
+        let Some(request) = nested(request) else {
+            return Response::new();
+        };
+        let request = &request;
+        let Some(primary) = sources.get(&self.primary) else {
+            tracing::error!(source = self.primary, "Source not found");
+            return Response::new();
+        };
+        let span = tracing::info_span!("source", source = self.primary);
+        let response = primary.call(request).instrument(span).await;
+
+        if let Some(shadow) = sources
+            .get(&self.shadow)
+            .filter(|shadow| shadow.validate(request).is_ok())
+            .cloned()
+        {
+            let name = self.shadow.clone();
+            let Ok(permit) = self.pending.clone().try_acquire_owned() else {
+                metrics()
+                    .shadow_comparisons
+                    .with_label_values(&[&name, "dropped"])
+                    .inc();
+                return response;
+            };
+            let request = request.clone();
+            let expected = response.value();
+            let span = tracing::info_span!("source", source = name);
+            tokio::spawn(
+                async move {
+                    let actual = shadow.call(&request).await.value();
+                    drop(permit);
+                    let result = if actual == expected {
+                        "match"
+                    } else {
+                        // Values may be secrets, so only their sizes are logged
+                        warn!(
+                            key = request.key(),
+                            primary_len = ?expected.as_ref().map(String::len),
+                            shadow_len = ?actual.as_ref().map(String::len),
+                            "Shadow source returned a different value"
+                        );
+                        "mismatch"
+                    };
+                    metrics()
+                        .shadow_comparisons
+                        .with_label_values(&[&name, result])
+                        .inc();
+                }
+                .instrument(span),
+            );
+        }
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{Echo, File};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_shadow_is_compared() {
+        let echo = |template: &str| -> Arc<Box<dyn Source>> {
+            Arc::new(Box::new(Echo::new().with_template(template)))
+        };
+        let sources = HashMap::from([
+            ("legacy".to_string(), echo("value")),
+            ("shadow_same".to_string(), echo("value")),
+            ("shadow_differs".to_string(), echo("other")),
+        ]);
+        let request = Request::new("key").with_sources(Arc::new(sources));
+        let comparisons = |source, result| {
+            metrics()
+                .shadow_comparisons
+                .with_label_values(&[source, result])
+                .get()
+        };
+
+        for shadow in ["shadow_same", "shadow_differs"] {
+            let response = Shadow::new("legacy", shadow).call(&request).await;
+            assert_eq!(response.value(), Some("value".to_string()));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(comparisons("shadow_same", "match"), 1);
+        assert_eq!(comparisons("shadow_differs", "mismatch"), 1);
+    }
+
+    #[test]
+    fn test_only_primary_validates() {
+        let echo: Arc<Box<dyn Source>> = Arc::new(Box::new(Echo::new()));
+        let file: Arc<Box<dyn Source>> = Arc::new(Box::new(File::new("/data/{$key}")));
+        let sources = HashMap::from([("echo".to_string(), echo), ("file".to_string(), file)]);
+        let request = Request::new("../secrets").with_sources(Arc::new(sources));
+
+        assert!(Shadow::new("echo", "file").validate(&request).is_ok());
+        assert!(Shadow::new("file", "echo").validate(&request).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shadow_calls_are_dropped_when_full() {
+        let echo: Arc<Box<dyn Source>> = Arc::new(Box::new(Echo::new().with_template("value")));
+        let sources = HashMap::from([
+            ("legacy".to_string(), echo.clone()),
+            ("shadow_full".to_string(), echo),
+        ]);
+        let request = Request::new("key").with_sources(Arc::new(sources));
+        let source = Shadow::new("legacy", "shadow_full");
+        let _held = source
+            .pending
+            .acquire_many(MAX_PENDING as u32)
+            .await
+            .unwrap();
+
+        let response = source.call(&request).await;
+        assert_eq!(response.value(), Some("value".to_string()));
+        let dropped = metrics()
+            .shadow_comparisons
+            .with_label_values(&["shadow_full", "dropped"])
+            .get();
+        assert_eq!(dropped, 1);
+    }
+}
@@ -0,0 +1,179 @@
+//! Picks another source for each request, by the request's captures or by
+//! weight.
+//!
+//! Weighted choices hash the key, so a key always goes to the same source
+//! and its value does not flip between sources from one refresh to the next.
+
+use crate::{
+    Request, Response,
+    source::{Source, nested},
+};
+use async_trait::async_trait;
+use tracing::Instrument;
+use xxhash_rust::xxh3::xxh3_64;
+
+#[derive(Debug, Clone, PartialEq)]
+struct Case {
+    capture: String,
+    values: Vec<String>,
+    source: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Switch {
+    cases: Vec<Case>,
+    // Sources for requests no case matches, with their weights
+    split: Vec<(String, u32)>,
+}
+
+impl Switch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends requests whose `capture` is one of `values` to `source`. Cases
+    /// are tried in the order they are added.
+    pub fn with_case(mut self, capture: &str, values: Vec<String>, source: &str) -> Self {
+        self.cases.push(Case {
+            capture: capture.to_string(),
+            values,
+            source: source.to_string(),
+        });
+        self
+    }
+
+    /// Sends requests no case matches to `source`
+    pub fn with_default(self, source: &str) -> Self {
+        self.with_split(source, 1)
+    }
+
+    /// Sends a `weight` share of the keys no case matches to `source`
+    pub fn with_split(mut self, source: &str, weight: u32) -> Self {
+        self.split.push((source.to_string(), weight));
+        self
+    }
+
+    /// Name of the source for `request`
+    pub fn select(&self, request: &Request) -> Option<&str> {
+        let case = self.cases.iter().find(|case| {
+            request
+                .get(&case.capture)
+                .is_some_and(|value| case.values.iter().any(|v| v == value))
+        });
+        if let Some(case) = case {
+            return Some(&case.source);
+        }
+
+        let total: u64 = self.split.iter().map(|(_, weight)| *weight as u64).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut point = xxh3_64(request.key().as_bytes()) % total;
+        for (source, weight) in &self.split {
+            if point < *weight as u64 {
+                return Some(source);
+            }
+            point -= *weight as u64;
+        }
+        None
+    }
+
+    fn selected<'a>(&self, request: &'a Request) -> Option<&'a dyn Source> {
+        let name = self.select(request)?;
+        let source = request.sources()?.get(name)?;
+        Some(source.as_ref().as_ref())
+    }
+}
+
+#[async_trait]
+impl Source for Switch {
+    fn validate(&self, request: &Request) -> Result<(), String> {
+        let request = nested(request).ok_or("sources nested too deep")?;
+        match self.selected(&request) {
+            Some(source) => source.validate(&request),
+            None => Ok(()),
+        }
+    }
+
+    async fn call(&self, request: &Request) -> Response {
+        let Some(name) = self.select(request) else {
+            return Response::new();
+        };
+        let Some(request) = nested(request) else {
+            return Response::new();
+        };
+        match self.selected(&request) {
+            Some(source) => {
+                let span = tracing::info_span!("source", source = name);
+                source.call(&request).instrument(span).await
+            }
+            None => {
+                tracing::error!(source = name, "Source not found");
+                Response::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::Echo;
+    use regex::Regex;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn request(key: &str) -> Request {
+        let re = Regex::new(r"^(?<environment>[^/]+)/(?<name>.+)$").unwrap();
+        let sources: HashMap<String, Arc<Box<dyn Source>>> = ["new", "legacy"]
+            .into_iter()
+            .map(|name| {
+                let source: Arc<Box<dyn Source>> =
+                    Arc::new(Box::new(Echo::new().with_template(name)));
+                (name.to_string(), source)
+            })
+            .collect();
+        Request::match_regex(&re, key)
+            .unwrap()
+            .with_sources(Arc::new(sources))
+    }
+
+    #[tokio::test]
+    async fn test_cases() {
+        let switch = Switch::new()
+            .with_case("environment", vec!["prod".into()], "new")
+            .with_default("legacy");
+
+        assert_eq!(switch.select(&request("prod/app")), Some("new"));
+        assert_eq!(switch.select(&request("staging/app")), Some("legacy"));
+        let response = switch.call(&request("prod/app")).await;
+        assert_eq!(response.value(), Some("new".to_string()));
+    }
+
+    #[test]
+    fn test_split_by_weight() {
+        let switch = Switch::new().with_split("new", 1).with_split("legacy", 3);
+
+        let keys: Vec<String> = (0..1000).map(|i| format!("prod/{}", i)).collect();
+        let new = keys
+            .iter()
+            .filter(|key| switch.select(&request(key)) == Some("new"))
+            .count();
+        assert!(
+            (200..300).contains(&new),
+            "{} of 1000 keys went to new",
+            new
+        );
+
+        // The same key always goes to the same source
+        assert_eq!(
+            switch.select(&request("prod/1")),
+            switch.select(&request("prod/1"))
+        );
+    }
+
+    #[test]
+    fn test_nothing_selected() {
+        assert_eq!(Switch::new().select(&request("prod/app")), None);
+    }
+}
@@ -0,0 +1,176 @@
+use prometheus::{IntCounter, IntGauge, Registry};
+
+/// Connection counters shared between the `Server` accepting sockets and the
+/// `Service` answering `stats`. Names follow the ones memcached reports, and
+/// the same counters are exported to Prometheus with a `platypus_` prefix.
+#[derive(Debug)]
+pub struct Stats {
+    max_connections: IntGauge,
+    curr_connections: IntGauge,
+    total_connections: IntCounter,
+    rejected_connections: IntCounter,
+    listen_disabled_num: IntCounter,
+    idle_kicks: IntCounter,
+    read_timeouts: IntCounter,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn gauge(name: &str, help: &str) -> IntGauge {
+    IntGauge::new(format!("platypus_{}", name), help).unwrap()
+}
+
+fn counter(name: &str, help: &str) -> IntCounter {
+    IntCounter::new(format!("platypus_{}", name), help).unwrap()
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            max_connections: gauge("max_connections", "Maximum concurrent client connections"),
+            curr_connections: gauge("curr_connections", "Open client connections"),
+            total_connections: counter("total_connections", "Client connections accepted"),
+            rejected_connections: counter(
+                "rejected_connections",
+                "Client connections rejected at the connection limit",
+            ),
+            listen_disabled_num: counter(
+                "listen_disabled_num",
+                "Times accepting paused at the connection limit",
+            ),
+            idle_kicks: counter("idle_kicks", "Connections closed for being idle"),
+            read_timeouts: counter("read_timeouts", "Connections closed for a slow command"),
+        }
+    }
+
+    /// Exports the counters through `registry`
+    pub fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.max_connections.clone()))?;
+        registry.register(Box::new(self.curr_connections.clone()))?;
+        registry.register(Box::new(self.total_connections.clone()))?;
+        registry.register(Box::new(self.rejected_connections.clone()))?;
+        registry.register(Box::new(self.listen_disabled_num.clone()))?;
+        registry.register(Box::new(self.idle_kicks.clone()))?;
+        registry.register(Box::new(self.read_timeouts.clone()))?;
+        Ok(())
+    }
+
+    pub fn set_max_connections(&self, max: usize) {
+        self.max_connections.set(max as i64);
+    }
+
+    pub fn connection_opened(&self) {
+        self.curr_connections.inc();
+        self.total_connections.inc();
+    }
+
+    pub fn connection_closed(&self) {
+        self.curr_connections.dec();
+    }
+
+    pub fn connection_rejected(&self) {
+        self.rejected_connections.inc();
+    }
+
+    // Counts the times the accept loop stopped accepting because it was at the limit
+    pub fn listen_disabled(&self) {
+        self.listen_disabled_num.inc();
+    }
+
+    pub fn idle_kick(&self) {
+        self.idle_kicks.inc();
+    }
+
+    pub fn read_timeout(&self) {
+        self.read_timeouts.inc();
+    }
+
+    pub fn curr_connections(&self) -> u64 {
+        self.curr_connections.get() as u64
+    }
+
+    pub fn total_connections(&self) -> u64 {
+        self.total_connections.get()
+    }
+
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected_connections.get()
+    }
+
+    /// Returns the counters as `stats` name/value pairs
+    pub fn snapshot(&self) -> Vec<(String, String)> {
+        let counters = [
+            ("max_connections", self.max_connections.get() as u64),
+            ("curr_connections", self.curr_connections()),
+            ("total_connections", self.total_connections()),
+            ("rejected_connections", self.rejected_connections()),
+            ("listen_disabled_num", self.listen_disabled_num.get()),
+            ("idle_kicks", self.idle_kicks.get()),
+            ("read_timeouts", self.read_timeouts.get()),
+        ];
+        counters
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(stats: &Stats, name: &str) -> String {
+        stats
+            .snapshot()
+            .into_iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_connection_counters() {
+        let stats = Stats::new();
+        stats.connection_opened();
+        stats.connection_opened();
+        stats.connection_closed();
+
+        assert_eq!(stats.curr_connections(), 1);
+        assert_eq!(stats.total_connections(), 2);
+    }
+
+    #[test]
+    fn test_snapshot_names() {
+        let stats = Stats::new();
+        stats.set_max_connections(64);
+        stats.connection_rejected();
+        stats.listen_disabled();
+        stats.idle_kick();
+        stats.read_timeout();
+
+        assert_eq!(stat(&stats, "max_connections"), "64");
+        assert_eq!(stat(&stats, "rejected_connections"), "1");
+        assert_eq!(stat(&stats, "listen_disabled_num"), "1");
+        assert_eq!(stat(&stats, "idle_kicks"), "1");
+        assert_eq!(stat(&stats, "read_timeouts"), "1");
+    }
+
+    #[test]
+    fn test_register() {
+        let stats = Stats::new();
+        stats.connection_opened();
+        let registry = Registry::new();
+        stats.register(&registry).unwrap();
+
+        let families = registry.gather();
+        let curr = families
+            .iter()
+            .find(|family| family.name() == "platypus_curr_connections")
+            .unwrap();
+        assert_eq!(curr.get_metric()[0].get_gauge().get_value(), 1.0);
+    }
+}
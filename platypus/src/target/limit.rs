@@ -0,0 +1,231 @@
+//! Values larger than the target accepts.
+//!
+//! Memcached rejects items over its item size limit, 1 MiB by default, so a
+//! value over it never reaches clients. A [`ValueLimit`] decides what is
+//! written instead: nothing, the start of the value, or the value in chunks.
+//!
+//! Writes to different keys are queued separately, so chunks may land after
+//! the manifest, or be dropped. Chunk keys include a hash of the whole value,
+//! the version in the manifest, so a client reading a manifest never joins
+//! chunks of different values: a chunk is either the right one or missing.
+
+use crate::Encoded;
+use serde::Serialize;
+use xxhash_rust::xxh3::xxh3_64;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Oversize {
+    /// Write nothing, and remove the key so clients do not read an older value
+    #[default]
+    Reject,
+    /// Write the first `max_size` bytes, or reject compressed values
+    Truncate,
+    /// Write the value in chunks under `{key}:{version}:{index}`, with a manifest under the key
+    Chunk,
+}
+
+impl Oversize {
+    /// Label for metrics and stats
+    pub fn name(&self) -> &'static str {
+        match self {
+            Oversize::Reject => "rejected",
+            Oversize::Truncate => "truncated",
+            Oversize::Chunk => "chunked",
+        }
+    }
+}
+
+impl std::str::FromStr for Oversize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Oversize::Reject),
+            "truncate" => Ok(Oversize::Truncate),
+            "chunk" => Ok(Oversize::Chunk),
+            _ => Err(format!(
+                "unknown oversize action '{}', expected reject, truncate or chunk",
+                s
+            )),
+        }
+    }
+}
+
+/// What a chunked value's key holds, as JSON
+#[derive(Debug, Serialize, PartialEq)]
+pub struct Manifest {
+    /// Hash of the whole value, in the keys of its chunks
+    pub version: String,
+    /// Number of chunks, stored under `{key}:{version}:0` to `{key}:{version}:{chunks - 1}`
+    pub chunks: usize,
+    /// Size of the whole value in bytes
+    pub size: usize,
+    /// Client flags of the whole value
+    pub flags: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ValueLimit {
+    max_size: usize,
+    oversize: Oversize,
+}
+
+impl ValueLimit {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            max_size,
+            oversize: Oversize::default(),
+        }
+    }
+
+    pub fn with_oversize(mut self, oversize: Oversize) -> Self {
+        self.oversize = oversize;
+        self
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub fn oversize(&self) -> Oversize {
+        self.oversize
+    }
+
+    pub fn exceeded_by(&self, value: &Encoded) -> bool {
+        value.data.len() > self.max_size
+    }
+
+    /// What is done with `value` when it is over the limit. The start of a
+    /// compressed value can't be decompressed, so it is rejected instead of
+    /// truncated.
+    pub fn action(&self, value: &Encoded) -> Oversize {
+        match self.oversize {
+            Oversize::Truncate if value.is_compressed() => Oversize::Reject,
+            oversize => oversize,
+        }
+    }
+
+    /// The keys and values to write to store `value` under `key`. Empty if
+    /// the value is rejected.
+    pub fn split(&self, key: &str, mut value: Encoded) -> Vec<(String, Encoded)> {
+        if !self.exceeded_by(&value) {
+            return vec![(key.to_string(), value)];
+        }
+        match self.action(&value) {
+            Oversize::Reject => Vec::new(),
+            Oversize::Truncate => {
+                value.data.truncate(self.max_size);
+                vec![(key.to_string(), value)]
+            }
+            Oversize::Chunk => {
+                let version = format!("{:016x}", xxh3_64(&value.data));
+                let chunks = value.data.chunks(self.max_size.max(1));
+                let manifest = Manifest {
+                    version: version.clone(),
+                    chunks: chunks.len(),
+                    size: value.data.len(),
+                    flags: value.flags,
+                };
+                let mut writes: Vec<(String, Encoded)> = chunks
+                    .enumerate()
+                    .map(|(index, chunk)| {
+                        let chunk = Encoded {
+                            data: chunk.to_vec(),
+                            flags: 0,
+                        };
+                        (chunk_key(key, &version, index), chunk)
+                    })
+                    .collect();
+                let manifest = serde_json::to_string(&manifest).unwrap_or_default();
+                writes.push((key.to_string(), Encoded::from(manifest)));
+                writes
+            }
+        }
+    }
+}
+
+pub fn chunk_key(key: &str, version: &str, index: usize) -> String {
+    format!("{}:{}:{}", key, version, index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(size: usize) -> Encoded {
+        Encoded {
+            data: vec![b'a'; size],
+            flags: 2,
+        }
+    }
+
+    #[test]
+    fn test_small_values_are_unchanged() {
+        let limit = ValueLimit::new(10);
+        assert_eq!(
+            limit.split("key", value(10)),
+            vec![("key".into(), value(10))]
+        );
+    }
+
+    #[test]
+    fn test_reject_and_truncate() {
+        let limit = ValueLimit::new(10);
+        assert!(limit.split("key", value(11)).is_empty());
+
+        let limit = limit.with_oversize(Oversize::Truncate);
+        let plain = |size| Encoded {
+            flags: 0,
+            ..value(size)
+        };
+        assert_eq!(
+            limit.split("key", plain(11)),
+            vec![("key".into(), plain(10))]
+        );
+    }
+
+    #[test]
+    fn test_compressed_values_are_not_truncated() {
+        let limit = ValueLimit::new(10).with_oversize(Oversize::Truncate);
+        let compressed = value(11);
+        assert!(compressed.is_compressed());
+        assert_eq!(limit.action(&compressed), Oversize::Reject);
+        assert!(limit.split("key", compressed).is_empty());
+    }
+
+    #[test]
+    fn test_chunk() {
+        let limit = ValueLimit::new(10).with_oversize(Oversize::Chunk);
+        let writes = limit.split("key", value(25));
+        let version = format!("{:016x}", xxh3_64(&value(25).data));
+        let keys: Vec<&str> = writes.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                chunk_key("key", &version, 0),
+                chunk_key("key", &version, 1),
+                chunk_key("key", &version, 2),
+                "key".to_string(),
+            ]
+        );
+        assert_eq!(writes[2].1.data.len(), 5);
+        assert_eq!(writes[0].1.flags, 0);
+        assert_eq!(
+            writes[3].1,
+            Encoded::from(format!(
+                r#"{{"version":"{}","chunks":3,"size":25,"flags":2}}"#,
+                version
+            ))
+        );
+
+        // Another value's chunks never share keys with these
+        let other = limit.split("key", Encoded::from("b".repeat(25)));
+        assert_ne!(other[0].0, writes[0].0);
+    }
+
+    #[test]
+    fn test_oversize_from_str() {
+        assert_eq!("chunk".parse::<Oversize>(), Ok(Oversize::Chunk));
+        assert!("split".parse::<Oversize>().is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use super::Target;
+use crate::Encoded;
+use crate::writer::WriteError;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Value and when it expires; a TTL of zero never expires, as in memcached
+type Entry = (Encoded, Option<Instant>);
+
+/// A target kept in process memory, for tests and for embedding platypus.
+/// Clones share the same entries.
+#[derive(Clone, Default)]
+pub struct InMemory {
+    entries: Arc<Mutex<HashMap<String, Entry>>>,
+}
+
+impl InMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, key: &str) -> Option<Encoded> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((_, Some(expires_at))) if *expires_at <= Instant::now() => {
+                entries.remove(key);
+                None
+            }
+            Some((value, _)) => Some(value.clone()),
+            None => None,
+        }
+    }
+
+    /// Time left before `key` expires, or None if it is missing or never expires
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        let entries = self.entries.lock().unwrap();
+        let (_, expires_at) = entries.get(key)?;
+        expires_at.map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn expires_at(ttl: Duration) -> Option<Instant> {
+        (!ttl.is_zero()).then(|| Instant::now() + ttl)
+    }
+}
+
+#[async_trait]
+impl Target for InMemory {
+    fn address(&self) -> &str {
+        "memory"
+    }
+
+    async fn set(&self, key: &str, value: Encoded, ttl: Duration) -> Result<(), WriteError> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), (value, Self::expires_at(ttl)));
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), WriteError> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn touch(&self, key: &str, value: Encoded, ttl: Duration) -> Result<(), WriteError> {
+        if self.get(key).is_none() {
+            return self.set(key, value, ttl).await;
+        }
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+            entry.1 = Self::expires_at(ttl);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_set_get_delete() {
+        let memory = InMemory::new();
+        memory
+            .set("key", "value".into(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert_eq!(memory.get("key"), Some("value".into()));
+
+        memory.delete("key").await.unwrap();
+        assert_eq!(memory.get("key"), None);
+        assert!(memory.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_expiry_and_touch() {
+        let memory = InMemory::new();
+        let ttl = Duration::from_millis(50);
+        memory.set("key", "value".into(), ttl).await.unwrap();
+
+        memory
+            .touch("key", "value".into(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        assert!(memory.ttl("key").unwrap() > ttl);
+
+        memory.set("key", "value".into(), ttl).await.unwrap();
+        tokio::time::sleep(ttl * 2).await;
+        assert_eq!(memory.get("key"), None);
+
+        // Touching a missing key sets it again
+        memory
+            .touch("key", "value".into(), Duration::ZERO)
+            .await
+            .unwrap();
+        assert_eq!(memory.get("key"), Some("value".into()));
+        assert_eq!(memory.ttl("key"), None);
+    }
+}
@@ -0,0 +1,45 @@
+use crate::Encoded;
+use crate::writer::WriteError;
+use async_trait::async_trait;
+use std::time::Duration;
+
+pub mod limit;
+pub use limit::{Oversize, ValueLimit};
+
+pub mod memory;
+pub use memory::InMemory;
+
+pub mod redis;
+
+pub mod set;
+pub use set::{Distribution, NodeHealth, TargetSet};
+
+/// Somewhere fetched values are written to, so clients can read them
+/// without asking platypus.
+#[async_trait]
+pub trait Target: Send + Sync + 'static {
+    /// Where values are written, for logs and health reporting
+    fn address(&self) -> &str;
+
+    /// Writes `value` under `key`. Targets that support item flags store
+    /// `value.flags` with it.
+    async fn set(&self, key: &str, value: Encoded, ttl: Duration) -> Result<(), WriteError>;
+
+    async fn delete(&self, key: &str) -> Result<(), WriteError>;
+
+    /// Extends the TTL of `key`, whose value is known to be `value`. Targets
+    /// that no longer have the key should set it again.
+    async fn touch(&self, key: &str, value: Encoded, ttl: Duration) -> Result<(), WriteError>;
+
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    /// Writes that failed since the last successful write
+    fn consecutive_failures(&self) -> usize {
+        0
+    }
+
+    /// Stops accepting writes and waits for pending ones to finish
+    async fn shutdown(&self) {}
+}
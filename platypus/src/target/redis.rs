@@ -0,0 +1,205 @@
+//! Redis as a target, spoken to with RESP.
+//!
+//! [`Writer`](crate::Writer) uses this for `redis://host:port` addresses, so
+//! Redis gets the same queueing, coalescing and retries as memcached. Values
+//! are written with `SETEX`, removed with `DEL` and touched with `EXPIRE`.
+//! Redis has no item flags, so the server refuses to compress values or set
+//! client flags for a Redis target.
+
+use crate::writer::{WriteFailure, WriteOp};
+use std::io;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple,
+    Integer(i64),
+    Bulk,
+}
+
+pub(crate) struct Connection {
+    stream: BufStream<TcpStream>,
+}
+
+impl Connection {
+    /// Connects to `host:port`, ignoring any database path after it
+    pub(crate) async fn connect(address: &str) -> io::Result<Self> {
+        let address = address.split('/').next().unwrap_or(address);
+        let stream = TcpStream::connect(address).await?;
+        Ok(Self {
+            stream: BufStream::new(stream),
+        })
+    }
+
+    pub(crate) async fn apply(
+        &mut self,
+        key: &str,
+        op: &WriteOp,
+        ttl_secs: u32,
+    ) -> Result<(), WriteFailure> {
+        match op {
+            WriteOp::Set(value) => self.set(key, &value.data, ttl_secs).await,
+            WriteOp::Touch(value) if ttl_secs > 0 => {
+                let ttl = ttl_secs.to_string();
+                // EXPIRE answers 0 when the key is gone
+                match self
+                    .command(&[b"EXPIRE", key.as_bytes(), ttl.as_bytes()])
+                    .await?
+                {
+                    Reply::Integer(0) => self.set(key, &value.data, ttl_secs).await,
+                    _ => Ok(()),
+                }
+            }
+            // A TTL of zero never expires, so there is nothing to extend
+            WriteOp::Touch(value) => self.set(key, &value.data, ttl_secs).await,
+            WriteOp::Delete => self.command(&[b"DEL", key.as_bytes()]).await.map(|_| ()),
+        }
+    }
+
+    async fn set(&mut self, key: &str, value: &[u8], ttl_secs: u32) -> Result<(), WriteFailure> {
+        let reply = if ttl_secs > 0 {
+            let ttl = ttl_secs.to_string();
+            self.command(&[b"SETEX", key.as_bytes(), ttl.as_bytes(), value])
+                .await?
+        } else {
+            self.command(&[b"SET", key.as_bytes(), value]).await?
+        };
+        match reply {
+            Reply::Simple => Ok(()),
+            reply => Err(WriteFailure::Broken(format!(
+                "unexpected reply to set: {:?}",
+                reply
+            ))),
+        }
+    }
+
+    async fn command(&mut self, args: &[&[u8]]) -> Result<Reply, WriteFailure> {
+        let broken = |e: io::Error| WriteFailure::Broken(e.to_string());
+
+        let mut request = format!("*{}\r\n", args.len()).into_bytes();
+        for arg in args {
+            request.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+            request.extend_from_slice(arg);
+            request.extend_from_slice(b"\r\n");
+        }
+        self.stream.write_all(&request).await.map_err(broken)?;
+        self.stream.flush().await.map_err(broken)?;
+
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await.map_err(broken)? == 0 {
+            return Err(WriteFailure::Broken("connection closed".to_string()));
+        }
+        let line = line.trim_end();
+        let invalid = || WriteFailure::Broken(format!("invalid reply: {}", line));
+        match line.split_at_checked(1) {
+            Some(("+", _)) => Ok(Reply::Simple),
+            Some(("-", error)) => Err(WriteFailure::Rejected(error.to_string())),
+            Some((":", n)) => n.parse().map(Reply::Integer).map_err(|_| invalid()),
+            Some(("$", "-1")) => Ok(Reply::Bulk),
+            Some(("$", len)) => {
+                // Nothing here reads values back, skip it
+                let len: usize = len.parse().map_err(|_| invalid())?;
+                let mut data = vec![0; len + 2];
+                self.stream.read_exact(&mut data).await.map_err(broken)?;
+                Ok(Reply::Bulk)
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Writer;
+    use crate::target::Target;
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+    use tokio::task::JoinHandle;
+
+    // Accepts one connection and answers each command with the given replies
+    // in turn. Returns the commands received, with their arguments joined by
+    // spaces.
+    async fn fake_redis(replies: Vec<&'static str>) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("redis://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(socket);
+            let mut commands = Vec::new();
+            for reply in replies {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                let count: usize = line.trim_end()[1..].parse().unwrap();
+                let mut args = Vec::new();
+                for _ in 0..count {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).await.unwrap();
+                    let len: usize = header.trim_end()[1..].parse().unwrap();
+                    let mut data = vec![0; len + 2];
+                    reader.read_exact(&mut data).await.unwrap();
+                    args.push(String::from_utf8_lossy(&data[..len]).to_string());
+                }
+                commands.push(args.join(" "));
+                reader.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+            commands
+        });
+        (address, handle)
+    }
+
+    #[tokio::test]
+    async fn test_set_touch_delete() {
+        let (address, server) =
+            fake_redis(vec!["+OK\r\n", ":1\r\n", ":0\r\n", "+OK\r\n", ":1\r\n"]).await;
+        let writer = Writer::builder(&address).with_pool_size(1).build();
+        let ttl = Duration::from_secs(60);
+
+        // Each write waits for the previous one, so none are coalesced
+        let settle = || tokio::time::sleep(Duration::from_millis(50));
+        writer.set("key", "value".into(), ttl).await.unwrap();
+        settle().await;
+        writer.touch("key", "value".into(), ttl).await.unwrap();
+        settle().await;
+        // The key is gone by now, so it is set again
+        writer.touch("key", "value".into(), ttl).await.unwrap();
+        settle().await;
+        writer.delete("key").await.unwrap();
+        writer.shutdown().await;
+
+        assert_eq!(
+            server.await.unwrap(),
+            vec![
+                "SETEX key 60 value",
+                "EXPIRE key 60",
+                "EXPIRE key 60",
+                "SETEX key 60 value",
+                "DEL key",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_reply_is_retried() {
+        let (address, server) = fake_redis(vec!["-OOM command not allowed\r\n", "+OK\r\n"]).await;
+        let writer = Writer::builder(&address)
+            .with_pool_size(1)
+            .with_retries(1, Duration::from_millis(1))
+            .build();
+
+        writer
+            .set("key", "value".into(), Duration::ZERO)
+            .await
+            .unwrap();
+        writer.shutdown().await;
+
+        assert_eq!(
+            server.await.unwrap(),
+            vec!["SET key value", "SET key value"]
+        );
+        assert_eq!(writer.consecutive_failures(), 0);
+    }
+}
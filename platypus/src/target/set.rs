@@ -0,0 +1,413 @@
+//! A [`TargetSet`] either replicates every write to all of its nodes, or
+//! places each key on one node the same way memcached's built-in proxy does
+//! by default: an XXH3 hash of the key, distributed with jump consistent
+//! hashing. Keys are then written to the node the proxy will read them from.
+
+use super::{Target, ValueLimit};
+use crate::Encoded;
+use crate::metrics::metrics;
+use crate::writer::WriteError;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::time::Duration;
+use tracing::warn;
+use xxhash_rust::xxh3::xxh3_64;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Distribution {
+    /// Write every key to every node
+    Replicate,
+    /// Write each key to the one node its hash selects
+    #[default]
+    Hash,
+}
+
+/// Health of one node
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodeHealth {
+    pub address: String,
+    pub connected: bool,
+    /// Writes that failed after all retries since the last successful write
+    pub consecutive_failures: usize,
+}
+
+impl NodeHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.connected && self.consecutive_failures == 0
+    }
+}
+
+#[derive(Default)]
+pub struct TargetSet {
+    distribution: Distribution,
+    nodes: Vec<Box<dyn Target>>,
+    limit: Option<ValueLimit>,
+    // Chunk keys last written for each chunked value, to delete them with it
+    chunks: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl TargetSet {
+    pub fn new(distribution: Distribution) -> Self {
+        Self {
+            distribution,
+            nodes: Vec::new(),
+            limit: None,
+            chunks: Mutex::default(),
+        }
+    }
+
+    /// Adds a node. With [`Distribution::Hash`] the order of the nodes
+    /// decides placement, so it must match the proxy's pool.
+    pub fn with_node(mut self, target: impl Target) -> Self {
+        self.nodes.push(Box::new(target));
+        self
+    }
+
+    /// What to write instead of values larger than the nodes accept
+    pub fn with_value_limit(mut self, limit: ValueLimit) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// A set of one target
+    pub fn single(target: impl Target) -> Self {
+        Self::default().with_node(target)
+    }
+
+    pub fn distribution(&self) -> Distribution {
+        self.distribution
+    }
+
+    pub fn value_limit(&self) -> Option<ValueLimit> {
+        self.limit
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn health(&self) -> Vec<NodeHealth> {
+        self.nodes
+            .iter()
+            .map(|node| NodeHealth {
+                address: node.address().to_string(),
+                connected: node.is_connected(),
+                consecutive_failures: node.consecutive_failures(),
+            })
+            .collect()
+    }
+
+    /// Replicated sets are ready once any node is connected. Hashed sets need
+    /// every node, or the keys placed on a missing node are lost.
+    pub fn is_ready(&self) -> bool {
+        match self.distribution {
+            Distribution::Replicate => self.nodes.iter().any(|node| node.is_connected()),
+            Distribution::Hash => self.nodes.iter().all(|node| node.is_connected()),
+        }
+    }
+
+    /// Queues a write of `value` to `key` on the nodes that hold it, or a
+    /// delete if `value` is None
+    pub async fn send(
+        &self,
+        key: &str,
+        value: Option<Encoded>,
+        ttl: Duration,
+    ) -> Result<(), WriteError> {
+        let Some(value) = value else {
+            let mut result = self.delete_chunks(key, &[]).await;
+            if let Err(e) = self.delete(key).await {
+                result = Err(e);
+            }
+            return result;
+        };
+        if let Some(limit) = &self.limit
+            && limit.exceeded_by(&value)
+        {
+            warn!(
+                key = key,
+                size = value.data.len(),
+                max_size = limit.max_size(),
+                action = limit.action(&value).name(),
+                "Value is over the maximum value size"
+            );
+            metrics()
+                .oversize_values
+                .with_label_values(&[limit.action(&value).name()])
+                .inc();
+        }
+        let writes = self.split(key, value);
+        let mut result = self.delete_chunks(key, &writes).await;
+        if writes.is_empty() {
+            // Rejected, so clients miss rather than read an older value
+            if let Err(e) = self.delete(key).await {
+                result = Err(e);
+            }
+            return result;
+        }
+        for (key, value) in writes {
+            for node in self.nodes_for(&key) {
+                if let Err(e) = node.set(&key, value.clone(), ttl).await {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    /// Queues extending the TTL of `key` on the nodes that hold it
+    pub async fn touch(&self, key: &str, value: Encoded, ttl: Duration) -> Result<(), WriteError> {
+        let mut result = Ok(());
+        for (key, value) in self.split(key, value) {
+            for node in self.nodes_for(&key) {
+                if let Err(e) = node.touch(&key, value.clone(), ttl).await {
+                    result = Err(e);
+                }
+            }
+        }
+        result
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), WriteError> {
+        let mut result = Ok(());
+        for node in self.nodes_for(key) {
+            if let Err(e) = node.delete(key).await {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    // Deletes the chunks last written for `key` that `writes` doesn't write
+    // again, and remembers the ones it does
+    async fn delete_chunks(
+        &self,
+        key: &str,
+        writes: &[(String, Encoded)],
+    ) -> Result<(), WriteError> {
+        let chunks: Vec<String> = writes
+            .iter()
+            .map(|(chunk, _)| chunk)
+            .filter(|chunk| *chunk != key)
+            .cloned()
+            .collect();
+        let previous = {
+            let mut chunked = self.chunks.lock().unwrap();
+            if chunks.is_empty() {
+                chunked.remove(key)
+            } else {
+                chunked.insert(key.to_string(), chunks.clone())
+            }
+        };
+
+        let mut result = Ok(());
+        for chunk in previous.into_iter().flatten() {
+            if !chunks.contains(&chunk)
+                && let Err(e) = self.delete(&chunk).await
+            {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    // What is written for `value`, within the value limit
+    fn split(&self, key: &str, value: Encoded) -> Vec<(String, Encoded)> {
+        match &self.limit {
+            Some(limit) => limit.split(key, value),
+            None => vec![(key.to_string(), value)],
+        }
+    }
+
+    /// Stops accepting writes and waits for every node's queued writes
+    pub async fn shutdown(&self) {
+        for node in &self.nodes {
+            node.shutdown().await;
+        }
+    }
+
+    /// Index of the node a key is placed on, with [`Distribution::Hash`]
+    pub fn node_index(&self, key: &str) -> Option<usize> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        Some(jump_hash(xxh3_64(key.as_bytes()), self.nodes.len()))
+    }
+
+    fn nodes_for(&self, key: &str) -> &[Box<dyn Target>] {
+        match self.distribution {
+            Distribution::Replicate => &self.nodes,
+            Distribution::Hash => match self.node_index(key) {
+                Some(index) => std::slice::from_ref(&self.nodes[index]),
+                None => &[],
+            },
+        }
+    }
+}
+
+// Jump consistent hash (Lamping & Veach), as used by memcached's proxy
+fn jump_hash(mut key: u64, buckets: usize) -> usize {
+    let mut b: i64 = -1;
+    let mut j: i64 = 0;
+    while j < buckets as i64 {
+        b = j;
+        key = key.wrapping_mul(2862933555777941757).wrapping_add(1);
+        j = ((b + 1) as f64 * ((1u64 << 31) as f64 / ((key >> 33) + 1) as f64)) as i64;
+    }
+    b as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Writer;
+    use crate::target::InMemory;
+    use crate::writer::tests::fake_memcached;
+
+    #[test]
+    fn test_jump_hash() {
+        for key in 0..1000u64 {
+            assert_eq!(jump_hash(key, 1), 0);
+            assert!(jump_hash(key, 7) < 7);
+        }
+        // Key 0 always lands on the first node
+        assert_eq!(jump_hash(0, 1000), 0);
+    }
+
+    #[test]
+    fn test_jump_hash_moves_few_keys() {
+        // Growing from 4 to 5 nodes only moves keys onto the new node
+        let mut moved = 0;
+        for key in 0..10_000u64 {
+            let key = xxh3_64(&key.to_le_bytes());
+            let before = jump_hash(key, 4);
+            let after = jump_hash(key, 5);
+            if before != after {
+                assert_eq!(after, 4);
+                moved += 1;
+            }
+        }
+        assert!((1500..2500).contains(&moved), "moved {}", moved);
+    }
+
+    #[tokio::test]
+    async fn test_hash_writes_to_one_node() {
+        let (first, first_server) = fake_memcached(vec!["STORED\r\n"]).await;
+        let (second, second_server) = fake_memcached(vec!["STORED\r\n"]).await;
+        let targets = TargetSet::new(Distribution::Hash)
+            .with_node(Writer::builder(&first).with_pool_size(1).build())
+            .with_node(Writer::builder(&second).with_pool_size(1).build());
+
+        let index = targets.node_index("key").unwrap();
+        targets
+            .send("key", Some("value".into()), Duration::from_secs(60))
+            .await
+            .unwrap();
+        targets.shutdown().await;
+
+        let commands = [first_server.await.unwrap(), second_server.await.unwrap()];
+        assert_eq!(commands[index], vec!["set key 0 60 5"]);
+        assert!(commands[1 - index].is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replicate_writes_to_all_nodes() {
+        let (first, first_server) = fake_memcached(vec!["STORED\r\n"]).await;
+        let (second, second_server) = fake_memcached(vec!["STORED\r\n"]).await;
+        let targets = TargetSet::new(Distribution::Replicate)
+            .with_node(Writer::builder(&first).with_pool_size(1).build())
+            .with_node(Writer::builder(&second).with_pool_size(1).build());
+
+        targets
+            .send("key", Some("value".into()), Duration::from_secs(60))
+            .await
+            .unwrap();
+        targets.shutdown().await;
+
+        assert_eq!(first_server.await.unwrap(), vec!["set key 0 60 5"]);
+        assert_eq!(second_server.await.unwrap(), vec!["set key 0 60 5"]);
+    }
+
+    #[tokio::test]
+    async fn test_readiness_and_health() {
+        let (up, _server) = fake_memcached(vec![]).await;
+        let down = "tcp://127.0.0.1:1";
+        let nodes = || {
+            [
+                Writer::builder(&up).with_pool_size(1).build(),
+                Writer::builder(down).with_pool_size(1).build(),
+            ]
+        };
+
+        let [a, b] = nodes();
+        let replicated = TargetSet::new(Distribution::Replicate)
+            .with_node(a)
+            .with_node(b);
+        let [a, b] = nodes();
+        let hashed = TargetSet::new(Distribution::Hash).with_node(a).with_node(b);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        assert!(replicated.is_ready());
+        assert!(!hashed.is_ready());
+        let health = hashed.health();
+        assert_eq!(health[1].address, down);
+        assert!(!health[1].is_healthy());
+        replicated.shutdown().await;
+        hashed.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_replicated_delete() {
+        let (first, second) = (InMemory::new(), InMemory::new());
+        let targets = TargetSet::new(Distribution::Replicate)
+            .with_node(first.clone())
+            .with_node(second.clone());
+        let ttl = Duration::from_secs(60);
+
+        targets
+            .send("key", Some("value".into()), ttl)
+            .await
+            .unwrap();
+        assert_eq!(second.get("key"), Some("value".into()));
+        targets.send("key", None, ttl).await.unwrap();
+        assert!(first.is_empty() && second.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_value_limit() {
+        use crate::target::{Oversize, ValueLimit};
+
+        let memory = InMemory::new();
+        let ttl = Duration::from_secs(60);
+        let large = Encoded::from("a".repeat(25));
+
+        // A rejected value removes the older one
+        let targets = TargetSet::single(memory.clone()).with_value_limit(ValueLimit::new(10));
+        targets.send("key", Some("old".into()), ttl).await.unwrap();
+        targets.send("key", Some(large.clone()), ttl).await.unwrap();
+        assert!(memory.is_empty());
+
+        let targets = TargetSet::single(memory.clone())
+            .with_value_limit(ValueLimit::new(10).with_oversize(Oversize::Chunk));
+        targets.send("key", Some(large.clone()), ttl).await.unwrap();
+        assert_eq!(memory.len(), 4);
+        let version = format!("{:016x}", xxh3_64(b"a".repeat(25).as_slice()));
+        let last_chunk = crate::target::limit::chunk_key("key", &version, 2);
+        assert_eq!(memory.get(&last_chunk), Some("aaaaa".into()));
+
+        // Chunks go with the value, whether deleted or replaced
+        targets.send("key", None, ttl).await.unwrap();
+        assert!(memory.is_empty());
+        targets.send("key", Some(large), ttl).await.unwrap();
+        targets
+            .send("key", Some("small".into()), ttl)
+            .await
+            .unwrap();
+        assert_eq!(memory.len(), 1);
+        assert_eq!(memory.get("key"), Some("small".into()));
+    }
+}
@@ -0,0 +1,358 @@
+//! Templates for keys, paths, URLs and values, filled in from a request's
+//! captures.
+//!
+//! - `{name}` is the value of `name`. Names may be templates themselves, so
+//!   `{{name}}` is the value of the variable named by the value of `name`.
+//! - `{name|default}` is `default` when there is no `name`.
+//! - `{name:upper:urlencode}` passes the value through filters, in order.
+//! - `\{`, `\}`, `\|`, `\:` and `\\` are the characters themselves, e.g. for
+//!   JSON. Other backslashes are kept as they are.
+//!
+//! Missing variables become empty strings, unless the template is strict.
+
+use base64::{Engine as _, engine::general_purpose};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+
+// Characters left as they are in URL components, as in RFC 3986
+const UNRESERVED: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+const ESCAPED: [char; 5] = ['{', '}', '|', ':', '\\'];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    UrlEncode,
+    Lower,
+    Upper,
+    Base64,
+    Json,
+    Sha256,
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "urlencode" => Ok(Filter::UrlEncode),
+            "lower" => Ok(Filter::Lower),
+            "upper" => Ok(Filter::Upper),
+            "base64" => Ok(Filter::Base64),
+            "json" => Ok(Filter::Json),
+            "sha256" => Ok(Filter::Sha256),
+            _ => Err(format!(
+                "unknown filter '{}', expected urlencode, lower, upper, base64, json or sha256",
+                s
+            )),
+        }
+    }
+}
+
+impl Filter {
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            Filter::UrlEncode => utf8_percent_encode(value, UNRESERVED).to_string(),
+            Filter::Lower => value.to_lowercase(),
+            Filter::Upper => value.to_uppercase(),
+            Filter::Base64 => general_purpose::STANDARD.encode(value),
+            // Escaped for inside a JSON string, without the quotes
+            Filter::Json => {
+                let quoted = serde_json::Value::from(value).to_string();
+                quoted[1..quoted.len() - 1].to_string()
+            }
+            Filter::Sha256 => Sha256::digest(value)
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Text(String),
+    Placeholder(Placeholder),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Placeholder {
+    name: Vec<Segment>,
+    default: Option<Vec<Segment>>,
+    filters: Vec<Filter>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    text: String,
+    // Invalid templates keep their error, to fail when rendered
+    segments: Result<Vec<Segment>, String>,
+    strict: bool,
+}
+
+impl Template {
+    pub fn new(text: &str) -> Self {
+        let mut chars = text.chars().peekable();
+        Self {
+            text: text.to_string(),
+            segments: parse(&mut chars, &[]).map(|(segments, _)| segments),
+            strict: false,
+        }
+    }
+
+    /// Fails on missing variables without a default, instead of leaving them empty
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// Why the template is not valid, if it is not
+    pub fn check(&self) -> Result<(), String> {
+        self.segments.as_ref().map(|_| ()).map_err(Clone::clone)
+    }
+
+    /// Names of the variables used, leaving out the ones named by other variables
+    pub fn variables(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        if let Ok(segments) = &self.segments {
+            collect_variables(segments, &mut names);
+        }
+        names
+    }
+
+    /// The text before the first placeholder, which every rendering starts with
+    pub fn prefix(&self) -> &str {
+        match self.segments.as_deref() {
+            Ok([Segment::Text(text), ..]) => text,
+            _ => "",
+        }
+    }
+
+    pub fn render(&self, variables: &HashMap<String, String>) -> Result<String, String> {
+        let segments = self.segments.as_ref().map_err(Clone::clone)?;
+        render(segments, variables, self.strict)
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+// Reads segments up to one of `stops`, returning the stop found, or None at the end
+fn parse(
+    chars: &mut Peekable<Chars>,
+    stops: &[char],
+) -> Result<(Vec<Segment>, Option<char>), String> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut stop = None;
+
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.peek() {
+                Some(&next) if ESCAPED.contains(&next) => {
+                    text.push(next);
+                    chars.next();
+                }
+                _ => text.push(ch),
+            },
+            ch if stops.contains(&ch) => {
+                stop = Some(ch);
+                break;
+            }
+            '{' => {
+                if !text.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut text)));
+                }
+                segments.push(Segment::Placeholder(parse_placeholder(chars)?));
+            }
+            '}' => return Err("unmatched '}', escape it as \\}".to_string()),
+            ch => text.push(ch),
+        }
+    }
+
+    if !text.is_empty() {
+        segments.push(Segment::Text(text));
+    }
+    Ok((segments, stop))
+}
+
+// Reads a placeholder after its opening brace, up to and including its closing one
+fn parse_placeholder(chars: &mut Peekable<Chars>) -> Result<Placeholder, String> {
+    let (name, mut stop) = parse(chars, &['|', ':', '}'])?;
+
+    let mut default = None;
+    if stop == Some('|') {
+        let (segments, next) = parse(chars, &[':', '}'])?;
+        default = Some(segments);
+        stop = next;
+    }
+
+    let mut filters = Vec::new();
+    while stop == Some(':') {
+        let mut filter = String::new();
+        stop = None;
+        for ch in chars.by_ref() {
+            if ch == ':' || ch == '}' {
+                stop = Some(ch);
+                break;
+            }
+            filter.push(ch);
+        }
+        filters.push(filter.trim().parse()?);
+    }
+
+    if stop != Some('}') {
+        return Err("unclosed '{', escape it as \\{".to_string());
+    }
+    Ok(Placeholder {
+        name,
+        default,
+        filters,
+    })
+}
+
+fn collect_variables<'a>(segments: &'a [Segment], names: &mut Vec<&'a str>) {
+    for segment in segments {
+        if let Segment::Placeholder(placeholder) = segment {
+            match placeholder.name.as_slice() {
+                [Segment::Text(name)] => names.push(name),
+                name => collect_variables(name, names),
+            }
+            if let Some(default) = &placeholder.default {
+                collect_variables(default, names);
+            }
+        }
+    }
+}
+
+fn render(
+    segments: &[Segment],
+    variables: &HashMap<String, String>,
+    strict: bool,
+) -> Result<String, String> {
+    let mut out = String::new();
+    for segment in segments {
+        match segment {
+            Segment::Text(text) => out.push_str(text),
+            Segment::Placeholder(placeholder) => {
+                let name = render(&placeholder.name, variables, strict)?;
+                let mut value = match (variables.get(&name), &placeholder.default) {
+                    (Some(value), _) => value.clone(),
+                    (None, Some(default)) => render(default, variables, strict)?,
+                    (None, None) if strict => return Err(format!("missing variable '{}'", name)),
+                    (None, None) => String::new(),
+                };
+                for filter in &placeholder.filters {
+                    value = filter.apply(&value);
+                }
+                out.push_str(&value);
+            }
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> HashMap<String, String> {
+        HashMap::from([
+            ("name".to_string(), "Hello World".to_string()),
+            ("env".to_string(), "prod".to_string()),
+        ])
+    }
+
+    fn render(text: &str) -> Result<String, String> {
+        Template::new(text).render(&variables())
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(render("{env|dev}").unwrap(), "prod");
+        assert_eq!(render("{region|us-east-1}").unwrap(), "us-east-1");
+        assert_eq!(render("{region|{env}}").unwrap(), "prod");
+        assert_eq!(render("{region|}").unwrap(), "");
+    }
+
+    #[test]
+    fn test_filters() {
+        assert_eq!(render("{name:lower}").unwrap(), "hello world");
+        assert_eq!(render("{name:upper}").unwrap(), "HELLO WORLD");
+        assert_eq!(render("{name:urlencode}").unwrap(), "Hello%20World");
+        assert_eq!(render("{name:base64}").unwrap(), "SGVsbG8gV29ybGQ=");
+        assert_eq!(
+            render("{env:sha256}").unwrap(),
+            "6754af9632a2745e85c293e5aac0863370d9bd3330b9938c00cadfd215227d77"
+        );
+        assert_eq!(render("{region|A B:lower:urlencode}").unwrap(), "a%20b");
+        assert_eq!(
+            Template::new("{x:json}")
+                .render(&HashMap::from([("x".to_string(), "a \"b\"\n".to_string())]))
+                .unwrap(),
+            "a \\\"b\\\"\\n"
+        );
+        assert!(render("{name:reverse}").is_err());
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert_eq!(
+            render(r#"\{"env": "{env}"\}"#).unwrap(),
+            r#"{"env": "prod"}"#
+        );
+        assert_eq!(render(r"{region|a\:b}").unwrap(), "a:b");
+        assert_eq!(render(r"\{env\}").unwrap(), "{env}");
+        assert_eq!(render(r"C:\data\file").unwrap(), r"C:\data\file");
+        assert_eq!(render(r"C:\data\\{env}").unwrap(), r"C:\data\prod");
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert!(Template::new("{env").check().is_err());
+        assert!(Template::new("env}").check().is_err());
+        assert!(render("{env").is_err());
+        assert!(Template::new("{env:lower}").check().is_ok());
+    }
+
+    #[test]
+    fn test_strict() {
+        let template = Template::new("{env}/{region}").with_strict(true);
+        assert_eq!(
+            template.render(&variables()),
+            Err("missing variable 'region'".to_string())
+        );
+        let template = Template::new("{env}/{region|global}").with_strict(true);
+        assert_eq!(template.render(&variables()).unwrap(), "prod/global");
+    }
+
+    #[test]
+    fn test_prefix() {
+        assert_eq!(
+            Template::new(r"/data/\{x\}/{env}/{name}").prefix(),
+            "/data/{x}/"
+        );
+        assert_eq!(Template::new("{env}/data").prefix(), "");
+        assert_eq!(Template::new("{env").prefix(), "");
+    }
+
+    #[test]
+    fn test_variables() {
+        let template = Template::new("{env}/{{name}}/{region|{zone}:lower}");
+        assert_eq!(template.variables(), ["env", "name", "region", "zone"]);
+    }
+}
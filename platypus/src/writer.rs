@@ -1,277 +1,1052 @@
-use crate::Value;
-use memcache::MemcacheError;
-use std::sync::mpsc::{RecvTimeoutError, Sender, channel};
-use std::thread::JoinHandle;
-use tokio::time::Duration;
-use tracing::{error, info};
+use crate::Encoded;
+use crate::metrics::metrics;
+use crate::target::{Target, redis};
+use async_memcached::{AsciiProtocol, Client, Error as MemcacheError, MetaProtocol, Status};
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::pin::pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+use tracing::{Instrument, Span, debug, error, info, info_span, warn};
+
+// Limit on connecting to, and on each request to, the target
+const TARGET_TIMEOUT: Duration = Duration::from_secs(1);
+
+// CAS values remembered per connection for guarded writes
+const KNOWN_CAS_CAPACITY: u64 = 100_000;
+const KNOWN_CAS_IDLE: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Error, PartialEq)]
+pub enum WriteError {
+    #[error("write queue full")]
+    QueueFull,
+
+    #[error("writer shut down")]
+    Closed,
+}
+
+/// What to do with a write when its queue is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueOverflow {
+    /// Drop the new write
+    DropNewest,
+    /// Drop the oldest queued write to make room. A refresh supersedes the
+    /// writes queued before it, so this is the default.
+    DropOldest,
+    /// Wait until there is room
+    Backpressure,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum WriteOp {
+    Set(Encoded),
+    /// Extends the TTL of a value the target already has. The value is only
+    /// sent if the target no longer has the key.
+    Touch(Encoded),
+    Delete,
+}
+
+impl WriteOp {
+    fn name(&self) -> &'static str {
+        match self {
+            WriteOp::Set(_) => "set",
+            WriteOp::Touch(_) => "touch",
+            WriteOp::Delete => "delete",
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct WriteJob {
     key: String,
-    value: Option<Value>,
+    op: WriteOp,
     ttl_secs: u32,
+    queued_at: Instant,
+    // Entered on the writer task, so the write shows up in the caller's trace
+    span: Span,
 }
 
-pub struct Writer {
-    sender: Sender<WriteJob>,
-    shutdown_sender: Sender<()>,
-    handle: JoinHandle<()>,
+impl WriteJob {
+    // Folds a newer write to the same key into this one
+    fn coalesce(&mut self, newer: WriteJob) {
+        match (&self.op, newer.op) {
+            // A touch only changes when the queued set or delete expires
+            (WriteOp::Set(_), WriteOp::Touch(_)) | (WriteOp::Delete, WriteOp::Touch(_)) => {}
+            (_, op) => self.op = op,
+        }
+        self.ttl_secs = newer.ttl_secs;
+        self.span = newer.span;
+    }
 }
 
-impl Writer {
-    pub fn new(target_address: &str) -> Self {
-        let (tx, rx) = channel::<WriteJob>();
-        let (shutdown_tx, shutdown_rx) = channel::<()>();
-
-        let target_address = target_address.to_string();
-        let handle = std::thread::Builder::new()
-            .name(format!("writer/{}", target_address))
-            .spawn(move || {
-                info!(target_address = ?target_address, "Writer thread started");
-                let mut client = Self::client(target_address.as_str());
-                loop {
-                    // Check for shutdown signal
-                    if shutdown_rx.try_recv().is_ok() {
-                        break;
+// Queued writes, at most one per key
+#[derive(Default)]
+struct Pending {
+    order: VecDeque<String>,
+    jobs: HashMap<String, WriteJob>,
+}
+
+// Bounded FIFO of writes, drained by one worker
+struct WriteQueue {
+    pending: Mutex<Pending>,
+    capacity: usize,
+    overflow: QueueOverflow,
+    job_ready: Notify,
+    space_ready: Notify,
+    closed: AtomicBool,
+}
+
+impl WriteQueue {
+    fn new(capacity: usize, overflow: QueueOverflow) -> Self {
+        Self {
+            pending: Mutex::new(Pending::default()),
+            capacity: capacity.max(1),
+            overflow,
+            job_ready: Notify::new(),
+            space_ready: Notify::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    async fn push(&self, job: WriteJob) -> Result<(), WriteError> {
+        loop {
+            // Register before checking, so a pop in between is not missed
+            let mut space_ready = pin!(self.space_ready.notified());
+            space_ready.as_mut().enable();
+
+            if self.closed.load(Ordering::Relaxed) {
+                return Err(WriteError::Closed);
+            }
+            {
+                let mut pending = self.pending.lock().unwrap();
+                if let Some(queued) = pending.jobs.get_mut(&job.key) {
+                    queued.coalesce(job);
+                    metrics().writer_coalesced.inc();
+                    return Ok(());
+                }
+                if pending.order.len() < self.capacity {
+                    pending.order.push_back(job.key.clone());
+                    pending.jobs.insert(job.key.clone(), job);
+                    metrics().writer_queue_depth.inc();
+                    self.job_ready.notify_one();
+                    return Ok(());
+                }
+                match self.overflow {
+                    QueueOverflow::DropNewest => {
+                        metrics().writer_dropped.inc();
+                        return Err(WriteError::QueueFull);
                     }
-                    // Check if there is a job
-                    match rx.recv_timeout(std::time::Duration::from_millis(10)) {
-                        Ok(job) => client = Self::process(client, target_address.as_str(), job),
-                        Err(RecvTimeoutError::Timeout) => {}
-                        Err(RecvTimeoutError::Disconnected) => {}
+                    QueueOverflow::DropOldest => {
+                        if let Some(oldest) = pending.order.pop_front() {
+                            pending.jobs.remove(&oldest);
+                        }
+                        pending.order.push_back(job.key.clone());
+                        pending.jobs.insert(job.key.clone(), job);
+                        metrics().writer_dropped.inc();
+                        self.job_ready.notify_one();
+                        return Ok(());
                     }
+                    QueueOverflow::Backpressure => {}
                 }
-                // Process remaining jobs before shutting down
-                while let Ok(job) = rx.try_recv() {
-                    client = Self::process(client, target_address.as_str(), job)
-                }
-                info!(target_address = ?target_address, "Writer thread terminated");
-            })
-            .expect("failed to spawn memcache writer thread");
+            }
+            space_ready.await;
+        }
+    }
+
+    // Returns None once the queue is closed and empty
+    async fn pop(&self) -> Option<WriteJob> {
+        loop {
+            let mut job_ready = pin!(self.job_ready.notified());
+            job_ready.as_mut().enable();
+
+            let job = {
+                let mut pending = self.pending.lock().unwrap();
+                let key = pending.order.pop_front();
+                key.and_then(|key| pending.jobs.remove(&key))
+            };
+            if let Some(job) = job {
+                metrics().writer_queue_depth.dec();
+                self.space_ready.notify_one();
+                return Some(job);
+            }
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+            job_ready.await;
+        }
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.job_ready.notify_waiters();
+        self.space_ready.notify_waiters();
+    }
+}
+
+pub struct WriterBuilder {
+    target_address: String,
+    pool_size: usize,
+    queue_capacity: usize,
+    overflow: QueueOverflow,
+    max_retries: u32,
+    retry_backoff: Duration,
+    cas: bool,
+}
+
+impl WriterBuilder {
+    pub fn new(target_address: &str) -> Self {
+        Self {
+            target_address: target_address.to_string(),
+            pool_size: 2,
+            queue_capacity: 10_000,
+            overflow: QueueOverflow::DropOldest,
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(50),
+            cas: false,
+        }
+    }
+
+    /// Number of connections to the target. Writes to the same key always use
+    /// the same connection, so they are applied in order.
+    pub fn with_pool_size(mut self, pool_size: usize) -> Self {
+        self.pool_size = pool_size.max(1);
+        self
+    }
+
+    /// Maximum writes waiting to be sent, shared between the connections
+    pub fn with_queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.queue_capacity = queue_capacity;
+        self
+    }
+
+    pub fn with_overflow(mut self, overflow: QueueOverflow) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    /// Retries after a failed write, waiting `retry_backoff` doubled on each attempt
+    pub fn with_retries(mut self, max_retries: u32, retry_backoff: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.retry_backoff = retry_backoff;
+        self
+    }
+
+    /// Guards memcached writes with CAS, so a refresh never overwrites a value
+    /// something else wrote since this writer last wrote the key, and never
+    /// shortens a longer TTL the target already has. Costs an `mg` per write.
+    pub fn with_cas(mut self, cas: bool) -> Self {
+        self.cas = cas;
+        self
+    }
+
+    /// Starts the writer. Must be called from within a tokio runtime.
+    pub fn build(self) -> Writer {
+        let dsn = dsn(&self.target_address);
+        let connected = Arc::new(AtomicUsize::new(0));
+        let failures = Arc::new(AtomicUsize::new(0));
+        let capacity = self.queue_capacity.div_ceil(self.pool_size);
+
+        let mut queues = Vec::new();
+        let mut workers = Vec::new();
+        for id in 0..self.pool_size {
+            let queue = Arc::new(WriteQueue::new(capacity, self.overflow));
+            let worker = Worker {
+                dsn: dsn.clone(),
+                connection: None,
+                connected: connected.clone(),
+                failures: failures.clone(),
+                max_retries: self.max_retries,
+                retry_backoff: self.retry_backoff,
+                known_cas: self.cas.then(|| {
+                    moka::future::Cache::builder()
+                        .max_capacity(KNOWN_CAS_CAPACITY)
+                        .time_to_idle(KNOWN_CAS_IDLE)
+                        .build()
+                }),
+            };
+            let span = info_span!("writer", target_address = self.target_address, id = id);
+            workers.push(tokio::spawn(worker.run(queue.clone()).instrument(span)));
+            queues.push(queue);
+        }
 
         Writer {
-            sender: tx,
-            shutdown_sender: shutdown_tx,
-            handle,
+            target_address: self.target_address,
+            queues,
+            workers: Mutex::new(workers),
+            connected,
+            failures,
         }
     }
+}
+
+pub struct Writer {
+    target_address: String,
+    queues: Vec<Arc<WriteQueue>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    connected: Arc<AtomicUsize>,
+    // Writes given up on since the last successful one
+    failures: Arc<AtomicUsize>,
+}
 
-    pub fn send(
-        &self,
-        key: &str,
-        value: Option<Value>,
-        ttl: Duration,
-    ) -> Result<(), std::sync::mpsc::SendError<WriteJob>> {
-        let ttl_secs = ttl.as_secs() as u32;
+impl Writer {
+    /// Starts a writer with the default pool size, queue and retries
+    pub fn new(target_address: &str) -> Self {
+        WriterBuilder::new(target_address).build()
+    }
+
+    pub fn builder(target_address: &str) -> WriterBuilder {
+        WriterBuilder::new(target_address)
+    }
+
+    async fn push(&self, key: &str, op: WriteOp, ttl: Duration) -> Result<(), WriteError> {
         let job = WriteJob {
             key: key.into(),
-            value,
-            ttl_secs,
+            span: info_span!("write", key = key, op = op.name()),
+            op,
+            ttl_secs: ttl.as_secs() as u32,
+            queued_at: Instant::now(),
         };
-        self.sender.send(job)
-    }
-
-    pub fn shutdown(self) {
-        // Signal shutdown
-        let _ = self.shutdown_sender.send(());
-        // Wait for thread to finish processing all jobs
-        let _ = self.handle.join();
-    }
-
-    fn client(target_address: &str) -> Result<memcache::Client, MemcacheError> {
-        memcache::Client::builder()
-            .with_connection_timeout(std::time::Duration::from_secs(1))
-            .add_server(target_address)?
-            .build()
-    }
-
-    fn process(
-        client: Result<memcache::Client, MemcacheError>,
-        target_address: &str,
-        job: WriteJob,
-    ) -> Result<memcache::Client, MemcacheError> {
-        match client {
-            Ok(ref c) => {
-                info!(target_address = ?target_address, key = job.key.as_str(),  "Wrote");
-                let err = match job.value {
-                    Some(value) => c.set(job.key.as_str(), value, job.ttl_secs).err(),
-                    None => c.delete(job.key.as_str()).err(),
-                };
-                if let Some(MemcacheError::IOError(_)) = err {
-                    return Self::client(target_address);
+        self.queue(key).push(job).await
+    }
+
+    fn queue(&self, key: &str) -> &WriteQueue {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        &self.queues[hasher.finish() as usize % self.queues.len()]
+    }
+}
+
+/// Writes to a memcached server, or to Redis for `redis://` addresses.
+/// Writes are queued, and sent in the background.
+#[async_trait]
+impl Target for Writer {
+    fn address(&self) -> &str {
+        &self.target_address
+    }
+
+    async fn set(&self, key: &str, value: Encoded, ttl: Duration) -> Result<(), WriteError> {
+        self.push(key, WriteOp::Set(value), ttl).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), WriteError> {
+        self.push(key, WriteOp::Delete, Duration::ZERO).await
+    }
+
+    async fn touch(&self, key: &str, value: Encoded, ttl: Duration) -> Result<(), WriteError> {
+        self.push(key, WriteOp::Touch(value), ttl).await
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed) > 0
+    }
+
+    fn consecutive_failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    async fn shutdown(&self) {
+        for queue in &self.queues {
+            queue.close();
+        }
+        let workers = std::mem::take(&mut *self.workers.lock().unwrap());
+        for worker in workers {
+            let _ = worker.await;
+        }
+    }
+}
+
+// Accepts the `memcache://host:port` form used by the memcache crate
+fn dsn(target_address: &str) -> String {
+    match target_address.strip_prefix("memcache://") {
+        Some(rest) => {
+            let address = rest.split('?').next().unwrap_or(rest);
+            format!("tcp://{}", address)
+        }
+        None => target_address.to_string(),
+    }
+}
+
+/// Why a write to the target failed
+pub(crate) enum WriteFailure {
+    /// The target answered with an error, the connection is still usable
+    Rejected(String),
+    /// The connection failed or is out of sync
+    Broken(String),
+}
+
+impl From<MemcacheError> for WriteFailure {
+    fn from(e: MemcacheError) -> Self {
+        match e {
+            MemcacheError::Protocol(status) => WriteFailure::Rejected(format!("{:?}", status)),
+            e => WriteFailure::Broken(e.to_string()),
+        }
+    }
+}
+
+enum Connection {
+    Memcache(Client),
+    Redis(redis::Connection),
+}
+
+impl Connection {
+    async fn open(dsn: &str) -> Result<Self, String> {
+        match dsn.strip_prefix("redis://") {
+            Some(address) => redis::Connection::connect(address)
+                .await
+                .map(Connection::Redis)
+                .map_err(|e| e.to_string()),
+            None => Client::new(dsn)
+                .await
+                .map(Connection::Memcache)
+                .map_err(|e| e.to_string()),
+        }
+    }
+
+    async fn apply(
+        &mut self,
+        job: &WriteJob,
+        known_cas: Option<&KnownCas>,
+    ) -> Result<(), WriteFailure> {
+        let client = match self {
+            Connection::Memcache(client) => client,
+            Connection::Redis(connection) => {
+                return connection.apply(&job.key, &job.op, job.ttl_secs).await;
+            }
+        };
+        if let Some(known_cas) = known_cas {
+            return write_guarded(client, job, known_cas)
+                .await
+                .map_err(WriteFailure::from);
+        }
+        let ttl = Some(job.ttl_secs as i64);
+        let result = match &job.op {
+            WriteOp::Set(value) => {
+                client
+                    .set(&job.key, value.data.as_slice(), ttl, Some(value.flags))
+                    .await
+            }
+            WriteOp::Touch(value) => {
+                // `mg <key> T<ttl> k` updates the TTL, and returns the key on a hit
+                let flags = [format!("T{}", job.ttl_secs), "k".to_string()];
+                let flags: Vec<&str> = flags.iter().map(String::as_str).collect();
+                match client.meta_get(&job.key, false, None, Some(&flags)).await {
+                    Ok(Some(_)) => Ok(()),
+                    Ok(None) => {
+                        client
+                            .set(&job.key, value.data.as_slice(), ttl, Some(value.flags))
+                            .await
+                    }
+                    Err(e) => Err(e),
                 }
             }
-            Err(err) => {
-                error!(error = ?err, "client was not connected");
-                return Self::client(target_address);
+            WriteOp::Delete => match client.delete(&job.key).await {
+                // Already gone is what a delete wants
+                Err(MemcacheError::Protocol(Status::NotFound)) => Ok(()),
+                result => result,
+            },
+        };
+        result.map_err(WriteFailure::from)
+    }
+}
+
+type KnownCas = moka::future::Cache<String, u64>;
+
+// Writes `job` unless the key changed on the target since this connection
+// last wrote it. Such a value is newer than the refresh being written, so it
+// is kept and the write counted as a conflict.
+async fn write_guarded(
+    client: &mut Client,
+    job: &WriteJob,
+    known_cas: &KnownCas,
+) -> Result<(), MemcacheError> {
+    let key = job.key.as_str();
+    let conflict = || {
+        debug!(key = key, "Key changed on the target, not overwriting");
+        metrics()
+            .writer_conflicts
+            .with_label_values(&[job.op.name()])
+            .inc();
+    };
+
+    let current = client.meta_get(key, false, None, Some(&["c", "t"])).await?;
+    let Some(current) = current else {
+        // Missing: add it, unless another writer gets there first
+        return match &job.op {
+            WriteOp::Set(value) | WriteOp::Touch(value) => {
+                let ttl = format!("T{}", job.ttl_secs);
+                let mut flags = vec![ttl.as_str(), "ME", "c"];
+                let item_flags = item_flags(value);
+                flags.extend(item_flags.as_deref());
+                match client
+                    .meta_set(key, value.data.as_slice(), false, None, Some(&flags))
+                    .await
+                {
+                    Ok(stored) => {
+                        remember_cas(known_cas, key, stored.and_then(|s| s.cas)).await;
+                        Ok(())
+                    }
+                    Err(MemcacheError::Protocol(Status::NotStored)) => {
+                        conflict();
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            WriteOp::Delete => {
+                known_cas.invalidate(key).await;
+                Ok(())
             }
+        };
+    };
+
+    let cas = current.cas.unwrap_or_default();
+    match known_cas.get(key).await {
+        // Not written since this process started, e.g. after a restart, so
+        // the value read is taken as the one to replace
+        None => {}
+        Some(known) if known != cas => {
+            // Remember it, so the next refresh, which starts after this, may replace it
+            known_cas.insert(key.to_string(), cas).await;
+            conflict();
+            return Ok(());
         }
-        client
+        Some(_) => {}
+    }
+
+    let ttl_secs = respect_ttl(job.ttl_secs, current.ttl_remaining);
+    let compare = format!("C{}", cas);
+    let result = match &job.op {
+        WriteOp::Set(value) => {
+            let ttl = format!("T{}", ttl_secs);
+            let mut flags = vec![ttl.as_str(), compare.as_str(), "c"];
+            let item_flags = item_flags(value);
+            flags.extend(item_flags.as_deref());
+            client
+                .meta_set(key, value.data.as_slice(), false, None, Some(&flags))
+                .await
+                .map(|stored| stored.and_then(|s| s.cas))
+        }
+        WriteOp::Touch(_) => {
+            // Touching leaves the CAS as it is
+            let ttl = format!("T{}", ttl_secs);
+            client
+                .meta_get(key, false, None, Some(&[ttl.as_str()]))
+                .await
+                .map(|_| Some(cas))
+        }
+        WriteOp::Delete => client
+            .meta_delete(key, false, None, Some(&[compare.as_str()]))
+            .await
+            .map(|_| None),
+    };
+    match result {
+        Ok(cas) => {
+            remember_cas(known_cas, key, cas).await;
+            Ok(())
+        }
+        Err(MemcacheError::Protocol(Status::Exists | Status::NotFound)) => {
+            known_cas.invalidate(key).await;
+            conflict();
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+// The `F` flag of a meta set, left out for plain values as memcached defaults to 0
+fn item_flags(value: &Encoded) -> Option<String> {
+    (value.flags != 0).then(|| format!("F{}", value.flags))
+}
+
+async fn remember_cas(known_cas: &KnownCas, key: &str, cas: Option<u64>) {
+    match cas {
+        Some(cas) => known_cas.insert(key.to_string(), cas).await,
+        None => known_cas.invalidate(key).await,
+    }
+}
+
+// TTL for a write that should not shorten the `ttl_remaining` the target
+// reports, where -1 means the value never expires
+fn respect_ttl(ttl_secs: u32, ttl_remaining: Option<i64>) -> u32 {
+    match ttl_remaining {
+        Some(-1) => 0,
+        Some(remaining) if ttl_secs != 0 && remaining > ttl_secs as i64 => remaining as u32,
+        _ => ttl_secs,
+    }
+}
+
+// Sends the writes from one queue over one connection
+struct Worker {
+    dsn: String,
+    connection: Option<Connection>,
+    connected: Arc<AtomicUsize>,
+    failures: Arc<AtomicUsize>,
+    max_retries: u32,
+    retry_backoff: Duration,
+    // CAS of the value last written or seen for each key, with `with_cas`
+    known_cas: Option<KnownCas>,
+}
+
+impl Worker {
+    async fn run(mut self, queue: Arc<WriteQueue>) {
+        info!("Writer started");
+        self.connect().await;
+        while let Some(job) = queue.pop().await {
+            metrics()
+                .writer_queue_wait
+                .observe(job.queued_at.elapsed().as_secs_f64());
+            let span = job.span.clone();
+            self.write(job).instrument(span).await;
+        }
+        self.disconnect();
+        info!("Writer terminated");
+    }
+
+    async fn connect(&mut self) {
+        match tokio::time::timeout(TARGET_TIMEOUT, Connection::open(&self.dsn)).await {
+            Ok(Ok(connection)) => {
+                debug!(dsn = self.dsn, "Connected to target");
+                self.connection = Some(connection);
+                self.connected.fetch_add(1, Ordering::Relaxed);
+                metrics()
+                    .target_connections
+                    .with_label_values(&[&self.dsn])
+                    .inc();
+            }
+            Ok(Err(e)) => warn!(dsn = self.dsn, error = e, "Failed to connect to target"),
+            Err(_) => warn!(dsn = self.dsn, "Timed out connecting to target"),
+        }
+    }
+
+    fn disconnect(&mut self) {
+        if self.connection.take().is_some() {
+            self.connected.fetch_sub(1, Ordering::Relaxed);
+            metrics()
+                .target_connections
+                .with_label_values(&[&self.dsn])
+                .dec();
+        }
+    }
+
+    async fn write(&mut self, job: WriteJob) {
+        let op = job.op.name();
+        for attempt in 0..=self.max_retries {
+            if attempt > 0 {
+                metrics().writer_retries.with_label_values(&[op]).inc();
+                tokio::time::sleep(self.retry_backoff * 2u32.pow(attempt - 1)).await;
+            }
+            if self.connection.is_none() {
+                self.connect().await;
+            }
+            let Some(connection) = self.connection.as_mut() else {
+                continue;
+            };
+
+            let started = Instant::now();
+            let request = connection.apply(&job, self.known_cas.as_ref());
+            let result = tokio::time::timeout(TARGET_TIMEOUT, request).await;
+            metrics()
+                .writer_write_duration
+                .with_label_values(&[op])
+                .observe(started.elapsed().as_secs_f64());
+
+            match result {
+                Ok(Ok(())) => {
+                    debug!(key = job.key.as_str(), "Wrote");
+                    self.failures.store(0, Ordering::Relaxed);
+                    return;
+                }
+                Ok(Err(WriteFailure::Rejected(reason))) => {
+                    warn!(
+                        key = job.key.as_str(),
+                        reason = reason,
+                        attempt = attempt,
+                        "Write rejected"
+                    );
+                }
+                Ok(Err(WriteFailure::Broken(e))) => {
+                    warn!(
+                        key = job.key.as_str(),
+                        error = e,
+                        attempt = attempt,
+                        "Write failed"
+                    );
+                    // The connection may be out of sync, start over
+                    self.disconnect();
+                }
+                Err(_) => {
+                    warn!(key = job.key.as_str(), attempt = attempt, "Write timed out");
+                    self.disconnect();
+                }
+            }
+        }
+
+        error!(key = job.key.as_str(), op = op, "Giving up on write");
+        metrics().writer_failures.with_label_values(&[op]).inc();
+        self.failures.fetch_add(1, Ordering::Relaxed);
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
-    use std::sync::Arc;
-    use std::sync::atomic::AtomicUsize;
-    use std::time::Duration as StdDuration;
-    use tokio::time::Duration;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
 
-    #[test]
-    fn test_write_job_creation() {
-        let job = WriteJob {
-            key: "test_key".to_string(),
-            value: Some("test_value".to_string()),
+    fn job_with(key: &str, op: WriteOp) -> WriteJob {
+        WriteJob {
+            key: key.to_string(),
+            op,
             ttl_secs: 300,
-        };
+            queued_at: Instant::now(),
+            span: Span::none(),
+        }
+    }
 
-        assert_eq!(job.key, "test_key");
-        assert_eq!(job.value, Some("test_value".to_string()));
-        assert_eq!(job.ttl_secs, 300);
+    fn job(key: &str, value: &str) -> WriteJob {
+        job_with(key, WriteOp::Set(value.into()))
     }
 
     #[test]
-    fn test_write_job_clone() {
-        let job = WriteJob {
-            key: "test_key".to_string(),
-            value: Some("test_value".to_string()),
-            ttl_secs: 300,
-        };
-
-        let cloned_job = job.clone();
-        assert_eq!(job.key, cloned_job.key);
-        assert_eq!(job.value, cloned_job.value);
-        assert_eq!(job.ttl_secs, cloned_job.ttl_secs);
+    fn test_write_op_name() {
+        assert_eq!(WriteOp::Set("v".into()).name(), "set");
+        assert_eq!(WriteOp::Touch("v".into()).name(), "touch");
+        assert_eq!(WriteOp::Delete.name(), "delete");
     }
 
     #[test]
-    #[should_panic(expected = "thread name may not contain interior null bytes")]
-    fn test_writer_new_with_invalid_thread_name() {
-        let invalid_name = "\0invalid";
-        let _writer = Writer::new(invalid_name);
+    fn test_coalesce() {
+        // A newer set replaces whatever is queued
+        let mut queued = job("key", "old");
+        queued.coalesce(job("key", "new"));
+        assert_eq!(queued.op, WriteOp::Set("new".into()));
+
+        // A touch keeps the queued set, with the newer TTL
+        let mut touch = job_with("key", WriteOp::Touch("new".into()));
+        touch.ttl_secs = 600;
+        queued.coalesce(touch);
+        assert_eq!(queued.op, WriteOp::Set("new".into()));
+        assert_eq!(queued.ttl_secs, 600);
+
+        let mut queued = job_with("key", WriteOp::Delete);
+        queued.coalesce(job_with("key", WriteOp::Touch("v".into())));
+        assert_eq!(queued.op, WriteOp::Delete);
     }
 
-    #[test]
-    fn test_writer_send_job() {
-        let writer = Writer::new("127.0.0.1:11211");
-        let result = writer.send(
-            "test_key",
-            Some("test_value".to_string()),
-            Duration::from_secs(300),
+    #[tokio::test]
+    async fn test_queue_coalesces_per_key() {
+        let queue = WriteQueue::new(4, QueueOverflow::DropNewest);
+        queue.push(job("a", "1")).await.unwrap();
+        queue.push(job("b", "1")).await.unwrap();
+        queue.push(job("a", "2")).await.unwrap();
+
+        let first = queue.pop().await.unwrap();
+        assert_eq!(
+            (first.key.as_str(), first.op),
+            ("a", WriteOp::Set("2".into()))
         );
-        assert!(result.is_ok());
-        writer.shutdown();
+        assert_eq!(queue.pop().await.unwrap().key, "b");
+        queue.close();
+        assert!(queue.pop().await.is_none());
     }
 
     #[test]
-    fn test_writer_send_with_zero_ttl() {
-        let writer = Writer::new("127.0.0.1:11211");
-        let result = writer.send(
-            "test_key",
-            Some("test_value".to_string()),
-            Duration::from_secs(0),
+    fn test_dsn() {
+        assert_eq!(dsn("memcache://127.0.0.1:11213"), "tcp://127.0.0.1:11213");
+        assert_eq!(
+            dsn("memcache://127.0.0.1:11213?timeout=10"),
+            "tcp://127.0.0.1:11213"
         );
-        assert!(result.is_ok());
-        writer.shutdown();
+        assert_eq!(dsn("tcp://127.0.0.1:11213"), "tcp://127.0.0.1:11213");
+        assert_eq!(dsn("unix:///tmp/mc.sock"), "unix:///tmp/mc.sock");
     }
 
-    #[test]
-    fn test_writer_send_with_large_ttl() {
-        let writer = Writer::new("127.0.0.1:11211");
-        let large_ttl = Duration::from_secs(u32::MAX as u64);
-        let result = writer.send("test_key", Some("test_value".to_string()), large_ttl);
-        assert!(result.is_ok());
-        writer.shutdown();
+    #[tokio::test]
+    async fn test_queue_drop_newest() {
+        let queue = WriteQueue::new(1, QueueOverflow::DropNewest);
+        queue.push(job("a", "1")).await.unwrap();
+        assert_eq!(queue.push(job("b", "2")).await, Err(WriteError::QueueFull));
+        assert_eq!(queue.pop().await.unwrap().key, "a");
     }
 
-    #[test]
-    fn test_writer_shutdown() {
-        let writer = Writer::new("127.0.0.1:11211");
+    #[tokio::test]
+    async fn test_queue_drop_oldest() {
+        let queue = WriteQueue::new(1, QueueOverflow::DropOldest);
+        queue.push(job("a", "1")).await.unwrap();
+        queue.push(job("b", "2")).await.unwrap();
+        assert_eq!(queue.pop().await.unwrap().key, "b");
+    }
 
-        let result = writer.send(
-            "test_key",
-            Some("test_value".to_string()),
-            Duration::from_secs(300),
-        );
-        assert!(result.is_ok());
+    #[tokio::test]
+    async fn test_queue_backpressure() {
+        let queue = Arc::new(WriteQueue::new(1, QueueOverflow::Backpressure));
+        queue.push(job("a", "1")).await.unwrap();
 
-        writer.shutdown();
+        let pushing = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(job("b", "2")).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!pushing.is_finished());
+
+        assert_eq!(queue.pop().await.unwrap().key, "a");
+        pushing.await.unwrap().unwrap();
+        assert_eq!(queue.pop().await.unwrap().key, "b");
     }
 
-    #[test]
-    fn test_writer_shutdown_processes_remaining_jobs() {
-        let _counter = Arc::new(AtomicUsize::new(0));
-        let writer = Writer::new("127.0.0.1:11211");
-
-        for i in 0..10 {
-            let _ = writer.send(
-                &format!("key_{}", i),
-                Some(format!("value_{}", i)),
-                Duration::from_secs(300),
-            );
-        }
+    #[tokio::test]
+    async fn test_queue_close() {
+        let queue = WriteQueue::new(4, QueueOverflow::DropOldest);
+        queue.push(job("a", "1")).await.unwrap();
+        queue.close();
 
-        std::thread::sleep(StdDuration::from_millis(100));
-        writer.shutdown();
+        // Queued writes are still drained
+        assert_eq!(queue.pop().await.unwrap().key, "a");
+        assert!(queue.pop().await.is_none());
+        assert_eq!(queue.push(job("b", "2")).await, Err(WriteError::Closed));
     }
 
-    #[test]
-    fn test_writer_thread_name() {
-        let writer = Writer::new("127.0.0.1:11211");
+    // Accepts one connection and answers each command with the given replies in turn
+    pub(crate) async fn fake_memcached(
+        replies: Vec<&'static str>,
+    ) -> (String, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("tcp://{}", listener.local_addr().unwrap());
+        let handle = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut reader = BufReader::new(socket);
+            let mut commands = Vec::new();
+            for reply in replies {
+                let mut line = String::new();
+                if reader.read_line(&mut line).await.unwrap() == 0 {
+                    break;
+                }
+                // Skip the data block of a set or meta set
+                let words: Vec<&str> = line.split_whitespace().collect();
+                let bytes = match words.first() {
+                    Some(&"set") => words.get(4),
+                    Some(&"ms") => words.get(2),
+                    _ => None,
+                };
+                if let Some(bytes) = bytes {
+                    let mut data = vec![0; bytes.parse::<usize>().unwrap() + 2];
+                    reader.read_exact(&mut data).await.unwrap();
+                }
+                commands.push(line.trim_end().to_string());
+                reader.get_mut().write_all(reply.as_bytes()).await.unwrap();
+            }
+            commands
+        });
+        (address, handle)
+    }
 
-        let thread_name = writer.handle.thread().name().unwrap_or("");
-        assert!(thread_name.starts_with("writer/"));
-        assert!(thread_name.contains("127.0.0.1:11211"));
+    #[tokio::test]
+    async fn test_writer_sends_set_and_delete() {
+        let (address, server) = fake_memcached(vec!["STORED\r\n", "NOT_FOUND\r\n"]).await;
+        let writer = Writer::builder(&address).with_pool_size(1).build();
 
-        writer.shutdown();
+        writer
+            .set("key", "value".into(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        // Let the set go out, so the delete is not folded into it
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        writer.delete("key").await.unwrap();
+        writer.shutdown().await;
+
+        let commands = server.await.unwrap();
+        assert_eq!(commands, vec!["set key 0 60 5", "delete key"]);
     }
 
-    #[test]
-    fn test_writer_client_connection_timeout() {
-        let client_result = Writer::client("127.0.0.1:11211");
-        // Just test that the function can be called - the result depends on whether memcached is running
-        let _ = client_result;
+    #[tokio::test]
+    async fn test_writer_touch() {
+        // The first touch finds the key, the second has to set it again
+        let (address, server) = fake_memcached(vec!["HD kkey\r\n", "EN\r\n", "STORED\r\n"]).await;
+        let writer = Writer::builder(&address).with_pool_size(1).build();
+
+        writer
+            .touch("key", "value".into(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        writer
+            .touch("key", "value".into(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        writer.shutdown().await;
+
+        let commands = server.await.unwrap();
+        assert_eq!(
+            commands,
+            vec!["mg key T60 k", "mg key T60 k", "set key 0 60 5"]
+        );
     }
 
-    #[test]
-    fn test_writer_client_invalid_address() {
-        let client_result = Writer::client("invalid_address");
-        assert!(client_result.is_err());
+    #[tokio::test]
+    async fn test_writer_sends_flags() {
+        let compressed = Encoded {
+            data: b"zzz".to_vec(),
+            flags: crate::compression::FLAG_COMPRESSED,
+        };
+
+        let (address, server) = fake_memcached(vec!["STORED\r\n"]).await;
+        let writer = Writer::builder(&address).with_pool_size(1).build();
+        writer
+            .set("key", compressed.clone(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        writer.shutdown().await;
+        assert_eq!(server.await.unwrap(), vec!["set key 2 60 3"]);
+
+        let (address, server) = fake_memcached(vec!["EN\r\n", "HD c1\r\n"]).await;
+        let writer = Writer::builder(&address)
+            .with_pool_size(1)
+            .with_cas(true)
+            .build();
+        writer
+            .set("key", compressed, Duration::from_secs(60))
+            .await
+            .unwrap();
+        writer.shutdown().await;
+        assert_eq!(
+            server.await.unwrap(),
+            vec!["mg key c t", "ms key 3 T60 ME c F2"]
+        );
     }
 
     #[test]
-    fn test_writer_send_after_shutdown() {
-        let writer = Writer::new("127.0.0.1:11211");
-        let sender = writer.sender.clone();
-        writer.shutdown();
+    fn test_respect_ttl() {
+        assert_eq!(respect_ttl(60, None), 60);
+        assert_eq!(respect_ttl(60, Some(30)), 60);
+        assert_eq!(respect_ttl(60, Some(300)), 300);
+        assert_eq!(respect_ttl(60, Some(-1)), 0);
+        assert_eq!(respect_ttl(0, Some(300)), 0);
+    }
 
-        let job = WriteJob {
-            key: "test_key".to_string(),
-            value: Some("test_value".to_string()),
-            ttl_secs: 300,
-        };
-        let result = sender.send(job);
-        assert!(result.is_err());
+    #[tokio::test]
+    async fn test_guarded_writes() {
+        let (address, server) = fake_memcached(vec![
+            // Missing, so it is added
+            "EN\r\n",
+            "HD c7\r\n",
+            // Unchanged since, so it is replaced keeping the longer TTL
+            "HD c7 t600\r\n",
+            "HD c8\r\n",
+            // Changed by someone else, so it is left alone
+            "HD c9 t60\r\n",
+        ])
+        .await;
+        let writer = Writer::builder(&address)
+            .with_pool_size(1)
+            .with_cas(true)
+            .build();
+        let conflicts = || metrics().writer_conflicts.with_label_values(&["set"]).get();
+        let before = conflicts();
+
+        for value in ["a", "b", "c"] {
+            writer
+                .set("key", value.into(), Duration::from_secs(60))
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        writer.shutdown().await;
+
+        assert_eq!(
+            server.await.unwrap(),
+            vec![
+                "mg key c t",
+                "ms key 1 T60 ME c",
+                "mg key c t",
+                "ms key 1 T600 C7 c",
+                "mg key c t",
+            ]
+        );
+        assert!(conflicts() > before);
     }
 
-    #[test]
-    fn test_ttl_conversion() {
-        let writer = Writer::new("127.0.0.1:11211");
+    #[tokio::test]
+    async fn test_guarded_write_to_existing_key() {
+        // The key is already there, e.g. written before a restart
+        let (address, server) = fake_memcached(vec!["HD c5 t60\r\n", "HD c6\r\n"]).await;
+        let writer = Writer::builder(&address)
+            .with_pool_size(1)
+            .with_cas(true)
+            .build();
+
+        writer
+            .set("key", "a".into(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        writer.shutdown().await;
+
+        assert_eq!(
+            server.await.unwrap(),
+            vec!["mg key c t", "ms key 1 T60 C5 c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_writer_retries_failed_set() {
+        let (address, server) = fake_memcached(vec!["NOT_STORED\r\n", "STORED\r\n"]).await;
+        let writer = Writer::builder(&address)
+            .with_pool_size(1)
+            .with_retries(2, Duration::from_millis(1))
+            .build();
+        let retries = metrics().writer_retries.with_label_values(&["set"]).get();
 
-        let ttl_millis = Duration::from_millis(5500);
-        let result = writer.send("test_key", Some("test_value".to_string()), ttl_millis);
+        writer
+            .set("key", "value".into(), Duration::from_secs(60))
+            .await
+            .unwrap();
+        writer.shutdown().await;
+
+        assert_eq!(server.await.unwrap().len(), 2);
+        assert!(metrics().writer_retries.with_label_values(&["set"]).get() > retries);
+    }
+
+    #[tokio::test]
+    async fn test_writer_not_connected() {
+        let writer = Writer::builder("tcp://127.0.0.1:1")
+            .with_retries(0, Duration::ZERO)
+            .build();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!writer.is_connected());
+        assert_eq!(writer.address(), "tcp://127.0.0.1:1");
+
+        // Writes are still accepted, and dropped after the retries
+        let result = writer
+            .set("key", "value".into(), Duration::from_secs(60))
+            .await;
         assert!(result.is_ok());
+        writer.shutdown().await;
+        assert_eq!(writer.consecutive_failures(), 1);
+    }
 
-        writer.shutdown();
+    #[tokio::test]
+    async fn test_writer_send_after_shutdown() {
+        let writer = Writer::new("tcp://127.0.0.1:1");
+        let queue = writer.queues[0].clone();
+        writer.shutdown().await;
+        assert_eq!(
+            queue.push(job("key", "value")).await,
+            Err(WriteError::Closed)
+        );
     }
 
-    #[test]
-    fn test_writer_with_string_type() {
-        let string_writer = Writer::new("127.0.0.1:11211");
-        let string_result =
-            string_writer.send("key1", Some("value1".to_string()), Duration::from_secs(300));
-        assert!(string_result.is_ok());
-        string_writer.shutdown();
+    #[tokio::test]
+    async fn test_writes_to_a_key_use_one_queue() {
+        let writer = Writer::builder("tcp://127.0.0.1:1")
+            .with_pool_size(4)
+            .build();
+        let first = writer.queue("some/key") as *const WriteQueue;
+        let second = writer.queue("some/key") as *const WriteQueue;
+        assert_eq!(first, second);
+        writer.shutdown().await;
     }
 }
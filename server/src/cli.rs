@@ -0,0 +1,174 @@
+//! Subcommands that load the configuration without serving anything, to debug
+//! it before deploying it.
+
+use crate::reload::Loaded;
+use anyhow::{Result, anyhow};
+use platypus::{Request, Router, router::Rule};
+use std::fmt::Write;
+use std::sync::Arc;
+
+// Routes `key` the way the server does, or fails if nothing matches it
+fn route<'a>(loaded: &Loaded, router: &'a Router, key: &str) -> Result<(Request, &'a Rule)> {
+    let namespace = loaded.config.server.to_namespace();
+    let key = namespace.route_key(key);
+    router
+        .rule(key)
+        .ok_or_else(|| anyhow!("No route matches '{}'", key))
+}
+
+/// The rule `key` matches, its captures and its source
+pub fn explain_route(loaded: &Loaded, router: &Router, key: &str) -> Result<String> {
+    let (request, rule) = route(loaded, router, key)?;
+    let mut out = String::new();
+    writeln!(out, "route:   {}", rule.pattern())?;
+    writeln!(out, "source:  {}", rule.source())?;
+
+    let mut captures: Vec<(&String, &String)> = request
+        .captures()
+        .iter()
+        .filter(|(name, _)| !name.starts_with('$'))
+        .collect();
+    captures.sort();
+    for (name, value) in captures {
+        writeln!(out, "capture: {} = {}", name, value)?;
+    }
+
+    if let Some(canonical) = rule.rewrite(&request) {
+        writeln!(out, "rewrite: {}", canonical)?;
+    }
+    let mut namespace = loaded.config.server.to_namespace();
+    if let Some(prefix) = rule.overrides().prefix() {
+        namespace = namespace.with_prefix(prefix);
+    }
+    writeln!(out, "target:  {}", namespace.target_key(&request))?;
+    if let Err(reason) = rule.validate(&request) {
+        writeln!(out, "invalid: {}", reason)?;
+    }
+    Ok(out)
+}
+
+/// Calls the source for `key` once, and shows its value and how long it is kept
+pub async fn fetch(loaded: &Loaded, router: &Router, key: &str) -> Result<String> {
+    let (request, rule) = route(loaded, router, key)?;
+    let sources = Arc::new(loaded.sources.clone());
+    let source = sources
+        .get(rule.source())
+        .ok_or_else(|| anyhow!("Source '{}' not found", rule.source()))?;
+    let request = request.with_sources(sources.clone());
+    rule.validate(&request)
+        .and_then(|_| source.validate(&request))
+        .map_err(|reason| anyhow!("Invalid key: {}", reason))?;
+
+    let response = rule.overrides().apply(source.call(&request).await);
+    let value = response
+        .value()
+        .ok_or_else(|| anyhow!("Source '{}' returned no value", rule.source()))?;
+    let mut out = String::new();
+    writeln!(out, "source:  {}", rule.source())?;
+    writeln!(
+        out,
+        "ttl:     {}",
+        humantime::format_duration(response.ttl())
+    )?;
+    writeln!(
+        out,
+        "expiry:  {}",
+        humantime::format_duration(response.expiry())
+    )?;
+    writeln!(out, "flags:   {}", rule.flags().unwrap_or(response.flags()))?;
+    writeln!(out)?;
+    writeln!(out, "{}", value)?;
+    Ok(out)
+}
+
+/// A summary of a configuration that loaded, with the routes that overlap
+pub fn check_config(loaded: &Loaded, router: &Router) -> String {
+    let mut out = String::new();
+    let rules = router.rules();
+    for overlap in router.overlaps() {
+        let verb = if overlap.shadowed {
+            "is never used, shadowed by"
+        } else {
+            "overlaps"
+        };
+        let _ = writeln!(
+            out,
+            "warning: route {} {} {}, e.g. for '{}'",
+            rules[overlap.later].pattern(),
+            verb,
+            rules[overlap.earlier].pattern(),
+            overlap.example
+        );
+    }
+    let _ = writeln!(
+        out,
+        "ok: {} routes, {} sources, {} pools",
+        rules.len(),
+        loaded.sources.len(),
+        loaded.pools.len()
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn load(name: &str) -> (Loaded, Router) {
+        let path =
+            std::env::temp_dir().join(format!("platypus_cli_{}_{}.toml", name, std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+[server]
+prefix = "app:"
+
+[routes.test]
+routes = [
+  { match = "^echo/(?<name>[^/]+)$", to = "echo", ttl = "5s" },
+  { match = "^echo/(?<name>.+)$", to = "echo" },
+]
+
+[source.echo]
+type = "echo"
+template = "hello {name}"
+"#,
+        )
+        .unwrap();
+        let loaded = Loaded::load(path.to_str().unwrap(), None).await.unwrap();
+        std::fs::remove_file(path).unwrap();
+        loaded
+    }
+
+    #[tokio::test]
+    async fn test_explain_route() {
+        let (loaded, router) = load("route").await;
+        let out = explain_route(&loaded, &router, "echo/world").unwrap();
+        assert_eq!(
+            out,
+            "route:   ^echo/(?<name>[^/]+)$\n\
+             source:  echo\n\
+             capture: name = world\n\
+             target:  app:echo/world\n"
+        );
+        assert!(explain_route(&loaded, &router, "other").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let (loaded, router) = load("fetch").await;
+        let out = fetch(&loaded, &router, "echo/world").await.unwrap();
+        assert!(out.contains("ttl:     5s\n"));
+        assert!(out.ends_with("\nhello world\n"));
+    }
+
+    #[tokio::test]
+    async fn test_check_config() {
+        let (loaded, router) = load("check").await;
+        assert_eq!(
+            check_config(&loaded, &router),
+            "warning: route ^echo/(?<name>.+)$ overlaps ^echo/(?<name>[^/]+)$, e.g. for 'echo/a'\n\
+             ok: 2 routes, 1 sources, 0 pools\n"
+        );
+    }
+}
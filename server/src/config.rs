@@ -1,6 +1,11 @@
 use humantime::parse_duration;
+use indexmap::IndexMap;
 use platypus::{
-    AwsSecretsManagerConnectionManager, AwsSecretsManagerPoolBuilder, Router, Source, source,
+    AwsSecretsManagerConnectionManager, AwsSecretsManagerPoolBuilder, Compression, Namespace,
+    Oversize, Router, Source, ValueLimit,
+    compression::Algorithm,
+    router::{CaptureType, Constraint, Fetch, Overrides, Rule},
+    source,
     source::{AwsSecretsManager, Echo, File, Http},
 };
 use r2d2::Pool;
@@ -10,8 +15,10 @@ use std::default::Default;
 use std::fmt;
 use std::sync::Arc;
 
+pub type Pools = HashMap<String, Arc<Pool<AwsSecretsManagerConnectionManager>>>;
+
 //- Merge ---------------------------------------------------------------------
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub enum MergeRuleArgsConfig {
     #[serde(rename = "inherit")]
     Inherit,
@@ -34,15 +41,29 @@ impl MergeRuleArgsConfig {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct MergeRuleConfig {
     pub key: Vec<String>,
     pub source: String,
     pub args: MergeRuleArgsConfig,
 }
 
+//- Switch --------------------------------------------------------------------
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SwitchCaseConfig {
+    pub capture: String,
+    pub values: Vec<String>,
+    pub to: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SwitchSplitConfig {
+    pub to: String,
+    pub weight: u32,
+}
+
 //- Pool ----------------------------------------------------------------------
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum PoolConfig {
     #[serde(rename = "aws_secrets_manager")]
@@ -88,7 +109,7 @@ impl PoolConfig {
 }
 
 //- Source --------------------------------------------------------------------
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum SourceConfig {
     #[serde(rename = "aws_secrets_manager")]
@@ -97,14 +118,26 @@ pub enum SourceConfig {
         pool: Option<String>,
         ttl: Option<String>,
         expiry: Option<String>,
+        flags: Option<u32>,
+        /// Reject keys missing a variable the templates use
+        #[serde(default)]
+        strict: bool,
     },
     Echo {
         template: String,
+        flags: Option<u32>,
+        /// Reject keys missing a variable the templates use
+        #[serde(default)]
+        strict: bool,
     },
     File {
         path: String,
         ttl: Option<String>,
         expiry: Option<String>,
+        flags: Option<u32>,
+        /// Reject keys missing a variable the templates use
+        #[serde(default)]
+        strict: bool,
     },
     Http {
         url: String,
@@ -113,26 +146,43 @@ pub enum SourceConfig {
         headers: Option<HashMap<String, String>>,
         ttl: Option<String>,
         expiry: Option<String>,
+        flags: Option<u32>,
+        /// Reject keys missing a variable the templates use
+        #[serde(default)]
+        strict: bool,
     },
     Merge {
         format: String,
         template: Vec<MergeRuleConfig>,
         ttl: Option<String>,
         expiry: Option<String>,
+        flags: Option<u32>,
+        /// Reject keys missing a variable the templates use
+        #[serde(default)]
+        strict: bool,
+    },
+    Switch {
+        #[serde(default)]
+        cases: Vec<SwitchCaseConfig>,
+        default: Option<String>,
+        split: Option<Vec<SwitchSplitConfig>>,
+    },
+    Shadow {
+        primary: String,
+        shadow: String,
     },
 }
 
 impl SourceConfig {
-    pub fn to_source(
-        &self,
-        pools: &HashMap<String, Arc<Pool<AwsSecretsManagerConnectionManager>>>,
-    ) -> anyhow::Result<Box<dyn Source>> {
+    pub fn to_source(&self, pools: &Pools) -> anyhow::Result<Box<dyn Source>> {
         match self {
             SourceConfig::AwsSecretsManager {
                 secret_id,
                 pool,
                 ttl,
                 expiry,
+                flags,
+                strict,
             } => {
                 // Get the pool - either specified or  create default
                 let pool_ref = if let Some(pool_name) = pool {
@@ -146,7 +196,9 @@ impl SourceConfig {
                     ));
                 };
 
-                let mut source = AwsSecretsManager::new(pool_ref).with_secret_id(secret_id);
+                let mut source = AwsSecretsManager::new(pool_ref)
+                    .with_secret_id(secret_id)
+                    .with_strict(*strict);
 
                 if let Some(ttl_str) = ttl {
                     let ttl_duration = parse_duration(ttl_str)?;
@@ -158,14 +210,33 @@ impl SourceConfig {
                     source = source.with_expiry(expiry_duration);
                 }
 
+                if let Some(flags) = flags {
+                    source = source.with_flags(*flags);
+                }
+
                 Ok(Box::new(source))
             }
-            SourceConfig::Echo { template } => {
-                let echo = Echo::new().with_template(template);
+            SourceConfig::Echo {
+                template,
+                flags,
+                strict,
+            } => {
+                let mut echo = Echo::new().with_template(template).with_strict(*strict);
+
+                if let Some(flags) = flags {
+                    echo = echo.with_flags(*flags);
+                }
+
                 Ok(Box::new(echo))
             }
-            SourceConfig::File { path, ttl, expiry } => {
-                let mut file = File::new(path);
+            SourceConfig::File {
+                path,
+                ttl,
+                expiry,
+                flags,
+                strict,
+            } => {
+                let mut file = File::new(path).with_strict(*strict);
 
                 if let Some(ttl_str) = ttl {
                     let ttl_duration = parse_duration(ttl_str)?;
@@ -177,6 +248,10 @@ impl SourceConfig {
                     file = file.with_expiry(expiry_duration);
                 }
 
+                if let Some(flags) = flags {
+                    file = file.with_flags(*flags);
+                }
+
                 Ok(Box::new(file))
             }
             SourceConfig::Http {
@@ -186,8 +261,10 @@ impl SourceConfig {
                 headers,
                 ttl,
                 expiry,
+                flags,
+                strict,
             } => {
-                let mut http = Http::new(url);
+                let mut http = Http::new(url).with_strict(*strict);
 
                 if let Some(method_str) = method {
                     let method = method_str
@@ -210,6 +287,10 @@ impl SourceConfig {
                     http = http.with_expiry(expiry_duration);
                 }
 
+                if let Some(flags) = flags {
+                    http = http.with_flags(*flags);
+                }
+
                 Ok(Box::new(http))
             }
             SourceConfig::Merge {
@@ -217,8 +298,12 @@ impl SourceConfig {
                 template,
                 ttl,
                 expiry,
+                flags,
+                strict,
             } => {
-                let mut merge = source::Merge::new().with_format(format);
+                let mut merge = source::Merge::new()
+                    .with_format(format)
+                    .with_strict(*strict);
 
                 for r in template.iter() {
                     merge = merge.with_rule(r.key.clone(), r.source.clone(), r.args.to_rule_args());
@@ -234,37 +319,264 @@ impl SourceConfig {
                     merge = merge.with_expiry(expiry_duration);
                 }
 
+                if let Some(flags) = flags {
+                    merge = merge.with_flags(*flags);
+                }
+
                 Ok(Box::new(merge))
             }
+            SourceConfig::Switch {
+                cases,
+                default,
+                split,
+            } => {
+                let mut switch = source::Switch::new();
+
+                for case in cases.iter() {
+                    switch = switch.with_case(&case.capture, case.values.clone(), &case.to);
+                }
+
+                match (default, split) {
+                    (Some(_), Some(_)) => {
+                        return Err(anyhow::anyhow!(
+                            "Switch source takes either default or split, not both"
+                        ));
+                    }
+                    (Some(default), None) => switch = switch.with_default(default),
+                    (None, Some(split)) => {
+                        for s in split.iter() {
+                            switch = switch.with_split(&s.to, s.weight);
+                        }
+                    }
+                    (None, None) => {}
+                }
+
+                Ok(Box::new(switch))
+            }
+            SourceConfig::Shadow { primary, shadow } => {
+                Ok(Box::new(source::Shadow::new(primary, shadow)))
+            }
         }
     }
 }
 
 //- Route ---------------------------------------------------------------------
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct RouteGroupConfig {
+    /// Groups with a higher priority are matched first; equal ones keep file order
+    #[serde(default)]
+    pub priority: i32,
+
     pub routes: Vec<RouteConfig>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct RouteConfig {
     #[serde(rename = "match")]
     pub pattern: String,
 
     #[serde(rename = "to")]
     pub source: String,
+
+    /// Priority of this route instead of its group's
+    pub priority: Option<i32>,
+
+    pub compress: Option<CompressConfig>,
+
+    /// Client flags for values from this route, instead of the source's
+    pub flags: Option<u32>,
+
+    /// TTL and expiry for values from this route, instead of the source's
+    pub ttl: Option<String>,
+    pub expiry: Option<String>,
+
+    /// TTL when the source returns no value
+    pub negative_ttl: Option<String>,
+
+    /// poll, the default, or once
+    pub fetch: Option<String>,
+
+    /// Prefix of target keys, instead of the one in [server]
+    pub prefix: Option<String>,
+
+    /// Template for the canonical key, so keys rewritten to the same one share a fetch
+    pub rewrite: Option<String>,
+
+    /// Constraints on captures, by capture name
+    pub captures: Option<HashMap<String, CaptureConfig>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CaptureConfig {
+    pub values: Option<Vec<String>>,
+
+    /// string or integer
+    #[serde(rename = "type")]
+    pub capture_type: Option<String>,
+
+    pub max_length: Option<usize>,
+
+    /// Characters allowed, as the inside of a regex character class
+    pub chars: Option<String>,
+}
+
+impl CaptureConfig {
+    pub fn to_constraint(&self) -> anyhow::Result<Constraint> {
+        let mut constraint = Constraint::new();
+
+        if let Some(values) = &self.values {
+            constraint = constraint.with_values(values.clone());
+        }
+
+        if let Some(capture_type) = &self.capture_type {
+            let capture_type = capture_type
+                .parse::<CaptureType>()
+                .map_err(|e| anyhow::anyhow!(e))?;
+            constraint = constraint.with_type(capture_type);
+        }
+
+        if let Some(max_length) = self.max_length {
+            constraint = constraint.with_max_length(max_length);
+        }
+
+        if let Some(chars) = &self.chars {
+            constraint = constraint.with_chars(chars)?;
+        }
+
+        Ok(constraint)
+    }
+}
+
+impl RouteConfig {
+    pub fn to_overrides(&self) -> anyhow::Result<Overrides> {
+        let mut overrides = Overrides::new();
+
+        if let Some(ttl_str) = &self.ttl {
+            overrides = overrides.with_ttl(parse_duration(ttl_str)?);
+        }
+
+        if let Some(expiry_str) = &self.expiry {
+            overrides = overrides.with_expiry(parse_duration(expiry_str)?);
+        }
+
+        if let Some(negative_ttl_str) = &self.negative_ttl {
+            overrides = overrides.with_negative_ttl(parse_duration(negative_ttl_str)?);
+        }
+
+        if let Some(fetch) = &self.fetch {
+            let fetch = fetch.parse::<Fetch>().map_err(|e| anyhow::anyhow!(e))?;
+            overrides = overrides.with_fetch(fetch);
+        }
+
+        if let Some(prefix) = &self.prefix {
+            overrides = overrides.with_prefix(prefix);
+        }
+
+        Ok(overrides)
+    }
+}
+
+//- Compression ---------------------------------------------------------------
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct CompressConfig {
+    /// zlib, gzip, zstd, or none to turn off compression set in [server]
+    pub algorithm: String,
+
+    pub min_size: Option<usize>,
+}
+
+impl CompressConfig {
+    pub fn to_compression(&self) -> anyhow::Result<Option<Compression>> {
+        if self.algorithm == "none" {
+            return Ok(None);
+        }
+        let algorithm = self
+            .algorithm
+            .parse::<Algorithm>()
+            .map_err(|e| anyhow::anyhow!(e))?;
+        let mut compression = Compression::new(algorithm);
+        if let Some(min_size) = self.min_size {
+            compression = compression.with_min_size(min_size);
+        }
+        Ok(Some(compression))
+    }
+}
+
+//- Server --------------------------------------------------------------------
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct TargetConfig {
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ServerSection {
+    pub target: Option<TargetConfig>,
+
+    pub prefix: Option<String>,
+
+    #[serde(default)]
+    pub strip_prefix: bool,
+
+    pub target_key: Option<String>,
+
+    /// Compression for routes that do not set their own
+    pub compress: Option<CompressConfig>,
+
+    /// Largest value written to the target, in bytes
+    pub max_value_size: Option<usize>,
+
+    /// What to write for larger values: reject, truncate or chunk
+    pub oversize: Option<String>,
+}
+
+impl ServerSection {
+    pub fn target_address(&self) -> Option<String> {
+        self.target
+            .as_ref()
+            .map(|target| format!("memcache://{}:{}", target.host, target.port))
+    }
+
+    pub fn to_value_limit(&self) -> anyhow::Result<Option<ValueLimit>> {
+        let Some(max_value_size) = self.max_value_size else {
+            return Ok(None);
+        };
+        let mut limit = ValueLimit::new(max_value_size);
+        if let Some(oversize) = &self.oversize {
+            let oversize = oversize
+                .parse::<Oversize>()
+                .map_err(|e| anyhow::anyhow!(e))?;
+            limit = limit.with_oversize(oversize);
+        }
+        Ok(Some(limit))
+    }
+
+    pub fn to_namespace(&self) -> Namespace {
+        let mut namespace = Namespace::new().with_strip_prefix(self.strip_prefix);
+        if let Some(prefix) = &self.prefix {
+            namespace = namespace.with_prefix(prefix);
+        }
+        if let Some(target_key) = &self.target_key {
+            namespace = namespace.with_target_key(target_key);
+        }
+        namespace
+    }
 }
 
 //- Service -------------------------------------------------------------------
 #[derive(Default, Clone, Deserialize, Serialize)]
 pub struct ServerConfig {
-    pub routes: HashMap<String, RouteGroupConfig>,
+    #[serde(default)]
+    pub server: ServerSection,
+
+    /// Route groups in file order
+    pub routes: IndexMap<String, RouteGroupConfig>,
 
     #[serde(rename = "source")]
-    pub source_configs: HashMap<String, SourceConfig>,
+    pub source_configs: IndexMap<String, SourceConfig>,
 
     #[serde(rename = "pool", default)]
-    pub pool_configs: HashMap<String, PoolConfig>,
+    pub pool_configs: IndexMap<String, PoolConfig>,
 }
 
 impl ServerConfig {
@@ -278,44 +590,53 @@ impl ServerConfig {
         Ok(config)
     }
 
-    pub async fn build_pools(
-        &self,
-    ) -> anyhow::Result<HashMap<String, Arc<Pool<AwsSecretsManagerConnectionManager>>>> {
-        let mut pools = HashMap::new();
-        for (name, pool_config) in self.pool_configs.iter() {
-            let pool = pool_config.to_pool().await?;
-            pools.insert(name.clone(), pool);
-        }
-        Ok(pools)
+    /// Routes in the order keys are matched against them: by descending
+    /// priority, then in file order
+    pub fn ordered_routes(&self) -> Vec<&RouteConfig> {
+        let mut routes: Vec<(i32, &RouteConfig)> = self
+            .routes
+            .values()
+            .flat_map(|group| {
+                group
+                    .routes
+                    .iter()
+                    .map(|r| (r.priority.unwrap_or(group.priority), r))
+            })
+            .collect();
+        // A stable sort keeps file order among equal priorities
+        routes.sort_by_key(|(priority, _)| std::cmp::Reverse(*priority));
+        routes.into_iter().map(|(_, r)| r).collect()
     }
 
     pub fn to_router(&self) -> anyhow::Result<Router> {
         let mut router = Router::new();
 
-        for (_name, route) in self.routes.iter() {
-            for r in route.routes.iter() {
-                router = router.route(&r.pattern, &r.source);
+        for r in self.ordered_routes() {
+            let compression = match r.compress.as_ref().or(self.server.compress.as_ref()) {
+                Some(compress) => compress.to_compression()?,
+                None => None,
+            };
+            let mut rule = Rule::new(&r.pattern, &r.source)?
+                .with_compression(compression)
+                .with_flags(r.flags)
+                .with_overrides(r.to_overrides()?);
+            if let Some(rewrite) = &r.rewrite {
+                rule = rule.with_rewrite(rewrite);
+            }
+            for (name, capture) in r.captures.iter().flatten() {
+                rule = rule.with_constraint(name, capture.to_constraint()?);
             }
+            router = router.with_rule(rule);
         }
 
         Ok(router)
     }
-
-    pub fn to_sources(
-        &self,
-        pools: &HashMap<String, Arc<Pool<AwsSecretsManagerConnectionManager>>>,
-    ) -> anyhow::Result<HashMap<String, Arc<Box<dyn Source>>>> {
-        let mut sources = HashMap::new();
-        for (name, config) in self.source_configs.iter() {
-            sources.insert(name.clone(), Arc::new(config.to_source(pools)?));
-        }
-        Ok(sources)
-    }
 }
 
 impl fmt::Debug for ServerConfig {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Config")
+            .field("server", &self.server)
             .field("routes", &self.routes)
             .field("source", &self.source_configs)
             .finish()
@@ -1,17 +1,42 @@
 use anyhow::{Result, anyhow};
-use clap::Parser;
-use platypus::{MonitorTasks, Server, Service};
+use clap::{Parser, Subcommand};
+use opentelemetry::trace::TracerProvider;
+use platypus::{
+    AdminServer, Distribution, MonitorTasks, QueueOverflow, Server, Service, Stats, TargetSet,
+    Writer, handoff, metrics, server::OverflowPolicy,
+};
+use std::sync::Arc;
 use std::time::Duration;
 use tower::ServiceBuilder;
 use tracing::info;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod cli;
 mod config;
-use config::ServerConfig;
+mod reload;
+mod telemetry;
+mod validate;
+use reload::Loaded;
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the route, captures and source a key matches
+    Route { key: String },
+
+    /// Fetch the value for a key once, and print it with its TTL and expiry
+    Fetch { key: String },
+
+    /// Load the configuration, report overlapping routes and exit
+    CheckConfig,
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Run a one-off command against the configuration instead of the server
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Address to bind to (TCP address like "127.0.0.1:11212")
     #[arg(short, long, conflicts_with = "unix_socket")]
     bind: Option<String>,
@@ -20,21 +45,100 @@ struct Args {
     #[arg(short, long, conflicts_with = "bind")]
     unix_socket: Option<String>,
 
-    /// Target memcached server
-    #[arg(short, long, default_value = "memcache://127.0.0.1:11213")]
-    target: String,
+    /// Accept on an inherited listening socket instead of binding
+    #[arg(long, conflicts_with_all = ["bind", "unix_socket", "upgrade_from"])]
+    listen_fd: Option<i32>,
+
+    /// Unix socket where a new platypus process can ask to take over the listener
+    #[arg(long)]
+    upgrade_socket: Option<String>,
+
+    /// Take over the listener and monitored keys of the platypus at this upgrade socket
+    #[arg(long, conflicts_with_all = ["bind", "unix_socket"])]
+    upgrade_from: Option<String>,
+
+    /// Maximum time to wait for connections to finish when shutting down (e.g. "30s")
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "30s")]
+    drain_timeout: Duration,
+
+    /// Target memcached server. Repeat for a pool of servers.
+    /// Defaults to `[server] target` in the config, then memcache://127.0.0.1:11213.
+    #[arg(short, long)]
+    target: Vec<String>,
+
+    /// How keys are spread over multiple targets: hash (like memcached's proxy) or replicate
+    #[arg(long, default_value = "hash")]
+    target_distribution: String,
+
+    /// Number of connections to each target
+    #[arg(long, default_value = "2")]
+    target_connections: usize,
+
+    /// Maximum writes waiting to be sent to the target
+    #[arg(long, default_value = "10000")]
+    write_queue_capacity: usize,
+
+    /// What to do when the write queue is full: drop-oldest, drop-newest or backpressure
+    #[arg(long, default_value = "drop-oldest")]
+    write_queue_overflow: String,
+
+    /// Times to retry a failed write to the target
+    #[arg(long, default_value = "3")]
+    write_retries: u32,
+
+    /// Guard writes to memcached targets with CAS, so a slow refresh never overwrites a newer value
+    #[arg(long)]
+    write_cas: bool,
 
     /// Maximum cache size in bytes (default: 10MB)
     #[arg(long, default_value = "10485760")]
     cache_max_bytes: u64,
 
+    /// Maximum number of concurrent client connections (default: unlimited)
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// What to do when max connections is reached: reject or backpressure
+    #[arg(long, default_value = "reject")]
+    connection_overflow: String,
+
+    /// Close connections that send no command for this long (e.g. "5m")
+    #[arg(long, value_parser = humantime::parse_duration)]
+    idle_timeout: Option<Duration>,
+
+    /// Close connections that take longer than this to send a command (e.g. "10s")
+    #[arg(long, value_parser = humantime::parse_duration)]
+    read_timeout: Option<Duration>,
+
+    /// Address for the HTTP admin endpoint (e.g. "127.0.0.1:9090"). Disabled if not set.
+    #[arg(long)]
+    admin_bind: Option<String>,
+
+    /// Include monitored values in the admin /tasks listing instead of redacting them
+    #[arg(long)]
+    admin_show_values: bool,
+
+    /// Address for a dedicated Prometheus metrics endpoint (e.g. "0.0.0.0:9091").
+    /// Metrics are also served on /metrics of the admin endpoint.
+    #[arg(long)]
+    metrics_bind: Option<String>,
+
+    /// Export tracing spans over OTLP/HTTP to this endpoint (e.g. "http://localhost:4318/v1/traces")
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+
     /// Log format: json or text
     #[arg(long, default_value = "text")]
     log_format: String,
 
     /// Configuration file path
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     config: Option<String>,
+
+    /// Also reload the configuration when the file changes, checking at this interval (e.g. "5s").
+    /// The configuration is always reloaded on SIGHUP.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    watch_config: Option<Duration>,
 }
 
 #[tokio::main]
@@ -44,13 +148,33 @@ async fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    let env_filter =
-        tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "debug".into());
+    // Taken before any other thread runs, as this also clears the variables
+    let mut inherited = handoff::listen_fds_from_env()?;
+
+    // Commands print their own output, so only warnings are logged by default
+    let default_filter = if args.command.is_some() {
+        "warn"
+    } else {
+        "debug"
+    };
+    let env_filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| default_filter.into());
+
+    let tracer_provider = match &args.otlp_endpoint {
+        Some(endpoint) => Some(telemetry::tracer_provider(endpoint)?),
+        None => None,
+    };
+    let otel_layer = || {
+        tracer_provider
+            .as_ref()
+            .map(|provider| tracing_opentelemetry::layer().with_tracer(provider.tracer("platypus")))
+    };
 
     match args.log_format.as_str() {
         "json" => {
             tracing_subscriber::registry()
                 .with(env_filter)
+                .with(otel_layer())
                 .with(
                     tracing_subscriber::fmt::layer()
                         .with_file(false)
@@ -60,16 +184,14 @@ async fn main() -> Result<()> {
                         .with_thread_names(true)
                         .json()
                         .flatten_event(true)
-                        .with_span_events(
-                            tracing_subscriber::fmt::format::FmtSpan::ENTER
-                                | tracing_subscriber::fmt::format::FmtSpan::CLOSE,
-                        ),
+                        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE),
                 )
                 .init();
         }
         "text" => {
             tracing_subscriber::registry()
                 .with(env_filter)
+                .with(otel_layer())
                 .with(
                     tracing_subscriber::fmt::layer()
                         .with_file(false)
@@ -77,10 +199,7 @@ async fn main() -> Result<()> {
                         .with_target(true)
                         .with_thread_ids(true)
                         .with_thread_names(true)
-                        .with_span_events(
-                            tracing_subscriber::fmt::format::FmtSpan::ENTER
-                                | tracing_subscriber::fmt::format::FmtSpan::CLOSE,
-                        ),
+                        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE),
                 )
                 .init();
         }
@@ -97,50 +216,199 @@ async fn main() -> Result<()> {
     let _guard = span.enter();
 
     // Load configuration if provided
-    let config = if let Some(config_path) = &args.config {
-        ServerConfig::from_file(config_path)?
-    } else {
+    let Some(config_path) = args.config.clone() else {
         return Err(anyhow!("No config file specified"));
     };
+    let (loaded, router) = Loaded::load(&config_path, None).await?;
+
+    if let Some(command) = &args.command {
+        let out = match command {
+            Command::Route { key } => cli::explain_route(&loaded, &router, key)?,
+            Command::Fetch { key } => cli::fetch(&loaded, &router, key).await?,
+            Command::CheckConfig => cli::check_config(&loaded, &router),
+        };
+        print!("{}", out);
+        return Ok(());
+    }
 
-    info!(config = ?config, "Server starting");
+    info!(config = ?loaded.config, "Server starting");
+    info!(
+        pool_count = loaded.pools.len(),
+        "Connection pools initialized"
+    );
 
-    // Build connection pools
-    let pools = config.build_pools().await?;
-    info!(pool_count = pools.len(), "Connection pools initialized");
+    let overflow_policy = match args.connection_overflow.as_str() {
+        "reject" => OverflowPolicy::Reject,
+        "backpressure" => OverflowPolicy::Backpressure,
+        _ => {
+            return Err(anyhow!(
+                "Invalid connection overflow '{}'. Valid options are 'reject' or 'backpressure'",
+                args.connection_overflow
+            ));
+        }
+    };
 
-    // Determine target from CLI args
-    let target = args.target.clone();
+    let write_queue_overflow = match args.write_queue_overflow.as_str() {
+        "drop-oldest" => QueueOverflow::DropOldest,
+        "drop-newest" => QueueOverflow::DropNewest,
+        "backpressure" => QueueOverflow::Backpressure,
+        _ => {
+            return Err(anyhow!(
+                "Invalid write queue overflow '{}'. Valid options are 'drop-oldest', 'drop-newest' or 'backpressure'",
+                args.write_queue_overflow
+            ));
+        }
+    };
+    let distribution = match args.target_distribution.as_str() {
+        "hash" => Distribution::Hash,
+        "replicate" => Distribution::Replicate,
+        _ => {
+            return Err(anyhow!(
+                "Invalid target distribution '{}'. Valid options are 'hash' or 'replicate'",
+                args.target_distribution
+            ));
+        }
+    };
+    let mut target_addresses = args.target.clone();
+    if target_addresses.is_empty() {
+        target_addresses.push(
+            loaded
+                .config
+                .server
+                .target_address()
+                .unwrap_or_else(|| "memcache://127.0.0.1:11213".to_string()),
+        );
+    }
+    loaded.config.validate_targets(&target_addresses)?;
+    validate::validate_write_cas(args.write_cas, &target_addresses)?;
+    let mut targets = TargetSet::new(distribution);
+    for target in &target_addresses {
+        let writer = Writer::builder(target)
+            .with_pool_size(args.target_connections)
+            .with_queue_capacity(args.write_queue_capacity)
+            .with_overflow(write_queue_overflow)
+            .with_retries(args.write_retries, Duration::from_millis(50))
+            .with_cas(args.write_cas)
+            .build();
+        targets = targets.with_node(writer);
+    }
+    if let Some(limit) = loaded.config.server.to_value_limit()? {
+        targets = targets.with_value_limit(limit);
+    }
 
     let monitor_tasks = MonitorTasks::with_max_bytes(args.cache_max_bytes);
     let monitor_tasks_for_tick = monitor_tasks.clone();
+    let stats = Arc::new(Stats::new());
+    stats.register(metrics::registry())?;
 
     let handler = Service::new()
         .version(env!("CARGO_PKG_VERSION"))
         .with_monitor_tasks(monitor_tasks)
-        .with_router(config.to_router()?)
-        .with_sources(config.to_sources(&pools)?)
-        .with_target(target.as_str());
+        .with_stats(stats.clone())
+        .with_router(router)
+        .with_sources(loaded.sources.clone())
+        .with_targets(targets)
+        .with_namespace(loaded.config.server.to_namespace());
 
     // Keep a reference to the original service for shutdown
     let handler_for_shutdown = handler.clone();
 
+    tokio::spawn(reload::reload_on_change(
+        config_path,
+        handler.clone(),
+        loaded,
+        target_addresses,
+        args.watch_config,
+    ));
+
+    if let Some(admin_bind) = &args.admin_bind {
+        let mut admin = AdminServer::bind(admin_bind);
+        admin.with_values(args.admin_show_values);
+        let admin_service = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = admin.serve(admin_service).await {
+                tracing::error!(error = %e, "Admin server failed");
+            }
+        });
+    }
+
+    if let Some(metrics_bind) = &args.metrics_bind {
+        let mut metrics_server = AdminServer::bind(metrics_bind);
+        metrics_server.metrics_only();
+        let metrics_service = handler.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics_server.serve(metrics_service).await {
+                tracing::error!(error = %e, "Metrics server failed");
+            }
+        });
+    }
+
     let service = ServiceBuilder::new()
         .timeout(Duration::from_secs(5))
         .service(handler);
 
-    let mut server = match (args.bind, args.unix_socket) {
-        (Some(bind_addr), None) => Server::bind(&bind_addr),
-        (None, Some(unix_path)) => Server::bind_unix(&unix_path),
-        (None, None) => Server::bind("127.0.0.1:11212"), // Default TCP binding
-        (Some(_), Some(_)) => return Err(anyhow!("Cannot specify both --bind and --unix-socket")),
+    if let Some(fd) = args.listen_fd {
+        inherited.push(handoff::inherit_fd(fd)?);
+    }
+    // Checked before taking over, as the old process stops serving once asked
+    let taken_over = usize::from(args.upgrade_from.is_some());
+    if inherited.len() + taken_over > 1 {
+        return Err(anyhow!(
+            "Expected one inherited listener, got {}",
+            inherited.len() + taken_over
+        ));
+    }
+    if inherited.len() + taken_over > 0 && (args.bind.is_some() || args.unix_socket.is_some()) {
+        return Err(anyhow!("Cannot bind while also inheriting a listener"));
+    }
+    if let Some(upgrade_from) = &args.upgrade_from {
+        let handoff = handoff::request(upgrade_from).await?;
+        info!(keys = handoff.keys.len(), "Took over listener");
+        inherited.push(handoff.listener);
+
+        let prefetch = handler_for_shutdown.clone();
+        tokio::spawn(async move { prefetch.prefetch(&handoff.keys).await });
+    }
+
+    let mut server = match (inherited.pop(), args.bind, args.unix_socket) {
+        (Some(listener), None, None) => Server::from_fd(listener),
+        (Some(_), _, _) => {
+            return Err(anyhow!("Cannot bind while also inheriting a listener"));
+        }
+        (None, Some(bind_addr), None) => Server::bind(&bind_addr),
+        (None, None, Some(unix_path)) => Server::bind_unix(&unix_path),
+        (None, None, None) => Server::bind("127.0.0.1:11212"), // Default TCP binding
+        (None, Some(_), Some(_)) => {
+            return Err(anyhow!("Cannot specify both --bind and --unix-socket"));
+        }
     };
 
-    server.with_monitor_tasks(monitor_tasks_for_tick);
-    let _ = server.serve(service).await?;
+    server
+        .with_monitor_tasks(monitor_tasks_for_tick)
+        .with_stats(stats);
+    if let Some(max_connections) = args.max_connections {
+        server.with_max_connections(max_connections, overflow_policy);
+    }
+    if let Some(idle_timeout) = args.idle_timeout {
+        server.with_idle_timeout(idle_timeout);
+    }
+    if let Some(read_timeout) = args.read_timeout {
+        server.with_read_timeout(read_timeout);
+    }
+    if let Some(upgrade_socket) = &args.upgrade_socket {
+        server.with_upgrade_socket(upgrade_socket);
+    }
+    server.with_drain_timeout(args.drain_timeout);
+    server.serve(service).await?;
 
-    // Shutdown the service to ensure Writer threads are properly joined
-    handler_for_shutdown.shutdown();
+    // Shutdown the service to ensure queued writes are sent
+    handler_for_shutdown.shutdown().await;
+
+    if let Some(provider) = tracer_provider
+        && let Err(e) = provider.shutdown()
+    {
+        tracing::error!(error = %e, "Failed to flush traces");
+    }
 
     info!("Server terminated");
     Ok(())
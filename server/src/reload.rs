@@ -0,0 +1,565 @@
+use crate::config::{Pools, ServerConfig, ServerSection, SourceConfig};
+use platypus::{Router, Service, Sources, router::Overlap};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{SignalKind, signal};
+use tracing::{error, info, warn};
+
+/// A parsed configuration together with the pools and sources built from it.
+pub struct Loaded {
+    pub config: ServerConfig,
+    pub pools: Pools,
+    pub sources: Sources,
+}
+
+impl Loaded {
+    /// Reads and builds the configuration at `path`.
+    ///
+    /// Pools and sources whose configuration is unchanged from `previous` are
+    /// reused as is, so monitor tasks using them survive the reload.
+    pub async fn load(path: &str, previous: Option<&Loaded>) -> anyhow::Result<(Loaded, Router)> {
+        let config = ServerConfig::from_file(path)?;
+        config.validate()?;
+        let router = config.to_router()?;
+        // Finding overlaps walks the routes' automata, which can take a while
+        // with many routes, so it stays off the runtime serving clients
+        let router = tokio::task::spawn_blocking(move || {
+            report_overlaps(&router);
+            router
+        })
+        .await?;
+
+        let mut pools = Pools::new();
+        for (name, pool_config) in config.pool_configs.iter() {
+            let reused =
+                previous.and_then(|previous| match previous.config.pool_configs.get(name) {
+                    Some(previous_config) if previous_config == pool_config => {
+                        previous.pools.get(name).cloned()
+                    }
+                    _ => None,
+                });
+            let pool = match reused {
+                Some(pool) => pool,
+                None => pool_config.to_pool().await?,
+            };
+            pools.insert(name.clone(), pool);
+        }
+
+        let mut sources = Sources::new();
+        for (name, source_config) in config.source_configs.iter() {
+            let reused = previous.and_then(|previous| {
+                let unchanged = previous.config.source_configs.get(name) == Some(source_config)
+                    && source_config.pool().is_none_or(|pool| {
+                        match (previous.pools.get(pool), pools.get(pool)) {
+                            (Some(before), Some(after)) => Arc::ptr_eq(before, after),
+                            _ => false,
+                        }
+                    });
+                if unchanged {
+                    previous.sources.get(name).cloned()
+                } else {
+                    None
+                }
+            });
+            let source = match reused {
+                Some(source) => source,
+                None => Arc::new(source_config.to_source(&pools)?),
+            };
+            sources.insert(name.clone(), source);
+        }
+
+        Ok((
+            Loaded {
+                config,
+                pools,
+                sources,
+            },
+            router,
+        ))
+    }
+}
+
+impl SourceConfig {
+    // Name of the pool this source draws connections from
+    fn pool(&self) -> Option<&str> {
+        match self {
+            SourceConfig::AwsSecretsManager { pool, .. } => pool.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// Warns about routes that share keys with an earlier route, once per route
+fn report_overlaps(router: &Router) {
+    let rules = router.rules();
+    let mut overlaps: Vec<Overlap> = Vec::new();
+    for overlap in router.overlaps() {
+        match overlaps.last_mut() {
+            // Overlaps come in order of the later route; a shadowing one is the more useful report
+            Some(last) if last.later == overlap.later => {
+                if overlap.shadowed && !last.shadowed {
+                    *last = overlap;
+                }
+            }
+            _ => overlaps.push(overlap),
+        }
+    }
+    for overlap in overlaps {
+        let route = rules[overlap.later].pattern();
+        let earlier = rules[overlap.earlier].pattern();
+        if overlap.shadowed {
+            warn!(
+                route = route,
+                earlier = earlier,
+                example = overlap.example,
+                "Route is never used, an earlier route matches every key it does"
+            );
+        } else {
+            warn!(
+                route = route,
+                earlier = earlier,
+                example = overlap.example,
+                "Route overlaps an earlier route, which wins for keys both match"
+            );
+        }
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    Path::new(path).metadata().and_then(|m| m.modified()).ok()
+}
+
+/// Reloads the configuration into `service` on SIGHUP, and also whenever the
+/// file changes if `watch_interval` is set. A configuration that fails to load,
+/// or can't be written to `targets`, is logged and the current one is kept.
+pub async fn reload_on_change(
+    path: String,
+    service: Service,
+    mut loaded: Loaded,
+    targets: Vec<String>,
+    watch_interval: Option<Duration>,
+) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut last_modified = modified(&path);
+    let mut watch = watch_interval.map(tokio::time::interval);
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                info!(path = path, "SIGHUP received, reloading configuration");
+            }
+
+            _ = async {
+                match &mut watch {
+                    Some(watch) => watch.tick().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let current = modified(&path);
+                if current == last_modified {
+                    continue;
+                }
+                info!(path = path, "Configuration changed, reloading");
+            }
+        }
+        last_modified = modified(&path);
+
+        let result = Loaded::load(&path, Some(&loaded))
+            .await
+            .and_then(|(reloaded, router)| {
+                reloaded.config.validate_targets(&targets)?;
+                Ok((reloaded, router))
+            });
+        match result {
+            Ok((reloaded, router)) => {
+                // Compression is part of the routes, so it is reloaded with them
+                let without_compress = |server: &ServerSection| ServerSection {
+                    compress: None,
+                    ..server.clone()
+                };
+                if without_compress(&reloaded.config.server)
+                    != without_compress(&loaded.config.server)
+                {
+                    warn!("Changes to [server] take effect after a restart");
+                }
+                service.reload(router, reloaded.sources.clone()).await;
+                loaded = reloaded;
+            }
+            Err(e) => {
+                error!(error = %e, "Reload failed, keeping the current configuration");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use platypus::compression::Algorithm;
+    use platypus::router::{Fetch, Overrides};
+
+    fn write_config(path: &Path, echo1_template: &str) {
+        let config = format!(
+            r#"
+[routes.test]
+routes = [
+  {{ match = "^echo1/(?<path>.+)", to = "echo1" }},
+  {{ match = "^echo2/(?<path>.+)", to = "echo2" }},
+]
+
+[source.echo1]
+type = "echo"
+template = "{}"
+
+[source.echo2]
+type = "echo"
+template = "echo2 = {{path}}"
+"#,
+            echo1_template
+        );
+        std::fs::write(path, config).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_reuses_unchanged_sources() {
+        let path =
+            std::env::temp_dir().join(format!("platypus_reload_{}.toml", std::process::id()));
+        let path_str = path.to_str().unwrap();
+
+        write_config(&path, "echo1 = {path}");
+        let (first, _) = Loaded::load(path_str, None).await.unwrap();
+
+        write_config(&path, "changed = {path}");
+        let (second, _) = Loaded::load(path_str, Some(&first)).await.unwrap();
+
+        assert!(!Arc::ptr_eq(
+            &first.sources["echo1"],
+            &second.sources["echo1"]
+        ));
+        assert!(Arc::ptr_eq(
+            &first.sources["echo2"],
+            &second.sources["echo2"]
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reload_rejects_invalid_config() {
+        let path =
+            std::env::temp_dir().join(format!("platypus_reload_bad_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+[routes.test]
+routes = [{ match = "^(unclosed", to = "echo1" }]
+
+[source.echo1]
+type = "echo"
+template = "x"
+"#,
+        )
+        .unwrap();
+
+        assert!(Loaded::load(path.to_str().unwrap(), None).await.is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_server_section() {
+        let path =
+            std::env::temp_dir().join(format!("platypus_server_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+[server]
+target = { host = "cache", port = 11211 }
+prefix = "app/"
+strip_prefix = true
+max_value_size = 1048576
+oversize = "chunk"
+
+[routes.test]
+routes = [{ match = "^echo/(?<path>.+)", to = "echo" }]
+
+[source.echo]
+type = "echo"
+template = "x"
+"#,
+        )
+        .unwrap();
+
+        let (loaded, _) = Loaded::load(path.to_str().unwrap(), None).await.unwrap();
+        let server = &loaded.config.server;
+        assert_eq!(
+            server.target_address().as_deref(),
+            Some("memcache://cache:11211")
+        );
+        let namespace = server.to_namespace();
+        assert_eq!(namespace.prefix(), "app/");
+        assert_eq!(namespace.route_key("app/echo/a"), "echo/a");
+        assert_eq!(
+            server.to_value_limit().unwrap(),
+            Some(platypus::ValueLimit::new(1048576).with_oversize(platypus::Oversize::Chunk))
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_compress() {
+        let path =
+            std::env::temp_dir().join(format!("platypus_compress_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+[server]
+compress = { algorithm = "zstd", min_size = 1024 }
+
+[routes.test]
+routes = [
+  { match = "^plain/(?<path>.+)", to = "echo", compress = { algorithm = "none" } },
+  { match = "^zlib/(?<path>.+)", to = "echo", compress = { algorithm = "zlib" } },
+  { match = "^echo/(?<path>.+)", to = "echo" },
+]
+
+[source.echo]
+type = "echo"
+template = "x"
+"#,
+        )
+        .unwrap();
+
+        let (_, router) = Loaded::load(path.to_str().unwrap(), None).await.unwrap();
+        let compression = |key| router.rule(key).unwrap().1.compression();
+        assert_eq!(compression("plain/a"), None);
+        assert_eq!(
+            compression("zlib/a"),
+            Some(platypus::Compression::new(Algorithm::Zlib))
+        );
+        assert_eq!(
+            compression("echo/a"),
+            Some(platypus::Compression::new(Algorithm::Zstd).with_min_size(1024))
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flags() {
+        let path = std::env::temp_dir().join(format!("platypus_flags_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+[routes.test]
+routes = [
+  { match = "^json/(?<path>.+)", to = "echo", flags = 4 },
+  { match = "^echo/(?<path>.+)", to = "echo" },
+]
+
+[source.echo]
+type = "echo"
+template = "x"
+flags = 1
+"#,
+        )
+        .unwrap();
+
+        let (loaded, router) = Loaded::load(path.to_str().unwrap(), None).await.unwrap();
+        assert_eq!(router.rule("json/a").unwrap().1.flags(), Some(4));
+        assert_eq!(router.rule("echo/a").unwrap().1.flags(), None);
+        let response = loaded.sources["echo"]
+            .call(&platypus::Request::new("echo/a"))
+            .await;
+        assert_eq!(response.flags(), 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_route_order() {
+        let path = std::env::temp_dir().join(format!("platypus_order_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+[routes.fallback]
+priority = -1
+routes = [
+  { match = "^(?<path>.+)$", to = "fallback" },
+]
+
+[routes.zz]
+routes = [
+  { match = "^a/(?<path>.+)$", to = "zz" },
+  { match = "^a/b/(?<path>.+)$", to = "zz_b", priority = 1 },
+]
+
+[routes.aa]
+routes = [
+  { match = "^a/(?<path>.+)$", to = "aa" },
+]
+
+[source.fallback]
+type = "echo"
+template = "x"
+
+[source.zz]
+type = "echo"
+template = "x"
+
+[source.zz_b]
+type = "echo"
+template = "x"
+
+[source.aa]
+type = "echo"
+template = "x"
+"#,
+        )
+        .unwrap();
+
+        let (loaded, router) = Loaded::load(path.to_str().unwrap(), None).await.unwrap();
+        let order: Vec<&str> = loaded
+            .config
+            .ordered_routes()
+            .iter()
+            .map(|r| r.source.as_str())
+            .collect();
+        assert_eq!(order, vec!["zz_b", "zz", "aa", "fallback"]);
+        assert_eq!(router.rule("a/b/c").unwrap().1.source(), "zz_b");
+        assert_eq!(router.rule("a/c").unwrap().1.source(), "zz");
+        assert_eq!(router.rule("c").unwrap().1.source(), "fallback");
+
+        let overlaps: Vec<(usize, usize, bool)> = router
+            .overlaps()
+            .iter()
+            .map(|o| (o.earlier, o.later, o.shadowed))
+            .collect();
+        assert!(overlaps.contains(&(1, 2, true)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_route_overrides() {
+        let path =
+            std::env::temp_dir().join(format!("platypus_overrides_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+[routes.test]
+routes = [
+  { match = "^hot/(?<path>.+)", to = "http", ttl = "5s", fetch = "once", prefix = "hot:" },
+  { match = "^cold/(?<path>.+)", to = "http", ttl = "5m", negative_ttl = "10s", captures = { path = { type = "integer", max_length = 4 } } },
+  { match = "^other/(?<path>.+?)(@v\\d+)?$", to = "http", rewrite = "other/{path}" },
+]
+
+[source.http]
+type = "echo"
+template = "x"
+"#,
+        )
+        .unwrap();
+
+        let (_, router) = Loaded::load(path.to_str().unwrap(), None).await.unwrap();
+        let overrides = |key: &str| router.rule(key).unwrap().1.overrides().clone();
+        assert_eq!(
+            overrides("hot/a"),
+            Overrides::new()
+                .with_ttl(Duration::from_secs(5))
+                .with_fetch(Fetch::Once)
+                .with_prefix("hot:")
+        );
+        assert_eq!(
+            overrides("cold/a"),
+            Overrides::new()
+                .with_ttl(Duration::from_secs(300))
+                .with_negative_ttl(Duration::from_secs(10))
+        );
+        assert_eq!(overrides("other/a"), Overrides::new());
+        let validate = |key: &str| {
+            let (request, rule) = router.rule(key).unwrap();
+            rule.validate(&request)
+        };
+        assert!(validate("cold/42").is_ok());
+        assert!(validate("cold/12345").is_err());
+        assert!(validate("cold/a").is_err());
+        let (request, rule) = router.rule("other/a@v2").unwrap();
+        assert_eq!(rule.rewrite(&request).as_deref(), Some("other/a"));
+
+        std::fs::write(
+            &path,
+            r#"
+[routes.test]
+routes = [{ match = "^hot/(?<path>.+)", to = "http", fetch = "sometimes" }]
+"#,
+        )
+        .unwrap();
+        assert!(Loaded::load(path.to_str().unwrap(), None).await.is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_switch_and_shadow() {
+        let path =
+            std::env::temp_dir().join(format!("platypus_switch_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+[routes.test]
+routes = [
+  { match = "^(?<environment>[^/]+)/(?<name>.+)$", to = "config" },
+]
+
+[source.config]
+type = "switch"
+cases = [{ capture = "environment", values = ["prod"], to = "new" }]
+split = [{ to = "new", weight = 0 }, { to = "checked", weight = 1 }]
+
+[source.checked]
+type = "shadow"
+primary = "legacy"
+shadow = "new"
+
+[source.new]
+type = "echo"
+template = "new"
+
+[source.legacy]
+type = "echo"
+template = "legacy"
+"#,
+        )
+        .unwrap();
+
+        let (loaded, router) = Loaded::load(path.to_str().unwrap(), None).await.unwrap();
+        let sources = Arc::new(loaded.sources.clone());
+        let value = async |key: &str| {
+            let (request, rule) = router.rule(key).unwrap();
+            let request = request.with_sources(sources.clone());
+            sources[rule.source()].call(&request).await.value()
+        };
+        assert_eq!(value("prod/app").await.as_deref(), Some("new"));
+        assert_eq!(value("staging/app").await.as_deref(), Some("legacy"));
+
+        std::fs::write(
+            &path,
+            r#"
+[routes.test]
+routes = []
+
+[source.config]
+type = "switch"
+default = "new"
+split = [{ to = "new", weight = 1 }]
+"#,
+        )
+        .unwrap();
+        assert!(Loaded::load(path.to_str().unwrap(), None).await.is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
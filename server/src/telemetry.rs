@@ -0,0 +1,29 @@
+use anyhow::Result;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::Resource;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+
+/// Builds a tracer provider that exports spans over OTLP/HTTP to `endpoint`,
+/// e.g. "http://localhost:4318/v1/traces" for a local collector.
+pub fn tracer_provider(endpoint: &str) -> Result<SdkTracerProvider> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+    let resource = Resource::builder().with_service_name("platypus").build();
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(resource)
+        .build())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracer_provider() {
+        let provider = tracer_provider("http://127.0.0.1:4318/v1/traces").unwrap();
+        provider.shutdown().ok();
+    }
+}
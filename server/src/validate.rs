@@ -0,0 +1,587 @@
+//! Checks a whole configuration before anything is built from it, so every
+//! problem is reported at once, with where in the file it is.
+
+use crate::config::{CompressConfig, MergeRuleArgsConfig, RouteConfig, ServerConfig, SourceConfig};
+use humantime::parse_duration;
+use platypus::{
+    Oversize, Template,
+    compression::Algorithm,
+    router::{CaptureType, Constraint, Fetch, Rule},
+};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A problem with the value at `path`, e.g. `routes.api.routes[2].to`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Regex errors span several lines; keep them under their path
+        write!(f, "{}: {}", self.path, self.message.replace('\n', "\n    "))
+    }
+}
+
+#[derive(Default)]
+struct Problems(Vec<Problem>);
+
+impl Problems {
+    fn add(&mut self, path: &str, message: impl Into<String>) {
+        self.0.push(Problem {
+            path: path.to_string(),
+            message: message.into(),
+        });
+    }
+
+    fn duration(&mut self, path: &str, value: &Option<String>) {
+        if let Some(value) = value
+            && let Err(e) = parse_duration(value)
+        {
+            self.add(path, format!("invalid duration '{}': {}", value, e));
+        }
+    }
+
+    fn parse<T: std::str::FromStr<Err = String>>(&mut self, path: &str, value: &Option<String>) {
+        if let Some(value) = value
+            && let Err(e) = value.parse::<T>()
+        {
+            self.add(path, e);
+        }
+    }
+
+    fn pattern(&mut self, path: &str, pattern: &str) {
+        if let Err(e) = Rule::new(pattern, "") {
+            self.add(path, e.to_string());
+        }
+    }
+
+    fn template(&mut self, path: &str, text: &str) {
+        if let Err(e) = Template::new(text).check() {
+            self.add(path, format!("invalid template: {}", e));
+        }
+    }
+
+    fn source(&mut self, path: &str, config: &ServerConfig, name: &str) {
+        if !config.source_configs.contains_key(name) {
+            self.add(path, format!("unknown source '{}'", name));
+        }
+    }
+
+    fn compress(&mut self, path: &str, compress: &Option<CompressConfig>) {
+        if let Some(compress) = compress
+            && compress.algorithm != "none"
+        {
+            self.parse::<Algorithm>(
+                &format!("{}.algorithm", path),
+                &Some(compress.algorithm.clone()),
+            );
+        }
+    }
+}
+
+/// Fails if `--write-cas` is set with a Redis target, which has no CAS to
+/// guard writes with
+pub fn validate_write_cas(write_cas: bool, targets: &[String]) -> anyhow::Result<()> {
+    match targets.iter().find(|target| target.starts_with("redis://")) {
+        Some(redis) if write_cas => Err(anyhow::anyhow!(
+            "--write-cas only guards memcached targets, not Redis target '{}'",
+            redis
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn fail_on(problems: Vec<Problem>) -> anyhow::Result<()> {
+    if problems.is_empty() {
+        return Ok(());
+    }
+    let lines: Vec<String> = problems.iter().map(|p| format!("  {}", p)).collect();
+    Err(anyhow::anyhow!(
+        "Invalid configuration:\n{}",
+        lines.join("\n")
+    ))
+}
+
+impl ServerConfig {
+    /// Every problem in the configuration, in file order
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut problems = Problems::default();
+
+        problems.compress("server.compress", &self.server.compress);
+        problems.parse::<Oversize>("server.oversize", &self.server.oversize);
+        if let Some(target_key) = &self.server.target_key {
+            problems.template("server.target_key", target_key);
+        }
+
+        for (group_name, group) in &self.routes {
+            for (i, route) in group.routes.iter().enumerate() {
+                let path = format!("routes.{}.routes[{}]", group_name, i);
+                self.route_problems(&mut problems, &path, route);
+            }
+        }
+
+        for (name, source) in &self.source_configs {
+            let path = format!("source.{}", name);
+            self.source_problems(&mut problems, &path, source);
+        }
+        self.cycle_problems(&mut problems);
+
+        problems.0
+    }
+
+    /// Fails with every problem in the configuration, if it has any
+    pub fn validate(&self) -> anyhow::Result<()> {
+        fail_on(self.problems())
+    }
+
+    /// Problems with writing to `targets`. Redis has no item flags, so clients
+    /// could not tell compressed values from others there, and would read
+    /// values without the flags configured for them.
+    pub fn target_problems(&self, targets: &[String]) -> Vec<Problem> {
+        let mut problems = Problems::default();
+        let Some(redis) = targets.iter().find(|target| target.starts_with("redis://")) else {
+            return problems.0;
+        };
+        let compressed = |compress: &Option<CompressConfig>| {
+            compress.as_ref().is_some_and(|c| c.algorithm != "none")
+        };
+        let flagged = |flags: Option<u32>| flags.is_some_and(|flags| flags != 0);
+        let mut refuse = |path: &str, what: &str| {
+            problems.add(
+                path,
+                format!("{} can't be written to Redis target '{}'", what, redis),
+            );
+        };
+
+        if compressed(&self.server.compress) {
+            refuse("server.compress", "compressed values");
+        }
+        for (group_name, group) in &self.routes {
+            for (i, route) in group.routes.iter().enumerate() {
+                let path = format!("routes.{}.routes[{}]", group_name, i);
+                if compressed(&route.compress) {
+                    refuse(&format!("{}.compress", path), "compressed values");
+                }
+                if flagged(route.flags) {
+                    refuse(&format!("{}.flags", path), "client flags");
+                }
+            }
+        }
+        for (name, source) in &self.source_configs {
+            if flagged(source.flags()) {
+                refuse(&format!("source.{}.flags", name), "client flags");
+            }
+        }
+        problems.0
+    }
+
+    /// Fails with every problem with writing to `targets`, if there are any
+    pub fn validate_targets(&self, targets: &[String]) -> anyhow::Result<()> {
+        fail_on(self.target_problems(targets))
+    }
+
+    fn route_problems(&self, problems: &mut Problems, path: &str, route: &RouteConfig) {
+        problems.pattern(&format!("{}.match", path), &route.pattern);
+        problems.source(&format!("{}.to", path), self, &route.source);
+        problems.compress(&format!("{}.compress", path), &route.compress);
+        problems.duration(&format!("{}.ttl", path), &route.ttl);
+        problems.duration(&format!("{}.expiry", path), &route.expiry);
+        problems.duration(&format!("{}.negative_ttl", path), &route.negative_ttl);
+        problems.parse::<Fetch>(&format!("{}.fetch", path), &route.fetch);
+        if let Some(rewrite) = &route.rewrite {
+            problems.template(&format!("{}.rewrite", path), rewrite);
+        }
+
+        for (name, capture) in route.captures.iter().flatten() {
+            let path = format!("{}.captures.{}", path, name);
+            problems.parse::<CaptureType>(&format!("{}.type", path), &capture.capture_type);
+            if let Some(chars) = &capture.chars
+                && let Err(e) = Constraint::new().with_chars(chars)
+            {
+                problems.add(&format!("{}.chars", path), e.to_string());
+            }
+        }
+    }
+
+    // Sources that refer back to themselves would call each other forever
+    fn cycle_problems(&self, problems: &mut Problems) {
+        #[derive(Clone, Copy, PartialEq)]
+        enum Visit {
+            InProgress,
+            Done,
+        }
+
+        fn visit<'a>(
+            config: &'a ServerConfig,
+            name: &'a str,
+            path: &mut Vec<&'a str>,
+            visits: &mut HashMap<&'a str, Visit>,
+            problems: &mut Problems,
+        ) {
+            match visits.get(name) {
+                Some(Visit::Done) => return,
+                Some(Visit::InProgress) => {
+                    let start = path.iter().position(|n| *n == name).unwrap_or(0);
+                    let mut cycle = path[start..].to_vec();
+                    cycle.push(name);
+                    problems.add(
+                        &format!("source.{}", name),
+                        format!("refers back to itself: {}", cycle.join(" -> ")),
+                    );
+                    return;
+                }
+                None => {}
+            }
+            let Some(source) = config.source_configs.get(name) else {
+                return;
+            };
+            visits.insert(name, Visit::InProgress);
+            path.push(name);
+            for reference in source.references() {
+                visit(config, reference, path, visits, problems);
+            }
+            path.pop();
+            visits.insert(name, Visit::Done);
+        }
+
+        let mut visits = HashMap::new();
+        for name in self.source_configs.keys() {
+            visit(self, name, &mut Vec::new(), &mut visits, problems);
+        }
+    }
+
+    fn source_problems(&self, problems: &mut Problems, path: &str, source: &SourceConfig) {
+        match source {
+            SourceConfig::AwsSecretsManager {
+                secret_id,
+                pool,
+                ttl,
+                expiry,
+                ..
+            } => {
+                problems.template(&format!("{}.secret_id", path), secret_id);
+                match pool {
+                    Some(pool) if !self.pool_configs.contains_key(pool) => {
+                        problems.add(
+                            &format!("{}.pool", path),
+                            format!("unknown pool '{}'", pool),
+                        );
+                    }
+                    Some(_) => {}
+                    None => problems.add(path, "aws_secrets_manager sources need a pool"),
+                }
+                problems.duration(&format!("{}.ttl", path), ttl);
+                problems.duration(&format!("{}.expiry", path), expiry);
+            }
+            SourceConfig::Echo { template, .. } => {
+                problems.template(&format!("{}.template", path), template);
+            }
+            SourceConfig::File {
+                path: file_path,
+                ttl,
+                expiry,
+                ..
+            } => {
+                problems.template(&format!("{}.path", path), file_path);
+                problems.duration(&format!("{}.ttl", path), ttl);
+                problems.duration(&format!("{}.expiry", path), expiry);
+            }
+            SourceConfig::Http {
+                url,
+                method,
+                ttl,
+                expiry,
+                ..
+            } => {
+                problems.template(&format!("{}.url", path), url);
+                if let Some(method) = method
+                    && method.parse::<reqwest::Method>().is_err()
+                {
+                    problems.add(
+                        &format!("{}.method", path),
+                        format!("invalid HTTP method '{}'", method),
+                    );
+                }
+                problems.duration(&format!("{}.ttl", path), ttl);
+                problems.duration(&format!("{}.expiry", path), expiry);
+            }
+            SourceConfig::Merge {
+                template,
+                ttl,
+                expiry,
+                ..
+            } => {
+                for (i, rule) in template.iter().enumerate() {
+                    if let MergeRuleArgsConfig::Replace { args } = &rule.args {
+                        for (name, value) in args {
+                            let arg_path = format!("{}.template[{}].args.{}", path, i, name);
+                            problems.template(&arg_path, value);
+                        }
+                    }
+                    problems.source(
+                        &format!("{}.template[{}].source", path, i),
+                        self,
+                        &rule.source,
+                    );
+                }
+                problems.duration(&format!("{}.ttl", path), ttl);
+                problems.duration(&format!("{}.expiry", path), expiry);
+            }
+            SourceConfig::Switch {
+                cases,
+                default,
+                split,
+            } => {
+                for (i, case) in cases.iter().enumerate() {
+                    problems.source(&format!("{}.cases[{}].to", path, i), self, &case.to);
+                }
+                if let Some(default) = default {
+                    problems.source(&format!("{}.default", path), self, default);
+                }
+                for (i, split) in split.iter().flatten().enumerate() {
+                    problems.source(&format!("{}.split[{}].to", path, i), self, &split.to);
+                }
+                if default.is_some() && split.is_some() {
+                    problems.add(path, "takes either default or split, not both");
+                }
+            }
+            SourceConfig::Shadow { primary, shadow } => {
+                problems.source(&format!("{}.primary", path), self, primary);
+                problems.source(&format!("{}.shadow", path), self, shadow);
+            }
+        }
+    }
+}
+
+impl SourceConfig {
+    // Names of the sources this source calls
+    fn references(&self) -> Vec<&str> {
+        match self {
+            SourceConfig::Merge { template, .. } => {
+                template.iter().map(|rule| rule.source.as_str()).collect()
+            }
+            SourceConfig::Switch {
+                cases,
+                default,
+                split,
+            } => cases
+                .iter()
+                .map(|case| case.to.as_str())
+                .chain(default.as_deref())
+                .chain(split.iter().flatten().map(|split| split.to.as_str()))
+                .collect(),
+            SourceConfig::Shadow { primary, shadow } => vec![primary, shadow],
+            _ => Vec::new(),
+        }
+    }
+
+    // Client flags set on the source's values
+    fn flags(&self) -> Option<u32> {
+        match self {
+            SourceConfig::AwsSecretsManager { flags, .. }
+            | SourceConfig::Echo { flags, .. }
+            | SourceConfig::File { flags, .. }
+            | SourceConfig::Http { flags, .. }
+            | SourceConfig::Merge { flags, .. } => *flags,
+            SourceConfig::Switch { .. } | SourceConfig::Shadow { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(toml: &str) -> Vec<String> {
+        let config: ServerConfig = toml::from_str(toml).unwrap();
+        config.problems().iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let problems = problems(
+            r#"
+[routes.test]
+routes = [{ match = "^echo/(?<name>.+)$", to = "echo", ttl = "5s" }]
+
+[source.echo]
+type = "echo"
+template = "hello {name}"
+"#,
+        );
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn test_every_problem_is_reported() {
+        let problems = problems(
+            r#"
+[server]
+oversize = "drop"
+
+[routes.test]
+routes = [
+  { match = "^echo/(?<name>.+$", to = "echo" },
+  { match = "^other/(?<name>.+)$", to = "missing", ttl = "soon", fetch = "never" },
+]
+
+[source.echo]
+type = "echo"
+template = "hello {name}"
+
+[source.merged]
+type = "merge"
+format = "json"
+template = [{ key = ["a"], source = "gone", args = "inherit" }]
+
+[source.secret]
+type = "aws_secrets_manager"
+secret_id = "{name}"
+pool = "nowhere"
+"#,
+        );
+        assert_eq!(problems.len(), 7, "{:#?}", problems);
+        assert!(problems[0].starts_with("server.oversize: unknown"));
+        assert!(problems[1].starts_with("routes.test.routes[0].match: regex parse error"));
+        assert_eq!(
+            problems[2..],
+            [
+                "routes.test.routes[1].to: unknown source 'missing'",
+                "routes.test.routes[1].ttl: invalid duration 'soon': expected number at 0",
+                "routes.test.routes[1].fetch: unknown fetch mode 'never', expected poll or once",
+                "source.merged.template[0].source: unknown source 'gone'",
+                "source.secret.pool: unknown pool 'nowhere'",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_templates_are_reported() {
+        let problems = problems(
+            r#"
+[routes.test]
+routes = [{ match = "^(?<name>.+)$", to = "echo", rewrite = "{name" }]
+
+[source.echo]
+type = "echo"
+template = "{name:reverse}"
+strict = true
+"#,
+        );
+        assert_eq!(
+            problems,
+            [
+                "routes.test.routes[0].rewrite: invalid template: unclosed '{', escape it as \\{",
+                "source.echo.template: invalid template: unknown filter 'reverse', expected urlencode, lower, upper, base64, json or sha256",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cycles_are_reported() {
+        let problems = problems(
+            r#"
+[routes.test]
+routes = [{ match = "^(?<name>.+)$", to = "outer" }]
+
+[source.config]
+type = "echo"
+template = "{name}"
+
+[source.outer]
+type = "merge"
+format = "json"
+template = [
+  { key = ["config"], source = "config", args = "inherit" },
+  { key = ["inner"], source = "inner", args = "inherit" },
+]
+
+[source.inner]
+type = "switch"
+cases = [{ capture = "name", values = ["a"], to = "outer" }]
+default = "config"
+
+[source.recursive]
+type = "merge"
+format = "json"
+template = [{ key = ["again"], source = "recursive", args = "inherit" }]
+"#,
+        );
+        assert_eq!(
+            problems,
+            [
+                "source.outer: refers back to itself: outer -> inner -> outer",
+                "source.recursive: refers back to itself: recursive -> recursive",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate_lists_problems() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+[routes.test]
+routes = [{ match = "^a$", to = "missing" }]
+
+[source]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "Invalid configuration:\n  routes.test.routes[0].to: unknown source 'missing'"
+        );
+    }
+
+    #[test]
+    fn test_compress_and_flags_with_redis_target() {
+        let config: ServerConfig = toml::from_str(
+            r#"
+[server]
+compress = { algorithm = "zstd" }
+
+[routes.test]
+routes = [
+  { match = "^a/(?<name>.+)$", to = "echo", compress = { algorithm = "none" } },
+  { match = "^b/(?<name>.+)$", to = "echo", compress = { algorithm = "zlib" }, flags = 1 },
+]
+
+[source.echo]
+type = "echo"
+template = "hello {name}"
+flags = 4
+"#,
+        )
+        .unwrap();
+
+        let memcached = ["memcache://127.0.0.1:11213".to_string()];
+        assert!(config.validate_targets(&memcached).is_ok());
+        let redis = ["redis://127.0.0.1:6379".to_string()];
+        let problems: Vec<String> = config
+            .target_problems(&redis)
+            .iter()
+            .map(|p| p.path.clone())
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "server.compress",
+                "routes.test.routes[1].compress",
+                "routes.test.routes[1].flags",
+                "source.echo.flags",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_cas_with_redis_target() {
+        let memcached = ["memcache://127.0.0.1:11213".to_string()];
+        let redis = ["redis://127.0.0.1:6379".to_string()];
+        assert!(validate_write_cas(true, &memcached).is_ok());
+        assert!(validate_write_cas(false, &redis).is_ok());
+        assert!(validate_write_cas(true, &redis).is_err());
+    }
+}